@@ -0,0 +1,110 @@
+// Compares sending a file over loopback TCP through a buffer with sending it straight from the page cache.
+// Run with `cargo bench --bench zero_copy`.
+
+use std::fs::File;
+use std::sync::Arc;
+use std::time;
+
+use async_std::task;
+
+use bitgeon::protocol;
+use bitgeon::transport::{Connection, Frame, Listener, TransportKind};
+use bitgeon::util;
+
+const FILE_SIZE: u64 = 256 << 20;
+const CHUNK_SIZE: u64 = 256 << 10;
+const ROUNDS: usize = 5;
+
+// Chunks of the file as the job sends them, with the data still on disk
+fn frames(file: &Arc<File>) -> Vec<Frame> {
+    (0..FILE_SIZE)
+        .step_by(CHUNK_SIZE as usize)
+        .map(|offset| Frame::File {
+            header: Arc::new(protocol::file_chunk_header(0, offset, CHUNK_SIZE as u32)),
+            file: file.clone(),
+            offset,
+            length: CHUNK_SIZE,
+        })
+        .collect()
+}
+
+// What goes over the wire besides the file itself
+fn headers_size() -> usize {
+    let count = FILE_SIZE.div_ceil(CHUNK_SIZE) as usize;
+    count * protocol::file_chunk_header(0, 0, 0).len()
+}
+
+// Sends every frame once and returns how long it took until the other end had all of it
+async fn send(frames: &[Frame], buffered: bool) -> time::Duration {
+    let listener = Listener::bind(TransportKind::Tcp, "127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = listener.local_addr().unwrap();
+    let receiving = task::spawn(async move {
+        // Dropping the writing half would shut the socket down
+        let (mut reader, _writer) = listener
+            .accept(time::Duration::from_secs(5))
+            .await
+            .unwrap()
+            .split();
+        let expected = FILE_SIZE as usize + headers_size();
+        let mut buffer = vec![0; 1 << 20];
+        let mut received = 0;
+        while received < expected {
+            match reader.read(&mut buffer).await.unwrap() {
+                0 => panic!("The connection was closed early."),
+                read => received += read,
+            }
+        }
+    });
+    let connection = Connection::connect(TransportKind::Tcp, address, time::Duration::from_secs(5))
+        .await
+        .unwrap();
+    let (_reader, mut writer) = connection.split();
+
+    let start = time::Instant::now();
+    for frame in frames {
+        match buffered {
+            true => {
+                let copy = frame.clone();
+                let bytes = task::spawn_blocking(move || copy.read()).await.unwrap();
+                writer.write_frame(&Frame::Encoded(Arc::new(bytes))).await
+            }
+            false => writer.write_frame(frame).await,
+        }
+        .unwrap();
+    }
+    receiving.await;
+    start.elapsed()
+}
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("bitgeon_zero_copy_{}", std::process::id()));
+    let contents: Vec<u8> = (0..FILE_SIZE).map(|index| (index % 251) as u8).collect();
+    std::fs::write(&path, &contents).unwrap();
+    drop(contents);
+    let file = Arc::new(File::open(&path).unwrap());
+    let frames = frames(&file);
+
+    println!(
+        "Sending {} in chunks of {} over loopback TCP, best of {} rounds:",
+        util::format_size(FILE_SIZE),
+        util::format_size(CHUNK_SIZE),
+        ROUNDS
+    );
+    for (name, buffered) in [("buffered", true), ("zero-copy", false)] {
+        // The first round warms up the page cache
+        let best = (0..=ROUNDS)
+            .map(|_| task::block_on(send(&frames, buffered)))
+            .skip(1)
+            .min()
+            .unwrap();
+        let throughput = FILE_SIZE as f64 / best.as_secs_f64();
+        println!(
+            "{:>10}: {:>8.1?} ({}/s)",
+            name,
+            best,
+            util::format_size(throughput as u64)
+        );
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
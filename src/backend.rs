@@ -1,8 +1,10 @@
+use std::net::SocketAddr;
 use std::ops::Deref;
 use std::time;
 
 use anyhow::Result;
 
+use crate::link::Link;
 use crate::server;
 use crate::settings::LogicSettings;
 use crate::ui::{self, AppState};
@@ -41,7 +43,10 @@ pub mod event {
     pub enum Server {}
 
     pub enum Ui {
+        Back,
         Selection(usize),
+        Connecting(Vec<SocketAddr>), // Where the sender is to be found
+        Receiving(usize),            // Holds the ID of the peer whose download to follow
     }
 
     impl Event for Server {}
@@ -79,6 +84,9 @@ pub struct Application {
     >,
     pub settings: LogicSettings,
     pub files_for_transmission: StyledPathList,
+    pub link: Option<Link>, // Given on the command line, followed once we are up
+    pub sender: Vec<SocketAddr>, // Addresses of the peer we are about to receive from
+    pub receiving: Option<usize>, // ID of the peer we are receiving from
     pub server: util::ThreadChannel<
         server::Message<server::data::Backend, server::event::Backend>,
         Message<data::Server, event::Server>,
@@ -103,6 +111,9 @@ impl Application {
             server,
             ui,
             settings: LogicSettings::default(),
+            link: None,
+            sender: vec![],
+            receiving: None,
             files_for_transmission: StyledPathList::new(
                 String::from(
                     "Edit paths below, or simply drag and drop files or directories here:",
@@ -134,6 +145,11 @@ impl Application {
         ui_updates
     }
 
+    // Chat goes straight to the server. We only need to know when the user is done.
+    pub fn chat(&mut self) -> Result<State> {
+        self.show_until_back(AppState::Chat)
+    }
+
     pub fn edit_files(&mut self) -> Result<State> {
         self.ui
             .send(ui::Message::Event(ui::event::Backend::StateChange(
@@ -145,7 +161,12 @@ impl Application {
         for message in ui_updates {
             match message {
                 Message::Data(ui_data) => {
-                    let data::Ui::FilePathList(file_paths) = ui_data;
+                    let data::Ui::FilePathList(mut file_paths) = ui_data;
+                    for link in file_paths.take_links() {
+                        self.server.send(server::Message::Event(
+                            server::event::Backend::OpenLink(link),
+                        ))?;
+                    }
                     self.files_for_transmission = file_paths;
                 }
                 Message::Event(_) => todo!(),
@@ -167,6 +188,28 @@ impl Application {
         Ok(State(Self::exit))
     }
 
+    pub fn history(&mut self) -> Result<State> {
+        self.show_until_back(AppState::History)
+    }
+
+    pub fn conflicts(&mut self) -> Result<State> {
+        self.show_until_back(AppState::Conflicts)
+    }
+
+    // Scenes that only talk to the server are shown until the user backs out of them
+    fn show_until_back(&mut self, state: AppState) -> Result<State> {
+        self.ui
+            .send(ui::Message::Event(ui::event::Backend::StateChange(state)))?;
+
+        loop {
+            for message in self.wait_for_input() {
+                if let Message::Event(event::Ui::Back) = message {
+                    return Ok(State(Self::home));
+                }
+            }
+        }
+    }
+
     pub fn home(&mut self) -> Result<State> {
         self.ui
             .send(ui::Message::Event(ui::event::Backend::StateChange(
@@ -187,8 +230,14 @@ impl Application {
                 //     ui::Event::Selection(selection) => match selection {
                 Message::Event(event::Ui::Selection(selection)) => match selection {
                     0 => return Ok(State(Self::edit_files)),
-                    1 => return Ok(State(Self::receive)),
-                    2 => return Ok(State(Self::end)),
+                    1 => return Ok(State(Self::send_files)),
+                    2 => return Ok(State(Self::receive)),
+                    3 => return Ok(State(Self::send_text)),
+                    4 => return Ok(State(Self::chat)),
+                    5 => return Ok(State(Self::refresh_connection)),
+                    6 => return Ok(State(Self::history)),
+                    7 => return Ok(State(Self::conflicts)),
+                    8 => return Ok(State(Self::end)),
                     _ => todo!(),
                 },
                 _ => todo!(),
@@ -202,11 +251,79 @@ impl Application {
         // TODO: Is this state necessary, or should we start right to home?
         // Starting is probably quick enough that we can go straight to home. If that weren't the case, this could be used for displaying start-up information or a splash screen
 
+        if let Some(link) = self.link.take() {
+            self.server
+                .send(server::Message::Event(server::event::Backend::OpenLink(
+                    link,
+                )))?;
+        }
         Ok(State(Self::home))
     }
 
-    pub fn receive(&mut self) -> Result<State> {
-        todo!();
+    pub fn refresh_connection(&mut self) -> Result<State> {
+        self.server.send(server::Message::Event(
+            server::event::Backend::RefreshConnection,
+        ))?;
         Ok(State(Self::home))
     }
+
+    // The paths go along to the scene, which hands them to the server together with the chosen peers
+    pub fn send_files(&mut self) -> Result<State> {
+        let paths = self.files_for_transmission.sendable_paths();
+        self.show_until_back(AppState::SendFiles(paths))
+    }
+
+    pub fn send_text(&mut self) -> Result<State> {
+        self.show_until_back(AppState::SendText)
+    }
+
+    // Receiving goes through the connection details, the sender's offer and the download, until the user is done with it
+    pub fn receive(&mut self) -> Result<State> {
+        self.ui
+            .send(ui::Message::Event(ui::event::Backend::StateChange(
+                AppState::Receive,
+            )))?;
+
+        loop {
+            for message in self.wait_for_input() {
+                match message {
+                    Message::Event(event::Ui::Back) => return Ok(State(Self::home)),
+                    Message::Event(event::Ui::Connecting(addresses)) => {
+                        self.sender = addresses;
+                        return Ok(State(Self::receive_offer));
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // Waits for the offer, which is accepted or declined along with where the files should go
+    pub fn receive_offer(&mut self) -> Result<State> {
+        self.ui
+            .send(ui::Message::Event(ui::event::Backend::StateChange(
+                AppState::ReceiveOffer(self.sender.clone()),
+            )))?;
+
+        loop {
+            for message in self.wait_for_input() {
+                match message {
+                    Message::Event(event::Ui::Back) => return Ok(State(Self::home)),
+                    Message::Event(event::Ui::Receiving(peer)) => {
+                        self.receiving = Some(peer);
+                        return Ok(State(Self::receive_progress));
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // The download goes on in the background when the user leaves early. It's shown on Home as well.
+    pub fn receive_progress(&mut self) -> Result<State> {
+        match self.receiving.take() {
+            Some(peer) => self.show_until_back(AppState::ReceiveProgress(peer)),
+            None => Ok(State(Self::home)),
+        }
+    }
 }
@@ -5,34 +5,117 @@
 // TODO: Consider Flume vs. crossbeam_channel https://crates.io/crates/flume
 
 use anyhow::Result;
+use argh::FromArgs;
+use std::net::SocketAddr;
 use std::thread;
+use std::time;
 
 use bitgeon::backend::Application;
+use bitgeon::link::Link;
+use bitgeon::reachability;
 use bitgeon::server;
+use bitgeon::settings::ChannelSettings;
+use bitgeon::stream;
 use bitgeon::ui;
 use bitgeon::util;
 
+/// Send files directly from one computer to another.
+#[derive(FromArgs)]
+struct Arguments {
+    /// run a probe server for reachability self-tests on the given address, instead of the usual interface
+    #[argh(option)]
+    probe_server: Option<SocketAddr>,
+
+    /// bitgeon:// link to connect to on startup
+    #[argh(positional)]
+    link: Option<String>,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Send(SendCommand),
+    Receive(ReceiveCommand),
+}
+
+/// Send whatever comes in on stdin to the receiver that has the token, without the interface.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "send")]
+struct SendCommand {
+    /// port to listen on. Picked by the operating system, if not given
+    #[argh(option)]
+    port: Option<u16>,
+}
+
+/// Receive a stream from a sender and write it to stdout, without the interface.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "receive")]
+struct ReceiveCommand {
+    /// address of the sender, as shown by `bitgeon send`
+    #[argh(positional)]
+    address: SocketAddr,
+
+    /// token shown by `bitgeon send` along with the address
+    #[argh(positional)]
+    token: stream::Token,
+}
+
 fn main() -> Result<()> {
+    let arguments: Arguments = argh::from_env();
+    if let Some(address) = arguments.probe_server {
+        return reachability::run_probe_server(address, time::Duration::from_secs(5));
+    }
+
+    // The interface owns the terminal, so scripts get their own path
+    match arguments.command {
+        Some(Command::Send(command)) => {
+            return stream::run_send(command.port, time::Duration::from_secs(10))
+        }
+        Some(Command::Receive(command)) => {
+            return stream::run_receive(
+                command.address,
+                command.token,
+                time::Duration::from_secs(10),
+            )
+        }
+        None => (),
+    }
+
+    // A broken link is better reported before the interface takes over the terminal
+    let link = match &arguments.link {
+        Some(link) => Some(Link::parse(link)?),
+        None => None,
+    };
+
     // Initialize state machine
-    let (app_to_ui, ui_to_app) = util::ThreadChannel::new_pair();
-    let (app_to_server, server_to_app) = util::ThreadChannel::new_pair();
-    let (ui_to_server, server_to_ui) = util::ThreadChannel::new_pair();
+    let channels = ChannelSettings::default();
+    let (app_to_ui, ui_to_app) =
+        util::ThreadChannel::new_bounded_pair(channels.to_ui, channels.to_backend);
+    let (app_to_server, server_to_app) =
+        util::ThreadChannel::new_bounded_pair(channels.to_server, channels.to_backend);
+    let (ui_to_server, server_to_ui) =
+        util::ThreadChannel::new_bounded_pair(channels.to_server, channels.server_to_ui);
+    let (progress_to_ui, ui_from_progress) = util::Latest::new_pair();
     let mut application = Application::new(app_to_ui, app_to_server);
+    application.link = link;
 
     // Setup UI
     let ui = thread::Builder::new()
         .name(String::from("User Interface"))
         .spawn(move || -> Result<()> {
-            let mut ui = ui::Ui::new(ui_to_app, ui_to_server);
+            let mut ui = ui::Ui::new(ui_to_app, ui_to_server, ui_from_progress);
             ui.run()?;
             Ok(())
         })?;
 
-    let server =
+    let _server =
         thread::Builder::new()
             .name(String::from("Server"))
             .spawn(move || -> Result<()> {
-                let mut server = server::Server::new(server_to_app, server_to_ui);
+                let mut server = server::Server::new(server_to_app, server_to_ui, progress_to_ui);
                 server.run()?;
                 Ok(())
             })?;
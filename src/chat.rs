@@ -0,0 +1,417 @@
+// Text chat between peers. Every connection gets its own keys from an X25519 exchange, and messages are sealed with ChaCha20-Poly1305.
+// Nobody relaying or recording the traffic can read them, whatever the transport. The exchange isn't authenticated, though,
+// so someone sitting in the middle of the connection could pose as the peer. Comparing the safety code on both ends rules that out.
+// Chat history is kept locally, one file per peer.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time;
+
+use anyhow::{anyhow, Context, Result};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::rand::SystemRandom;
+
+use crate::peer::Direction;
+use crate::protocol::Message;
+use crate::util;
+
+// Our half of the key exchange, waiting for the peer's public key
+pub struct KeyPair {
+    private_key: EphemeralPrivateKey,
+    public_key: [u8; 32],
+}
+
+impl KeyPair {
+    pub fn generate() -> Result<KeyPair> {
+        let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| anyhow!("Unable to generate chat key."))?;
+        let public_key = private_key
+            .compute_public_key()
+            .map_err(|_| anyhow!("Unable to compute chat key."))?;
+        let public_key = public_key
+            .as_ref()
+            .try_into()
+            .map_err(|_| anyhow!("Chat key has the wrong length."))?;
+        Ok(KeyPair {
+            private_key,
+            public_key,
+        })
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    // Derives the session keys. Our direction tells which of the two keys is ours to send with, as the peer sees the connection the other way around.
+    pub fn agree(self, peer_key: &[u8; 32], direction: Direction) -> Result<Session> {
+        let KeyPair {
+            private_key,
+            public_key,
+        } = self;
+        let (outgoing, incoming) = match direction {
+            Direction::Outgoing => (public_key, *peer_key),
+            Direction::Incoming => (*peer_key, public_key),
+        };
+
+        // Both public keys go into the derivation, so the keys are bound to this exchange
+        let (to_incoming, to_outgoing) = agreement::agree_ephemeral(
+            private_key,
+            &UnparsedPublicKey::new(&X25519, peer_key),
+            |shared_secret| {
+                let mut material = shared_secret.to_vec();
+                material.extend_from_slice(&outgoing);
+                material.extend_from_slice(&incoming);
+                (
+                    blake3::derive_key("bitgeon chat 2021 outgoing to incoming", &material),
+                    blake3::derive_key("bitgeon chat 2021 incoming to outgoing", &material),
+                )
+            },
+        )
+        .map_err(|_| anyhow!("Peer sent an invalid chat key."))?;
+        let (sending, receiving) = match direction {
+            Direction::Outgoing => (to_incoming, to_outgoing),
+            Direction::Incoming => (to_outgoing, to_incoming),
+        };
+
+        let mut transcript = outgoing.to_vec();
+        transcript.extend_from_slice(&incoming);
+        Ok(Session {
+            sending: key(&sending)?,
+            receiving: key(&receiving)?,
+            next_counter: 0,
+            received_counter: None,
+            safety_code: safety_code(&transcript),
+        })
+    }
+}
+
+fn key(bytes: &[u8; 32]) -> Result<LessSafeKey> {
+    UnboundKey::new(&CHACHA20_POLY1305, bytes)
+        .map(LessSafeKey::new)
+        .map_err(|_| anyhow!("Unable to set up chat key."))
+}
+
+// Short enough to read out over the phone
+fn safety_code(transcript: &[u8]) -> String {
+    let hash = blake3::derive_key("bitgeon chat 2021 safety code", transcript);
+    hash[..8]
+        .chunks(2)
+        .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// What a sealed message is meant to be. It is authenticated along with the message, so a chat message can't be passed off as a snippet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Chat,
+    Snippet, // Text to copy, like a URL or a config file. Isn't kept in the history.
+}
+
+impl Kind {
+    fn aad(self) -> Aad<[u8; 1]> {
+        Aad::from([self as u8])
+    }
+}
+
+// Keys of a single connection. Each direction has its own key, so counters can never collide.
+pub struct Session {
+    sending: LessSafeKey,
+    receiving: LessSafeKey,
+    next_counter: u64,
+    received_counter: Option<u64>, // Anything at or below this is a replay
+    safety_code: String,
+}
+
+impl Session {
+    pub fn safety_code(&self) -> &str {
+        &self.safety_code
+    }
+
+    pub fn seal(&mut self, kind: Kind, text: &str) -> Result<Message> {
+        let counter = self.next_counter;
+        self.next_counter = counter
+            .checked_add(1)
+            .with_context(|| String::from("Chat counter exhausted."))?;
+
+        let mut ciphertext = text.as_bytes().to_vec();
+        self.sending
+            .seal_in_place_append_tag(nonce(counter), kind.aad(), &mut ciphertext)
+            .map_err(|_| anyhow!("Unable to encrypt chat message."))?;
+        Ok(match kind {
+            Kind::Chat => Message::Chat {
+                counter,
+                ciphertext,
+            },
+            Kind::Snippet => Message::Snippet {
+                counter,
+                ciphertext,
+            },
+        })
+    }
+
+    pub fn open(&mut self, kind: Kind, counter: u64, ciphertext: &[u8]) -> Result<String> {
+        if self
+            .received_counter
+            .is_some_and(|received| counter <= received)
+        {
+            return Err(anyhow!("Chat message {} was replayed.", counter));
+        }
+
+        let mut plaintext = ciphertext.to_vec();
+        let length = self
+            .receiving
+            .open_in_place(nonce(counter), kind.aad(), &mut plaintext)
+            .map_err(|_| anyhow!("Chat message {} failed to decrypt.", counter))?
+            .len();
+        plaintext.truncate(length);
+        self.received_counter = Some(counter);
+
+        String::from_utf8(plaintext).map_err(|_| anyhow!("Chat message {} is not text.", counter))
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[aead::NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Author {
+    Us,
+    Peer,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Line {
+    pub timestamp: time::SystemTime,
+    pub author: Author,
+    pub text: String,
+}
+
+impl Line {
+    pub fn new(author: Author, text: &str) -> Line {
+        Line {
+            timestamp: time::SystemTime::now(),
+            author,
+            text: text.to_string(),
+        }
+    }
+
+    // One line per message in the history file: seconds since the epoch, author and the text, separated by tabs
+    fn encode(&self) -> String {
+        let seconds = self
+            .timestamp
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let author = match self.author {
+            Author::Us => "us",
+            Author::Peer => "peer",
+        };
+        format!("{}\t{}\t{}", seconds, author, escape(&self.text))
+    }
+
+    fn decode(line: &str) -> Option<Line> {
+        let mut fields = line.splitn(3, '\t');
+        let seconds = fields.next()?.parse().ok()?;
+        let author = match fields.next()? {
+            "us" => Author::Us,
+            "peer" => Author::Peer,
+            _ => return None,
+        };
+        Some(Line {
+            timestamp: time::UNIX_EPOCH + time::Duration::from_secs(seconds),
+            author,
+            text: unescape(fields.next()?),
+        })
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let author = match self.author {
+            Author::Us => "You",
+            Author::Peer => "Peer",
+        };
+        write!(
+            f,
+            "{} {}: {}",
+            util::format_timestamp(self.timestamp),
+            author,
+            self.text
+        )
+    }
+}
+
+// Messages may span several lines, which the history file can't have
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut characters = text.chars();
+    while let Some(character) = characters.next() {
+        if character != '\\' {
+            unescaped.push(character);
+            continue;
+        }
+        match characters.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+// Peers don't have a lasting identity yet, so the history goes by IP address
+pub fn history_path(directory: &Path, peer: IpAddr) -> PathBuf {
+    // Colons in IPv6 addresses are not allowed in file names on Windows
+    directory.join(format!("{}.log", peer.to_string().replace(':', "-")))
+}
+
+// Latest lines of the history with the given peer. Lines that can't be read are skipped.
+pub fn load_history(directory: &Path, peer: IpAddr, count: usize) -> Result<Vec<Line>> {
+    let path = history_path(directory, peer);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Unable to read chat history \"{}\".", path.display()))?;
+
+    let lines: Vec<Line> = contents.lines().filter_map(Line::decode).collect();
+    let skip = lines.len().saturating_sub(count);
+    Ok(lines.into_iter().skip(skip).collect())
+}
+
+pub fn append_history(directory: &Path, peer: IpAddr, line: &Line) -> Result<()> {
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Unable to create directory \"{}\".", directory.display()))?;
+    let path = history_path(directory, peer);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Unable to open chat history \"{}\".", path.display()))?;
+    writeln!(file, "{}", line.encode())
+        .with_context(|| format!("Unable to write chat history \"{}\".", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    fn sessions() -> (Session, Session) {
+        let ours = KeyPair::generate().unwrap();
+        let theirs = KeyPair::generate().unwrap();
+        let (our_key, their_key) = (ours.public_key(), theirs.public_key());
+        (
+            ours.agree(&their_key, Direction::Outgoing).unwrap(),
+            theirs.agree(&our_key, Direction::Incoming).unwrap(),
+        )
+    }
+
+    fn open(session: &mut Session, message: Message) -> Result<String> {
+        match message {
+            Message::Chat {
+                counter,
+                ciphertext,
+            } => session.open(Kind::Chat, counter, &ciphertext),
+            Message::Snippet {
+                counter,
+                ciphertext,
+            } => session.open(Kind::Snippet, counter, &ciphertext),
+            message => panic!("Not a chat message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn encrypted() {
+        let (mut ours, mut theirs) = sessions();
+        assert_eq!(ours.safety_code(), theirs.safety_code());
+        assert_eq!(ours.safety_code().len(), 19);
+
+        let message = ours.seal(Kind::Chat, "Did you get it?").unwrap();
+        if let Message::Chat { ciphertext, .. } = &message {
+            assert!(!ciphertext.windows(3).any(|window| window == b"get"));
+        }
+        assert_eq!(
+            open(&mut theirs, message.clone()).unwrap(),
+            "Did you get it?"
+        );
+        assert!(open(&mut theirs, message).is_err()); // Replayed
+
+        let answer = theirs.seal(Kind::Chat, "Got it 👍").unwrap();
+        assert_eq!(open(&mut ours, answer).unwrap(), "Got it 👍");
+
+        // Tampering is noticed
+        if let Message::Chat {
+            counter,
+            mut ciphertext,
+        } = ours.seal(Kind::Chat, "Send the rest").unwrap()
+        {
+            ciphertext[0] ^= 1;
+            assert!(theirs.open(Kind::Chat, counter, &ciphertext).is_err());
+        }
+        if let Message::Chat {
+            counter,
+            ciphertext,
+        } = ours.seal(Kind::Chat, "https://example.com").unwrap()
+        {
+            assert!(theirs.open(Kind::Snippet, counter, &ciphertext).is_err());
+        }
+        let snippet = ours.seal(Kind::Snippet, "port = 31415\n").unwrap();
+        assert_eq!(open(&mut theirs, snippet).unwrap(), "port = 31415\n");
+
+        // So is a message from another session
+        let (mut other, _) = sessions();
+        assert!(open(&mut theirs, other.seal(Kind::Chat, "Hi").unwrap()).is_err());
+    }
+
+    #[test]
+    fn history() {
+        let directory = TempDir::new("chat-history");
+        let peer: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(load_history(directory.path(), peer, 10).unwrap().is_empty());
+
+        let lines = vec![
+            Line::new(Author::Us, "Did you get it?"),
+            Line::new(Author::Peer, "Not yet.\nStill at 40 %\t\\o/"),
+            Line::new(Author::Us, ""),
+        ];
+        for line in &lines {
+            append_history(directory.path(), peer, line).unwrap();
+        }
+
+        let loaded = load_history(directory.path(), peer, 10).unwrap();
+        assert_eq!(loaded.len(), 3);
+        for (loaded, line) in loaded.iter().zip(&lines) {
+            assert_eq!(loaded.author, line.author);
+            assert_eq!(loaded.text, line.text);
+        }
+        assert_eq!(
+            load_history(directory.path(), peer, 1).unwrap()[0].author,
+            Author::Us
+        );
+        assert!(history_path(directory.path(), peer)
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("2001-db8--1"));
+    }
+}
@@ -0,0 +1,119 @@
+// Deduplication: files are split into blocks at fixed offsets, and a block that is the same as one earlier in the manifest
+// goes out as a reference to that one instead of its data. The receiver has the earlier block already, and copies it, or
+// clones it on filesystems that share extents between files (btrfs, XFS), which takes no space at all.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+
+// Small enough to find duplicates within files, large enough to keep the index small
+pub const BLOCK_SIZE: u64 = 1 << 20;
+
+// Where the duplicate blocks of a source can be copied from, by file and offset
+#[derive(Debug, Default)]
+pub struct Duplicates(BTreeMap<(usize, u64), (usize, u64)>);
+
+impl Duplicates {
+    // Takes the hashes of every block of every file, in manifest order. Files and blocks go out in that order, so only
+    // the first of each kind is sent and all others refer to it.
+    pub fn find(blocks: &[Vec<blake3::Hash>]) -> Duplicates {
+        let mut first = HashMap::new();
+        let mut duplicates = BTreeMap::new();
+        for (file, hashes) in blocks.iter().enumerate() {
+            for (block, hash) in hashes.iter().enumerate() {
+                let offset = block as u64 * BLOCK_SIZE;
+                match first.get(hash) {
+                    Some(earlier) => {
+                        duplicates.insert((file, offset), *earlier);
+                    }
+                    None => {
+                        first.insert(*hash, (file, offset));
+                    }
+                }
+            }
+        }
+        Duplicates(duplicates)
+    }
+
+    // Where the block at the offset can be copied from, if it's a duplicate
+    pub fn get(&self, file: usize, offset: u64) -> Option<(usize, u64)> {
+        self.0.get(&(file, offset)).copied()
+    }
+
+    // Where the first duplicate block after the offset starts, if there is one before the end. Chunks of data stop there.
+    pub fn next(&self, file: usize, offset: u64, end: u64) -> Option<u64> {
+        self.0
+            .range((file, offset + 1)..(file, end))
+            .next()
+            .map(|((_, offset), _)| *offset)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// Makes the range of the target share its storage with the range of the source. Fails where the filesystem can't do that,
+// or the offsets aren't aligned to its blocks. The data is copied then.
+#[cfg(target_os = "linux")]
+pub fn clone_range(
+    source: &File,
+    source_offset: u64,
+    target: &File,
+    target_offset: u64,
+    length: u64,
+) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let range = libc::file_clone_range {
+        src_fd: source.as_raw_fd() as i64,
+        src_offset: source_offset,
+        src_length: length,
+        dest_offset: target_offset,
+    };
+    match unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONERANGE, &range) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn clone_range(
+    _source: &File,
+    _source_offset: u64,
+    _target: &File,
+    _target_offset: u64,
+    _length: u64,
+) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates() {
+        let hash = |byte: u8| blake3::hash(&[byte]);
+        let duplicates = Duplicates::find(&[
+            vec![hash(1), hash(2), hash(1)],
+            vec![],
+            vec![hash(3), hash(2)],
+        ]);
+        assert_eq!(duplicates.len(), 2);
+        assert_eq!(duplicates.get(0, 0), None);
+        assert_eq!(duplicates.get(0, 2 * BLOCK_SIZE), Some((0, 0)));
+        assert_eq!(duplicates.get(2, BLOCK_SIZE), Some((0, BLOCK_SIZE)));
+
+        // Chunks of data stop at the next duplicate, but not at one they start at
+        assert_eq!(duplicates.next(0, 0, 3 * BLOCK_SIZE), Some(2 * BLOCK_SIZE));
+        assert_eq!(duplicates.next(0, 0, 2 * BLOCK_SIZE), None);
+        assert_eq!(duplicates.next(0, 2 * BLOCK_SIZE, 3 * BLOCK_SIZE), None);
+        assert_eq!(duplicates.next(1, 0, u64::MAX), None);
+    }
+}
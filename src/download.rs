@@ -0,0 +1,764 @@
+// Received files are written to a partial file next to their final location, and only renamed into place
+// once their contents match the hash from the manifest. So a file under its final name is always complete.
+// Partial files of interrupted downloads are started over by the next download of the same file, or cleaned up once they get old.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time;
+
+use anyhow::{anyhow, Context, Result};
+use walkdir::WalkDir;
+
+use crate::dedup;
+use crate::manifest::Manifest;
+use crate::sandbox::Sandbox;
+use crate::sparse;
+use crate::util;
+use crate::zerocopy;
+
+// Distinctive enough that cleaning up never touches partial files of other programs
+pub const PART_EXTENSION: &str = "bitgeon.part";
+
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(PART_EXTENSION);
+    path.with_file_name(name)
+}
+
+pub fn is_part_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(&format!(".{}", PART_EXTENSION)))
+}
+
+pub struct PartialFile {
+    file: File,
+    path: PathBuf, // Final location
+    part_path: PathBuf,
+    hasher: blake3::Hasher,
+    written: u64,
+}
+
+impl PartialFile {
+    // Opens the partial file for the given final path. Leftovers of an earlier attempt are thrown away, as senders always
+    // start files from the beginning. The path is expected to come out of the sandbox.
+    pub fn open(path: &Path) -> Result<PartialFile> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Unable to create directory \"{}\".", parent.display()))?;
+        }
+
+        let part_path = part_path(path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&part_path)
+            .with_context(|| format!("Unable to open \"{}\".", part_path.display()))?;
+
+        Ok(PartialFile {
+            file,
+            path: path.to_path_buf(),
+            part_path,
+            hasher: blake3::Hasher::new(),
+            written: 0,
+        })
+    }
+
+    // Number of bytes received so far, i.e. where the next piece has to start
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file
+            .write_all(data)
+            .with_context(|| format!("Unable to write to \"{}\".", self.part_path.display()))?;
+        self.hasher.update(data);
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    // Leaves the range unallocated, for holes in sparse files. It reads as zeros, so that's what goes into the hash.
+    pub fn hole(&mut self, length: u64) -> Result<()> {
+        let end = self.written + length;
+        self.file
+            .set_len(end)
+            .and_then(|_| self.file.seek(SeekFrom::Start(end)))
+            .with_context(|| format!("Unable to write to \"{}\".", self.part_path.display()))?;
+        let zeros = [0; 64 * 1024];
+        let mut left = length;
+        while left > 0 {
+            let hashed = left.min(zeros.len() as u64);
+            self.hasher.update(&zeros[..hashed as usize]);
+            left -= hashed;
+        }
+        self.written = end;
+        Ok(())
+    }
+
+    // Like `write`, with the data of a range of a file received before. The range shares its storage with the source,
+    // where the filesystem can do that. It has to be read for the hash anyway.
+    pub fn copy(&mut self, source: &File, offset: u64, length: u64) -> Result<()> {
+        let data = zerocopy::read(source, offset, length)
+            .with_context(|| format!("Unable to copy to \"{}\".", self.part_path.display()))?;
+        let end = self.written + length;
+        let cloned = dedup::clone_range(source, offset, &self.file, self.written, length)
+            .and_then(|_| self.file.seek(SeekFrom::Start(end)));
+        match cloned {
+            Ok(_) => {
+                self.hasher.update(&data);
+                self.written = end;
+                Ok(())
+            }
+            Err(_) => self.write(&data),
+        }
+    }
+
+    // Moves the file into place, if it is complete and intact. Otherwise, the partial file is thrown away, as it's of no use
+    // to anyone. Existing files are only replaced when asked to, i.e. for folder sync.
+    pub fn finish(self, expected: &blake3::Hash, replace: bool) -> Result<PathBuf> {
+        let PartialFile {
+            file,
+            path,
+            part_path,
+            hasher,
+            ..
+        } = self;
+
+        // Everything needs to be on disk before the rename, or a crash could leave a complete-looking but empty file behind
+        file.sync_all()
+            .with_context(|| format!("Unable to flush \"{}\".", part_path.display()))?;
+        drop(file);
+
+        let hash = hasher.finalize();
+        if hash != *expected {
+            let _ = fs::remove_file(&part_path);
+            return Err(anyhow!(
+                "\"{}\" is corrupted. Expected hash {}, but got {}.",
+                path.display(),
+                expected.to_hex(),
+                hash.to_hex()
+            ));
+        }
+
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if replace && metadata.is_file() => (),
+            Ok(_) => return Err(anyhow!("\"{}\" already exists.", path.display())),
+            Err(_) => (),
+        }
+        fs::rename(&part_path, &path).with_context(|| {
+            format!(
+                "Unable to rename \"{}\" to \"{}\".",
+                part_path.display(),
+                path.display()
+            )
+        })?;
+        sync_directory(&path);
+
+        Ok(path)
+    }
+}
+
+// What comes in for a file: a chunk of it, a hole, or a range that is the same as one received before
+#[derive(Clone, Copy, Debug)]
+pub enum Piece<'a> {
+    Data(&'a [u8]),
+    Hole(u64),
+    Copy { file: u32, offset: u64, length: u64 }, // Where the range is to be found
+}
+
+impl Piece<'_> {
+    fn len(&self) -> u64 {
+        match self {
+            Piece::Data(data) => data.len() as u64,
+            Piece::Hole(length) | Piece::Copy { length, .. } => *length,
+        }
+    }
+}
+
+// An accepted offer, written to disk as the chunks come in. Files arrive one after the other, in manifest order.
+pub struct Download {
+    manifest: Manifest,
+    sandbox: Sandbox,
+    current: Option<(u32, PartialFile)>,
+    finished: BTreeMap<u32, PathBuf>, // Files verified and moved into place, for copying from
+    received: u64,
+    completed: usize,             // Files verified and moved into place
+    replace: bool,                // Whether existing files are overwritten
+    skipped: BTreeSet<u32>,       // Files whose chunks are taken but not written
+    skipping: Option<(u32, u64)>, // Skipped file in progress, and how much of it has come in
+}
+
+impl Download {
+    pub fn new(directory: &Path, manifest: Manifest) -> Result<Download> {
+        fs::create_dir_all(directory)
+            .with_context(|| format!("Unable to create directory \"{}\".", directory.display()))?;
+        Ok(Download {
+            manifest,
+            sandbox: Sandbox::new(directory)?,
+            current: None,
+            finished: BTreeMap::new(),
+            received: 0,
+            completed: 0,
+            replace: false,
+            skipped: BTreeSet::new(),
+            skipping: None,
+        })
+    }
+
+    // For folder sync, where changed files come again under the same name
+    pub fn replacing(directory: &Path, manifest: Manifest) -> Result<Download> {
+        let mut download = Download::new(directory, manifest)?;
+        download.replace = true;
+        Ok(download)
+    }
+
+    // Offers are taken as a whole, but two-way sync may already have a file, or a newer one. See shared.rs
+    pub fn skip(&mut self, file: usize) {
+        self.skipped.insert(file as u32);
+    }
+
+    // Returns the final path once the chunk completes a file
+    pub fn write(&mut self, file: u32, offset: u64, data: &[u8]) -> Result<Option<PathBuf>> {
+        self.write_piece(file, offset, Piece::Data(data))
+    }
+
+    // Like `write`, for a range the sender has as a hole. It stays a hole here as well. See sparse.rs
+    pub fn write_hole(&mut self, file: u32, offset: u64, length: u64) -> Result<Option<PathBuf>> {
+        self.write_piece(file, offset, Piece::Hole(length))
+    }
+
+    pub fn write_piece(&mut self, file: u32, offset: u64, piece: Piece) -> Result<Option<PathBuf>> {
+        let length = piece.len();
+        let entry = self
+            .manifest
+            .entries
+            .get(file as usize)
+            .ok_or_else(|| anyhow!("There is no file {} in the offer.", file))?;
+
+        // Nothing is written, but the chunks still have to add up
+        if self.skipped.contains(&file) {
+            let written = match self.skipping {
+                Some((current, written)) if current == file => written,
+                _ => 0,
+            };
+            if offset != written || written.saturating_add(length) > entry.size {
+                return Err(anyhow!("Unexpected chunk for \"{}\".", entry.path));
+            }
+            let written = written + length;
+            self.received += length;
+            self.skipping = Some((file, written));
+            if written == entry.size {
+                self.skipping = None;
+                self.completed += 1;
+            }
+            return Ok(None);
+        }
+
+        let mut partial = match self.current.take() {
+            Some((current, partial)) if current == file => partial,
+            _ => {
+                let path = self
+                    .sandbox
+                    .resolve(&entry.path)
+                    .with_context(|| format!("Refusing to write \"{}\".", entry.path))?;
+                PartialFile::open(&path)?
+            }
+        };
+        // Pieces that don't fit are refused without losing what was received so far, as opening the file again starts it over
+        let error = if offset != partial.written() {
+            Some(anyhow!(
+                "Expected \"{}\" to continue at {}, but got {}.",
+                entry.path,
+                partial.written(),
+                offset
+            ))
+        } else if partial.written().saturating_add(length) > entry.size {
+            Some(anyhow!("\"{}\" is larger than offered.", entry.path))
+        } else if matches!(piece, Piece::Hole(_)) && length > sparse::MAX_HOLE {
+            Some(anyhow!(
+                "Hole of {} bytes in \"{}\" is too long.",
+                length,
+                entry.path
+            ))
+        } else if matches!(piece, Piece::Copy { .. }) && length > dedup::BLOCK_SIZE {
+            // Copies are read into memory for the hash, and senders never copy more than a block
+            Some(anyhow!(
+                "Copy of {} bytes into \"{}\" is larger than a block.",
+                length,
+                entry.path
+            ))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            self.current = Some((file, partial));
+            return Err(error);
+        }
+
+        match piece {
+            Piece::Data(data) => partial.write(data)?,
+            Piece::Hole(length) => partial.hole(length)?,
+            // Earlier in the same file
+            Piece::Copy {
+                file: source,
+                offset: source_offset,
+                length,
+            } if source == file => {
+                if source_offset.saturating_add(length) > partial.written() {
+                    return Err(anyhow!("Unexpected copy within \"{}\".", entry.path));
+                }
+                let source = partial.file.try_clone().with_context(|| {
+                    format!("Unable to read \"{}\".", partial.part_path.display())
+                })?;
+                partial.copy(&source, source_offset, length)?
+            }
+            Piece::Copy {
+                file: source,
+                offset: source_offset,
+                length,
+            } => {
+                // Skipped files are whatever we had before, so only the ones written here will do
+                let path = self.finished.get(&source).ok_or_else(|| {
+                    anyhow!("\"{}\" refers to a file that wasn't received.", entry.path)
+                })?;
+                let source = File::open(path)
+                    .with_context(|| format!("Unable to open \"{}\".", path.display()))?;
+                partial
+                    .copy(&source, source_offset, length)
+                    .with_context(|| format!("Unable to copy from \"{}\".", path.display()))?
+            }
+        }
+        self.received += length;
+        if partial.written() < entry.size {
+            self.current = Some((file, partial));
+            return Ok(None);
+        }
+        let path = partial.finish(&entry.hash, self.replace)?;
+        self.finished.insert(file, path.clone());
+        self.completed += 1;
+        Ok(Some(path))
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed == self.manifest.len()
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub fn status(&self, peer: usize, address: SocketAddr, state: State) -> Status {
+        Status {
+            peer,
+            address,
+            directory: self.sandbox.root().to_path_buf(),
+            files: self.manifest.len(),
+            completed: self.completed,
+            total_size: self.manifest.total_size(),
+            received: self.received,
+            state,
+        }
+    }
+}
+
+// How a download is getting along
+#[derive(Clone, Debug, PartialEq)]
+pub enum State {
+    Receiving,
+    Done,
+    Failed(String),
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Receiving => write!(f, "Receiving"),
+            State::Done => write!(f, "Done"),
+            State::Failed(error) => write!(f, "Failed: {}", error),
+        }
+    }
+}
+
+// Snapshot of a download, for displaying progress
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub peer: usize,
+    pub address: SocketAddr,
+    pub directory: PathBuf,
+    pub files: usize,
+    pub completed: usize,
+    pub total_size: u64,
+    pub received: u64,
+    pub state: State,
+}
+
+impl Status {
+    pub fn percent(&self) -> u64 {
+        match self.total_size {
+            0 => 100,
+            total => self.received.min(total) * 100 / total,
+        }
+    }
+}
+
+// Makes the rename itself durable
+#[cfg(unix)]
+fn sync_directory(path: &Path) {
+    if let Some(parent) = path.parent() {
+        if let Ok(directory) = File::open(parent) {
+            let _ = directory.sync_all();
+        }
+    }
+}
+
+// Windows has no way to sync a directory, and doesn't need it
+#[cfg(not(unix))]
+fn sync_directory(_path: &Path) {}
+
+// Removes files a synced folder no longer has. Only regular files go, and the directories that are left empty.
+// Returns the paths that were removed.
+pub fn remove_files(directory: &Path, paths: &[String]) -> Result<Vec<PathBuf>> {
+    let sandbox = Sandbox::new(directory)?;
+    let mut removed = vec![];
+    for name in paths {
+        let path = sandbox
+            .resolve(name)
+            .with_context(|| format!("Refusing to remove \"{}\".", name))?;
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() => (),
+            _ => continue, // Gone already, or not ours to remove
+        }
+        fs::remove_file(&path)
+            .with_context(|| format!("Unable to remove \"{}\".", path.display()))?;
+
+        // Fails for directories that still hold something, which is just what we want
+        let mut parent = path.parent();
+        while let Some(directory) = parent.filter(|directory| *directory != sandbox.root()) {
+            if fs::remove_dir(directory).is_err() {
+                break;
+            }
+            parent = directory.parent();
+        }
+        removed.push(path);
+    }
+    Ok(removed)
+}
+
+// Writes text somebody sent us to a new file in the given directory. Numbers are added to the name until it is unique.
+pub fn save_text(directory: &Path, name: &str, text: &str) -> Result<PathBuf> {
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Unable to create directory \"{}\".", directory.display()))?;
+
+    for attempt in 0.. {
+        let path = match attempt {
+            0 => directory.join(format!("{}.txt", name)),
+            _ => directory.join(format!("{} ({}).txt", name, attempt)),
+        };
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(error) => {
+                return Err(error)
+                    .with_context(|| format!("Unable to create \"{}\".", path.display()))
+            }
+        };
+        file.write_all(text.as_bytes())
+            .with_context(|| format!("Unable to write to \"{}\".", path.display()))?;
+        return Ok(path);
+    }
+    unreachable!()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Space {
+    Sufficient,
+    Tight, // Fits, but leaves less than the safety margin
+    Insufficient,
+}
+
+// Free space on the destination compared to what an offer needs, checked before it is accepted.
+// Finding out halfway through a 200 GB transfer is no fun.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preflight {
+    pub required: u64,
+    pub available: u64,
+    pub margin: u64, // Should stay free after the transfer
+}
+
+impl Preflight {
+    pub fn check(directory: &Path, required: u64, margin: u64) -> Result<Preflight> {
+        // The download directory may not exist yet. What matters is the file system it will end up on.
+        let existing = directory
+            .ancestors()
+            .find(|path| path.exists())
+            .unwrap_or_else(|| Path::new("."));
+        let available = fs2::available_space(existing)
+            .with_context(|| format!("Unable to get free space of \"{}\".", existing.display()))?;
+
+        Ok(Preflight {
+            required,
+            available,
+            margin,
+        })
+    }
+
+    pub fn space(&self) -> Space {
+        match self.available.checked_sub(self.required) {
+            None => Space::Insufficient,
+            Some(left) if left < self.margin => Space::Tight,
+            Some(_) => Space::Sufficient,
+        }
+    }
+}
+
+impl fmt::Display for Preflight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Needs {} of {} free. ",
+            util::format_size(self.required),
+            util::format_size(self.available)
+        )?;
+        match self.space() {
+            Space::Sufficient => write!(f, "Enough space"),
+            Space::Tight => write!(
+                f,
+                "Less than {} would be left",
+                util::format_size(self.margin)
+            ),
+            Space::Insufficient => write!(f, "Not enough space"),
+        }
+    }
+}
+
+// Goes through the download directory on startup. Partial files older than the given age are removed, and returned.
+// Younger ones are left alone, as another instance may still be writing them.
+pub fn clean_up(root: &Path, max_age: time::Duration) -> Result<Vec<PathBuf>> {
+    let mut removed = vec![];
+    if !root.exists() {
+        return Ok(removed);
+    }
+
+    // Symbolic links are neither followed nor removed, as they can't be ours
+    for entry in WalkDir::new(root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+    {
+        if !entry.file_type().is_file() || !is_part_file(entry.path()) {
+            continue;
+        }
+
+        let age = entry
+            .metadata()
+            .ok()
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.elapsed().ok())
+            .unwrap_or_default();
+        if age >= max_age {
+            fs::remove_file(entry.path())
+                .with_context(|| format!("Unable to remove \"{}\".", entry.path().display()))?;
+            removed.push(entry.into_path());
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+
+    #[test]
+    fn verified_rename() {
+        let directory = TempDir::new("download");
+        let path = directory.path().join("photos").join("ferris.jpg");
+        let contents = b"crab".repeat(1000);
+
+        // Interrupted halfway through, and started over
+        let mut partial = PartialFile::open(&path).unwrap();
+        partial.write(&contents[..1500]).unwrap();
+        drop(partial);
+        assert!(!path.exists());
+        assert!(part_path(&path).exists());
+
+        let mut partial = PartialFile::open(&path).unwrap();
+        assert_eq!(partial.written(), 0);
+        partial.write(&contents).unwrap();
+        assert_eq!(
+            partial.finish(&blake3::hash(&contents), false).unwrap(),
+            path.clone()
+        );
+        assert_eq!(fs::read(&path).unwrap(), contents);
+        assert!(!part_path(&path).exists());
+
+        // Never replace what is there already
+        let mut partial = PartialFile::open(&path).unwrap();
+        partial.write(&contents).unwrap();
+        assert!(partial.finish(&blake3::hash(&contents), false).is_err());
+    }
+
+    #[test]
+    fn corrupted() {
+        let directory = TempDir::new("download-corrupted");
+        let path = directory.path().join("ferris.jpg");
+
+        let mut partial = PartialFile::open(&path).unwrap();
+        partial.write(b"crab").unwrap();
+        assert!(partial.finish(&blake3::hash(b"crap"), false).is_err());
+        assert!(!path.exists());
+        assert!(!part_path(&path).exists());
+    }
+
+    #[test]
+    fn download() {
+        let directory = TempDir::new("download-offer");
+        let entry = |path: &str, contents: &[u8]| crate::manifest::ManifestEntry {
+            path: String::from(path),
+            size: contents.len() as u64,
+            hash: blake3::hash(contents),
+        };
+        let manifest = Manifest::new(vec![
+            entry("photos/ferris.jpg", b"crab"),
+            entry("empty", b""),
+            entry("../escape", b"x"),
+        ]);
+
+        // Leftovers from an earlier attempt are replaced
+        fs::create_dir(directory.path().join("photos")).unwrap();
+        fs::write(
+            part_path(&directory.path().join("photos").join("ferris.jpg")),
+            b"old",
+        )
+        .unwrap();
+
+        let mut download = Download::new(directory.path(), manifest).unwrap();
+        assert_eq!(download.write(0, 0, b"cr").unwrap(), None);
+        assert!(download.write(0, 1, b"ab").is_err()); // Overlaps
+        assert!(download.write(0, 2, b"abs").is_err()); // Larger than offered
+        let path = download.write(0, 2, b"ab").unwrap().unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"crab");
+        assert!(download.write(1, 0, b"").unwrap().is_some());
+        assert!(!download.is_complete());
+        assert!(download.write(2, 0, b"x").is_err());
+        assert!(download.write(3, 0, b"").is_err());
+        assert_eq!(download.received(), 4);
+
+        let status = download.status(1, "203.0.113.7:31415".parse().unwrap(), State::Receiving);
+        assert_eq!((status.completed, status.files), (2, 3));
+        assert_eq!(status.percent(), 80);
+        assert_eq!(status.directory, directory.path());
+    }
+
+    #[test]
+    fn oversized_pieces() {
+        let directory = TempDir::new("download-oversized");
+        // The sender gets to say how large files are, and so how much a single piece may claim
+        let manifest = Manifest::new(vec![crate::manifest::ManifestEntry {
+            path: String::from("huge"),
+            size: 1 << 40,
+            hash: blake3::hash(b""),
+        }]);
+        let mut download = Download::new(directory.path(), manifest).unwrap();
+        download.write(0, 0, &[7; 16]).unwrap();
+        let copy = Piece::Copy {
+            file: 0,
+            offset: 0,
+            length: dedup::BLOCK_SIZE + 1,
+        };
+        let error = download.write_piece(0, 16, copy).unwrap_err();
+        assert!(error.to_string().contains("larger than a block"));
+        let error = download
+            .write_hole(0, 16, sparse::MAX_HOLE + 1)
+            .unwrap_err();
+        assert!(error.to_string().contains("too long"));
+        assert_eq!(download.received(), 16);
+    }
+
+    #[test]
+    fn saved_text() {
+        let directory = TempDir::new("download-text");
+        let path = save_text(directory.path(), "text", "https://example.com").unwrap();
+        assert_eq!(path, directory.path().join("text.txt"));
+        let path = save_text(directory.path(), "text", "port = 31415").unwrap();
+        assert_eq!(path, directory.path().join("text (1).txt"));
+        assert_eq!(fs::read_to_string(path).unwrap(), "port = 31415");
+    }
+
+    #[test]
+    fn preflight() {
+        let gib = 1024 * 1024 * 1024;
+        let preflight = |required| Preflight {
+            required,
+            available: 10 * gib,
+            margin: gib,
+        };
+        assert_eq!(preflight(5 * gib).space(), Space::Sufficient);
+        assert_eq!(preflight(9 * gib + 1).space(), Space::Tight);
+        assert_eq!(preflight(10 * gib).space(), Space::Tight);
+        assert_eq!(preflight(200 * gib).space(), Space::Insufficient);
+
+        // Directories that don't exist yet are checked on the file system they will be created on
+        let directory = TempDir::new("download-preflight");
+        let preflight = Preflight::check(&directory.path().join("not").join("yet"), 1, 0).unwrap();
+        assert!(preflight.available > 0);
+    }
+
+    #[test]
+    fn stale_partials() {
+        let directory = TempDir::new("download-stale");
+        let root = directory.path();
+        fs::create_dir(root.join("photos")).unwrap();
+        let partial = part_path(&root.join("photos").join("ferris.jpg"));
+        fs::write(&partial, b"crab").unwrap();
+        fs::write(root.join("unrelated.part"), b"").unwrap();
+
+        assert!(clean_up(root, time::Duration::from_secs(3600))
+            .unwrap()
+            .is_empty());
+        assert!(partial.exists());
+
+        let removed = clean_up(root, time::Duration::ZERO).unwrap();
+        assert_eq!(removed, vec![partial.clone()]);
+        assert!(!partial.exists());
+        assert!(root.join("unrelated.part").exists());
+    }
+
+    #[test]
+    fn remove_synced() {
+        let directory = TempDir::new("download-remove");
+        let root = directory.path();
+        fs::create_dir_all(root.join("notes").join("old")).unwrap();
+        fs::write(root.join("notes").join("old").join("monday.md"), b"soup").unwrap();
+        fs::write(root.join("notes").join("today.md"), b"sandwiches").unwrap();
+
+        let removed = remove_files(
+            root,
+            &[
+                String::from("notes/old/monday.md"),
+                String::from("notes/gone.md"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            removed,
+            vec![root.join("notes").join("old").join("monday.md")]
+        );
+        assert!(!root.join("notes").join("old").exists());
+        assert!(root.join("notes").join("today.md").exists());
+
+        assert!(remove_files(root, &[String::from("../outside")]).is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use std::fs::OpenOptions;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use fancy_regex::Regex;
@@ -8,15 +8,34 @@ use once_cell::sync::Lazy;
 use path_absolutize::*;
 use walkdir::{DirEntry, WalkDir};
 
+use crate::link::{self, Link};
+
 #[derive(Clone, PartialEq)]
 pub enum PathState {
     Directory(usize), // Holds number of files in directory
     File,
     Invalid,
+    Link, // A bitgeon:// link rather than a path
     Unchecked,
 }
 
+// Splits pasted text into paths. bitgeon:// links are kept whole, as the regex below would cut them apart at "n:".
 pub fn parse_paths(path_string: &str) -> Result<Vec<String>> {
+    let mut paths = vec![];
+    let mut rest = path_string;
+    while let Some(start) = rest.to_ascii_lowercase().find(link::SCHEME) {
+        paths.extend(split_paths(&rest[..start])?);
+        let end = rest[start..]
+            .find(|c: char| c.is_whitespace() || c == ';' || c == '"')
+            .map_or(rest.len(), |end| start + end);
+        paths.push(rest[start..end].to_string());
+        rest = &rest[end..];
+    }
+    paths.extend(split_paths(rest)?);
+    Ok(paths)
+}
+
+fn split_paths(path_string: &str) -> Result<Vec<String>> {
     let trim_characters = [';', '\"'];
     let mut paths: Vec<String> = vec![];
 
@@ -37,7 +56,7 @@ pub fn parse_paths(path_string: &str) -> Result<Vec<String>> {
     let mut capture_pos = 0;
     while capture_pos < path_string.len() {
         let result = RE
-            .captures_from_pos(&path_string, capture_pos)
+            .captures_from_pos(path_string, capture_pos)
             .with_context(|| String::from("Error running regex"))?;
 
         match result {
@@ -68,6 +87,12 @@ pub fn check_path(path_string: &str) -> Result<PathState> {
     if path.trim().is_empty() {
         return Ok(PathState::Invalid);
     }
+    if path.to_ascii_lowercase().starts_with(link::SCHEME) {
+        return Ok(match Link::parse(&path) {
+            Ok(_) => PathState::Link,
+            Err(_) => PathState::Invalid,
+        });
+    }
 
     // If the path is relative, trim it and add "./" to the beginning
     let trim_characters = ['\\', '/', '.'];
@@ -115,6 +140,34 @@ pub fn check_path(path_string: &str) -> Result<PathState> {
     Ok(PathState::Invalid)
 }
 
+// Lists the files to send for a checked path, along with the names they get in the manifest.
+// Names are relative to the directory the path is in, so sending "~/photos" gives "photos/ferris.jpg".
+// Hidden files are left out, just like they are left out of the count.
+pub fn collect_files(path: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let path = path
+        .absolutize()
+        .with_context(|| format!("Error turning \"{}\" into absolute path", path.display()))?
+        .to_path_buf();
+    let base = path.parent().unwrap_or(&path).to_path_buf();
+
+    let mut files = vec![];
+    for entry in WalkDir::new(&path).sort_by_file_name() {
+        let entry = entry.with_context(|| format!("Unable to read \"{}\"", path.display()))?;
+        if is_hidden_path(&entry) || !entry.file_type().is_file() {
+            continue;
+        }
+        let name: Vec<String> = entry
+            .path()
+            .strip_prefix(&base)
+            .unwrap_or_else(|_| entry.path())
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        files.push((entry.path().to_path_buf(), name.join("/")));
+    }
+    Ok(files)
+}
+
 // Returns whether a directory entry points to a hidden file or directory
 fn is_hidden_path(entry: &DirEntry) -> bool {
     entry
@@ -126,7 +179,8 @@ fn is_hidden_path(entry: &DirEntry) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
 
     #[test]
     fn parse_paths() {
@@ -157,5 +211,47 @@ mod tests {
         ];
 
         assert_eq!(output.unwrap(), expected_output);
+
+        let input = r"/images/ferris.jpg; bitgeon://203.0.113.7:31415,[::1]:31415?key=3f2a9c01d4e5b678 C:\Users\USERNAME\images";
+
+        let output = super::parse_paths(input);
+
+        let expected_output = vec![
+            "/images/ferris.jpg",
+            "bitgeon://203.0.113.7:31415,[::1]:31415?key=3f2a9c01d4e5b678",
+            "C:\\Users\\USERNAME\\images",
+        ];
+
+        assert_eq!(output.unwrap(), expected_output);
+    }
+
+    proptest! {
+        // Pasted text is whatever the user had in the clipboard
+        #[test]
+        fn parse_anything(text in "\\PC{0,80}") {
+            if let Ok(paths) = super::parse_paths(&text) {
+                for path in paths {
+                    prop_assert!(!path.is_empty());
+                    prop_assert_eq!(path.trim(), path.as_str());
+                    prop_assert!(text.contains(&path));
+                    prop_assert!(!path.contains('\n'));
+                }
+            }
+        }
+
+        // Relative paths and links, separated by semicolons, come back as they were
+        #[test]
+        fn parse_list(
+            paths in vec(
+                prop_oneof![
+                    "[a-z0-9._-]{1,8}(/[a-z0-9._-]{1,8}){0,3}",
+                    "[0-9a-f]{16}".prop_map(|key| format!("bitgeon://192.0.2.1:31415?key={}", key)),
+                ],
+                1..6,
+            )
+        ) {
+            let text = paths.join("; ");
+            prop_assert_eq!(super::parse_paths(&text).unwrap(), paths);
+        }
     }
 }
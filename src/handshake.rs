@@ -0,0 +1,231 @@
+// The first thing both ends of a peer connection send is a hello, with the protocol version they speak, the oldest one they
+// still accept, the optional features they support and the encryption suites they know. Each side works out
+// the same agreement from the two hellos: the lower of the two versions, the features both support and the best suite both know.
+
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::protocol::{self, Message};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 0);
+    pub const DELTA: Capabilities = Capabilities(1 << 1); // Only the changed parts of files the receiver already has
+    pub const RESUME: Capabilities = Capabilities(1 << 2); // Continuing interrupted downloads where they stopped
+    pub const SYNC: Capabilities = Capabilities(1 << 3); // Keeping a folder mirrored. See sync.rs
+    pub const SHARED: Capabilities = Capabilities(1 << 4); // Syncing a folder both ways. See shared.rs
+    pub const SPARSE: Capabilities = Capabilities(1 << 5); // Sending holes in sparse files as such. See sparse.rs
+    pub const DEDUP: Capabilities = Capabilities(1 << 6); // Sending duplicate blocks as references. See dedup.rs
+
+    // What this build offers. Features are added here once they are implemented.
+    pub const SUPPORTED: Capabilities = Capabilities(
+        Capabilities::SYNC.0
+            | Capabilities::SHARED.0
+            | Capabilities::SPARSE.0
+            | Capabilities::DEDUP.0,
+    );
+
+    const NAMES: [(Capabilities, &'static str); 7] = [
+        (Capabilities::COMPRESSION, "compression"),
+        (Capabilities::DELTA, "delta"),
+        (Capabilities::RESUME, "resume"),
+        (Capabilities::SYNC, "sync"),
+        (Capabilities::SHARED, "two-way sync"),
+        (Capabilities::SPARSE, "sparse files"),
+        (Capabilities::DEDUP, "deduplication"),
+    ];
+
+    // Bits we don't know are kept, so they can be passed on as they came
+    pub fn from_bits(bits: u32) -> Capabilities {
+        Capabilities(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub fn common(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = Capabilities::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect();
+        match names.len() {
+            0 => write!(f, "none"),
+            _ => write!(f, "{}", names.join(", ")),
+        }
+    }
+}
+
+// Key exchange and cipher used for chat and snippets. The numbers go out on the wire, so they never change.
+// A better suite gets a higher number than the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Suite {
+    X25519ChaCha20Poly1305 = 1,
+}
+
+impl Suite {
+    pub const SUPPORTED: [Suite; 1] = [Suite::X25519ChaCha20Poly1305];
+
+    pub fn from_id(id: u8) -> Option<Suite> {
+        Suite::SUPPORTED
+            .iter()
+            .copied()
+            .find(|suite| *suite as u8 == id)
+    }
+}
+
+impl fmt::Display for Suite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Suite::X25519ChaCha20Poly1305 => write!(f, "X25519 + ChaCha20-Poly1305"),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum HandshakeError {
+    #[error(
+        "Peer speaks protocol version {0}, but we need at least {}",
+        protocol::MIN_VERSION
+    )]
+    PeerTooOld(u16),
+    #[error(
+        "Peer needs protocol version {0} or later, but we speak {}",
+        protocol::VERSION
+    )]
+    PeerTooNew(u16),
+}
+
+// What both ends go with for the rest of the connection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Agreement {
+    pub version: u16,
+    pub capabilities: Capabilities,
+    pub suite: Option<Suite>, // None if there is no suite in common. Chat stays unavailable then.
+}
+
+impl fmt::Display for Agreement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Protocol version {}, features: {}, encryption: ",
+            self.version, self.capabilities
+        )?;
+        match self.suite {
+            Some(suite) => write!(f, "{}", suite),
+            None => write!(f, "none in common"),
+        }
+    }
+}
+
+pub fn hello() -> Message {
+    Message::Hello {
+        version: protocol::VERSION,
+        minimum_version: protocol::MIN_VERSION,
+        capabilities: Capabilities::SUPPORTED.bits(),
+        suites: Suite::SUPPORTED.iter().map(|suite| *suite as u8).collect(),
+    }
+}
+
+// Takes the fields of the peer's hello. Both ends have to arrive at the same result without another round trip,
+// so the suite is simply the highest numbered one both know. Newer suites get higher numbers.
+pub fn negotiate(
+    version: u16,
+    minimum_version: u16,
+    capabilities: u32,
+    suites: &[u8],
+) -> Result<Agreement, HandshakeError> {
+    if version < protocol::MIN_VERSION {
+        return Err(HandshakeError::PeerTooOld(version));
+    }
+    if minimum_version > protocol::VERSION {
+        return Err(HandshakeError::PeerTooNew(minimum_version));
+    }
+
+    let suite = suites
+        .iter()
+        .filter_map(|id| Suite::from_id(*id))
+        .max_by_key(|suite| *suite as u8);
+    Ok(Agreement {
+        version: version.min(protocol::VERSION),
+        capabilities: Capabilities::SUPPORTED.common(Capabilities::from_bits(capabilities)),
+        suite,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        // Ourselves
+        let agreement = match hello() {
+            Message::Hello {
+                version,
+                minimum_version,
+                capabilities,
+                suites,
+            } => negotiate(version, minimum_version, capabilities, &suites).unwrap(),
+            message => panic!("Unexpected message {:?}", message),
+        };
+        assert_eq!(
+            agreement,
+            Agreement {
+                version: protocol::VERSION,
+                capabilities: Capabilities::SUPPORTED,
+                suite: Some(Suite::X25519ChaCha20Poly1305),
+            }
+        );
+
+        // A newer build with features and suites we don't know yet, that still talks to us
+        let newer = negotiate(protocol::VERSION + 3, protocol::VERSION, u32::MAX, &[9, 1]).unwrap();
+        assert_eq!(newer.version, protocol::VERSION);
+        assert_eq!(newer.capabilities, Capabilities::SUPPORTED);
+        assert_eq!(newer.suite, Some(Suite::X25519ChaCha20Poly1305));
+
+        let strangers = negotiate(protocol::VERSION, protocol::MIN_VERSION, 0, &[9]).unwrap();
+        assert_eq!(strangers.suite, None);
+
+        assert_eq!(
+            negotiate(protocol::MIN_VERSION - 1, 1, 0, &[1]),
+            Err(HandshakeError::PeerTooOld(protocol::MIN_VERSION - 1))
+        );
+        assert_eq!(
+            negotiate(protocol::VERSION + 1, protocol::VERSION + 1, 0, &[1]),
+            Err(HandshakeError::PeerTooNew(protocol::VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn capabilities() {
+        let both = Capabilities::COMPRESSION.union(Capabilities::RESUME);
+        assert!(both.contains(Capabilities::RESUME));
+        assert!(!both.contains(Capabilities::DELTA));
+        assert_eq!(
+            both.common(Capabilities::RESUME.union(Capabilities::DELTA)),
+            Capabilities::RESUME
+        );
+        assert_eq!(both.to_string(), "compression, resume");
+        assert_eq!(Capabilities::NONE.to_string(), "none");
+    }
+}
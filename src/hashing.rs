@@ -0,0 +1,196 @@
+// Hashing files for manifests, which has to be done before anything can be offered and dominates the wait for large sends.
+// Files are spread over rayon's pool, and each large read is split over the pool once more by blake3, so a single huge file
+// keeps all cores busy, too. The hashes are the same as if everything had been hashed in one go.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use thiserror::Error;
+
+use crate::dedup;
+
+// Reads this much at a time, one block for deduplication. blake3 only spreads inputs over several threads from 128 KiB on.
+const READ_SIZE: usize = dedup::BLOCK_SIZE as usize;
+
+// Returned when hashing stopped because the send was cancelled
+#[derive(Debug, Error)]
+#[error("Cancelled")]
+pub struct Cancelled;
+
+// Shared between the threads that hash and whoever shows how far they've got
+#[derive(Debug, Default)]
+pub struct Progress {
+    files: AtomicUsize,
+    total_size: AtomicU64,
+    hashed: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    // Makes everything still hashing fail with `Cancelled` after its current read
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self, id: usize) -> Status {
+        Status {
+            id,
+            files: self.files.load(Ordering::Relaxed),
+            total_size: self.total_size.load(Ordering::Relaxed),
+            hashed: self.hashed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Snapshot of a send that is still being hashed, for displaying progress
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub id: usize,
+    pub files: usize,
+    pub total_size: u64,
+    pub hashed: u64,
+}
+
+impl Status {
+    pub fn percent(&self) -> u64 {
+        match self.total_size {
+            0 => 100,
+            total => self.hashed.min(total) * 100 / total,
+        }
+    }
+}
+
+// What hashing a file comes up with
+#[derive(Clone, Debug, PartialEq)]
+pub struct Digest {
+    pub size: u64,
+    pub hash: blake3::Hash,
+    pub blocks: Vec<blake3::Hash>, // One per block, if asked for. See dedup.rs
+}
+
+// Blocks until every file is hashed, so it runs on a blocking task. Returns the digests in the order of the paths.
+pub fn hash_files(paths: &[PathBuf], progress: &Progress, blocks: bool) -> Result<Vec<Digest>> {
+    // The sizes are only for the progress. Files that change in the meantime are hashed as they are.
+    let total_size = paths
+        .iter()
+        .filter_map(|path| path.metadata().ok())
+        .fold(0, |total: u64, metadata| {
+            total.saturating_add(metadata.len())
+        });
+    progress.files.fetch_add(paths.len(), Ordering::Relaxed);
+    progress.total_size.fetch_add(total_size, Ordering::Relaxed);
+
+    paths
+        .par_iter()
+        .map(|path| hash(path, progress, blocks))
+        .collect()
+}
+
+pub fn hash_file(path: &Path) -> Result<(u64, blake3::Hash)> {
+    let digest = hash(path, &Progress::default(), false)?;
+    Ok((digest.size, digest.hash))
+}
+
+fn hash(path: &Path, progress: &Progress, blocks: bool) -> Result<Digest> {
+    let mut file =
+        File::open(path).with_context(|| format!("Unable to open \"{}\".", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; READ_SIZE];
+    let mut size = 0;
+    let mut block_hashes = vec![];
+    loop {
+        if progress.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        let read = read_full(&mut file, &mut buffer)
+            .with_context(|| format!("Unable to read \"{}\".", path.display()))?;
+        if read == 0 {
+            return Ok(Digest {
+                size,
+                hash: hasher.finalize(),
+                blocks: block_hashes,
+            });
+        }
+        let data = &buffer[..read];
+        match blocks {
+            true => {
+                let ((), block) = rayon::join(
+                    || {
+                        hasher.update_rayon(data);
+                    },
+                    || blake3::Hasher::new().update_rayon(data).finalize(),
+                );
+                block_hashes.push(block);
+            }
+            false => {
+                hasher.update_rayon(data);
+            }
+        }
+        size += read as u64;
+        progress.hashed.fetch_add(read as u64, Ordering::Relaxed);
+    }
+}
+
+// Fills the buffer unless the file ends first, as short reads would leave little for the other threads to do
+fn read_full(file: &mut File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+    use std::fs;
+
+    #[test]
+    fn parallel() {
+        let directory = TempDir::new("hashing-parallel");
+        let mut paths = vec![];
+        let mut contents = vec![];
+        // Large enough for several reads, and a few that fit in one
+        for (index, size) in [3 * READ_SIZE + 7, 0, 5, READ_SIZE].iter().enumerate() {
+            let data: Vec<u8> = (0..*size).map(|byte| (byte * (index + 1)) as u8).collect();
+            let path = directory.path().join(index.to_string());
+            fs::write(&path, &data).unwrap();
+            paths.push(path);
+            contents.push(data);
+        }
+
+        let progress = Progress::default();
+        let digests = hash_files(&paths, &progress, true).unwrap();
+        for (digest, data) in digests.iter().zip(&contents) {
+            assert_eq!(digest.size, data.len() as u64);
+            assert_eq!(digest.hash, blake3::hash(data));
+            let blocks: Vec<blake3::Hash> = data.chunks(READ_SIZE).map(blake3::hash).collect();
+            assert_eq!(digest.blocks, blocks);
+        }
+        let status = progress.status(0);
+        assert_eq!(status.files, 4);
+        assert_eq!(status.hashed, status.total_size);
+        assert_eq!(status.percent(), 100);
+
+        // Nothing is read anymore once cancelled
+        let progress = Progress::default();
+        progress.cancel();
+        let error = hash_files(&paths, &progress, false).unwrap_err();
+        assert!(error.downcast_ref::<Cancelled>().is_some());
+        assert_eq!(progress.status(0).hashed, 0);
+    }
+}
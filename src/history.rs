@@ -0,0 +1,139 @@
+// Bounded record of what has happened to our connections. Unlike the current status, this keeps earlier errors around for bug reports.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time;
+
+use anyhow::{Context, Result};
+
+use crate::util;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Info => f.pad("INFO"),
+            Severity::Warning => f.pad("WARNING"),
+            Severity::Error => f.pad("ERROR"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub timestamp: time::SystemTime,
+    pub severity: Severity,
+    pub context: String, // What we were doing, e.g. "Port mapping" or "Peer 203.0.113.7:31415"
+    pub message: String,
+}
+
+impl Entry {
+    pub fn new(severity: Severity, context: &str, message: &str) -> Entry {
+        Entry {
+            timestamp: time::SystemTime::now(),
+            severity,
+            context: context.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:<7} [{}] {}",
+            util::format_timestamp(self.timestamp),
+            self.severity,
+            self.context,
+            self.message
+        )
+    }
+}
+
+// Keeps the latest entries. Once full, the oldest entry makes room for each new one.
+#[derive(Clone)]
+pub struct History {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, entry: Entry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Plain text version of the history, oldest entry first
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "bitgeon {} status history, exported {}\n",
+            env!("CARGO_PKG_VERSION"),
+            util::format_timestamp(time::SystemTime::now())
+        );
+        for entry in &self.entries {
+            report.push_str(&format!("{}\n", entry));
+        }
+        report
+    }
+
+    pub fn export(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.report())
+            .with_context(|| format!("Unable to write history to \"{}\".", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded() {
+        let mut history = History::new(2);
+        for message in &["first", "second", "third"] {
+            history.push(Entry::new(Severity::Info, "Test", message));
+        }
+
+        let messages: Vec<&str> = history
+            .entries()
+            .map(|entry| entry.message.as_str())
+            .collect();
+        assert_eq!(messages, vec!["second", "third"]);
+
+        let report = history.report();
+        assert!(!report.contains("first"));
+        assert!(report.contains("INFO    [Test] third"));
+    }
+}
@@ -0,0 +1,836 @@
+// Send jobs: one set of files going out to one or more peers. Every file is read from disk once, and each chunk is handed
+// to all recipients that keep up. A recipient that falls too far behind is cut loose and reads the rest from disk on its own,
+// so a slow receiver doesn't hold up the others. Each recipient fails on its own, too.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time;
+
+use anyhow::{anyhow, Context, Result};
+use async_std::channel;
+use async_std::task;
+
+use crate::dedup::{self, Duplicates};
+use crate::file_processing;
+use crate::hashing::{self, Progress};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::peer::FrameSender;
+use crate::protocol::{self, Message};
+use crate::server;
+use crate::settings::SendSettings;
+use crate::sparse::{self, Extent};
+use crate::transport::Frame;
+use crate::util;
+use crate::version::Version;
+use crate::zerocopy;
+
+// The files of a job: the manifest that is offered, and where each of its entries lives on our disk
+pub struct Source {
+    pub manifest: Manifest,
+    pub files: Vec<PathBuf>, // In manifest order
+    pub duplicates: Duplicates,
+}
+
+impl Source {
+    // Hashes everything up front, as the manifest needs the hashes. Takes a while for large files, so better not run this on the server task.
+    // Looking for duplicate blocks doubles the hashing, so it's only done for recipients that take them as references.
+    pub fn build(paths: &[PathBuf], progress: &Progress, dedup: bool) -> Result<Source> {
+        let mut names = vec![];
+        let mut files = vec![];
+        for path in paths {
+            for (file, name) in file_processing::collect_files(path)? {
+                names.push(name);
+                files.push(file);
+            }
+        }
+        if files.is_empty() {
+            return Err(anyhow!("There are no files to send."));
+        }
+        let digests = hashing::hash_files(&files, progress, dedup)?;
+        let duplicates = Duplicates::find(
+            &digests
+                .iter()
+                .map(|digest| digest.blocks.clone())
+                .collect::<Vec<_>>(),
+        );
+        let entries = names
+            .into_iter()
+            .zip(digests)
+            .map(|(path, digest)| ManifestEntry {
+                path,
+                size: digest.size,
+                hash: digest.hash,
+            })
+            .collect();
+        Ok(Source {
+            manifest: Manifest::new(entries),
+            files,
+            duplicates,
+        })
+    }
+}
+
+// Where in the job a recipient is, i.e. the next chunk it needs
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Position {
+    file: usize,
+    offset: u64,
+}
+
+// How file data goes out, depending on what every recipient takes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Framing {
+    pub holes: bool,     // Holes in sparse files as such. See sparse.rs
+    pub zero_copy: bool, // Straight from the file to the socket. See zerocopy.rs
+    pub dedup: bool,     // Blocks that went out before as references to them. See dedup.rs
+}
+
+struct Chunk {
+    frame: Frame, // Made once, no matter how many recipients get it
+    length: u64,
+    next: Position,
+}
+
+// Reads the files of a source one chunk after the other, starting at the given position
+struct Cursor {
+    source: Arc<Source>,
+    position: Position,
+    file: Option<Arc<File>>,
+    chunk_size: usize,
+    framing: Framing,
+}
+
+impl Cursor {
+    fn new(source: Arc<Source>, position: Position, chunk_size: usize, framing: Framing) -> Cursor {
+        Cursor {
+            source,
+            position,
+            file: None,
+            chunk_size,
+            framing,
+        }
+    }
+
+    // Blocks on the disk, so it runs on a blocking task
+    fn next(&mut self) -> Result<Option<Chunk>> {
+        let Position { file, offset } = self.position;
+        let entry = match self.source.manifest.entries.get(file) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let path = &self.source.files[file];
+
+        let handle = match &self.file {
+            Some(handle) => handle.clone(),
+            None => {
+                let handle = File::open(path)
+                    .with_context(|| format!("Unable to open \"{}\".", path.display()))?;
+                self.file.insert(Arc::new(handle)).clone()
+            }
+        };
+
+        // Empty files get a single empty chunk, so the receiver knows to create them
+        let remaining = entry.size - offset;
+        let extent = match self.framing.holes && remaining > 0 {
+            true => sparse::extent(&handle, offset, entry.size),
+            false => Extent::Data(remaining),
+        };
+        let copy = match self.framing.dedup {
+            true => self.source.duplicates.get(file, offset),
+            false => None,
+        };
+        let (frame, length) = match (extent, copy) {
+            (Extent::Hole(length), _) => {
+                let length = length.min(sparse::MAX_HOLE);
+                let message = Message::FileHole {
+                    file: file as u32,
+                    offset,
+                    length,
+                };
+                (Frame::Encoded(Arc::new(message.encode()?)), length)
+            }
+            (Extent::Data(_), Some((source, source_offset))) => {
+                let length = remaining.min(dedup::BLOCK_SIZE);
+                let message = Message::FileCopy {
+                    file: file as u32,
+                    offset,
+                    length,
+                    source: source as u32,
+                    source_offset,
+                };
+                (Frame::Encoded(Arc::new(message.encode()?)), length)
+            }
+            // The data is left for the connection to read, if it can do so without a copy
+            (Extent::Data(length), None) if self.framing.zero_copy => {
+                let length = self.data_length(file, offset, length);
+                let frame = Frame::File {
+                    header: Arc::new(protocol::file_chunk_header(
+                        file as u32,
+                        offset,
+                        length as u32,
+                    )),
+                    file: handle,
+                    offset,
+                    length,
+                };
+                (frame, length)
+            }
+            (Extent::Data(length), None) => {
+                let length = self.data_length(file, offset, length);
+                let data = zerocopy::read(&handle, offset, length).with_context(|| {
+                    format!("\"{}\" has changed since it was hashed.", path.display())
+                })?;
+                let message = Message::FileChunk {
+                    file: file as u32,
+                    offset,
+                    data,
+                };
+                (Frame::Encoded(Arc::new(message.encode()?)), length)
+            }
+        };
+
+        self.position = match offset + length {
+            end if end == entry.size => {
+                self.file = None;
+                Position {
+                    file: file + 1,
+                    offset: 0,
+                }
+            }
+            end => Position { file, offset: end },
+        };
+        Ok(Some(Chunk {
+            frame,
+            length,
+            next: self.position,
+        }))
+    }
+
+    // Chunks of data stop short of the next block that goes out as a copy
+    fn data_length(&self, file: usize, offset: u64, available: u64) -> u64 {
+        let length = available.min(self.chunk_size as u64);
+        match self.framing.dedup {
+            true => self
+                .source
+                .duplicates
+                .next(file, offset, offset + length)
+                .map_or(length, |next| next - offset),
+            false => length,
+        }
+    }
+}
+
+// Runs the cursor on a blocking task and gives it back along with the chunk
+async fn next_chunk(mut cursor: Cursor) -> (Cursor, Result<Option<Chunk>>) {
+    task::spawn_blocking(move || {
+        let chunk = cursor.next();
+        (cursor, chunk)
+    })
+    .await
+}
+
+// The shared reader. Recipients that fall behind while others are waiting for more are dropped from the list,
+// which ends their queue and leaves them to read from where they are.
+async fn read_once(
+    source: Arc<Source>,
+    mut queues: Vec<channel::Sender<Chunk>>,
+    settings: SendSettings,
+    framing: Framing,
+) {
+    let mut cursor = Cursor::new(source, Position::default(), settings.chunk_size, framing);
+    while !queues.is_empty() {
+        let (returned, chunk) = next_chunk(cursor).await;
+        cursor = returned;
+        let chunk = match chunk {
+            Ok(Some(chunk)) => Arc::new(chunk),
+            // Recipients run into read errors again on their own, and report them
+            Ok(None) | Err(_) => return,
+        };
+
+        let mut index = 0;
+        while index < queues.len() {
+            let delivered = loop {
+                match async_std::future::timeout(
+                    settings.patience,
+                    queues[index].send(Chunk {
+                        frame: chunk.frame.clone(),
+                        length: chunk.length,
+                        next: chunk.next,
+                    }),
+                )
+                .await
+                {
+                    Ok(result) => break result.is_ok(),
+                    // Waiting is fine as long as nobody else runs dry
+                    Err(_) => {
+                        let starving = queues
+                            .iter()
+                            .enumerate()
+                            .any(|(other, queue)| other != index && queue.is_empty());
+                        if starving {
+                            break false;
+                        }
+                    }
+                }
+            };
+            if delivered {
+                index += 1;
+            } else {
+                queues.remove(index);
+            }
+        }
+    }
+}
+
+// How a recipient is getting along
+#[derive(Clone, Debug, PartialEq)]
+pub enum State {
+    Offered,
+    Declined,
+    Sending,
+    Done,
+    Failed(String),
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Offered => write!(f, "Waiting for an answer"),
+            State::Declined => write!(f, "Declined"),
+            State::Sending => write!(f, "Sending"),
+            State::Done => write!(f, "Done"),
+            State::Failed(error) => write!(f, "Failed: {}", error),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recipient {
+    pub peer: usize,
+    pub address: SocketAddr,
+    pub state: State,
+    pub sent: u64,
+}
+
+// Snapshot of a job, for displaying progress
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub id: usize,
+    pub files: usize,
+    pub total_size: u64,
+    pub recipients: Vec<Recipient>,
+}
+
+impl Status {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} files ({})",
+            self.files,
+            util::format_size(self.total_size)
+        )
+    }
+}
+
+// Reported by the recipient tasks
+#[derive(Debug)]
+pub enum Update {
+    Sent(u64), // Total so far
+    Done,
+    Failed(String),
+}
+
+// What the files are offered as
+#[derive(Clone, Debug, PartialEq)]
+pub enum Kind {
+    Files,
+    Sync(Vec<String>), // Next round of a folder sync, with the paths the recipient should let go of. See sync.rs
+    Folder(Vec<Version>, Vec<(String, Version)>), // Next round of a two-way sync, with versions. See shared.rs
+}
+
+pub struct Job {
+    id: usize,
+    source: Arc<Source>,
+    recipients: BTreeMap<usize, Recipient>, // By peer
+    waiting: Vec<(usize, FrameSender)>,     // Accepted, until the shared reader starts
+    started: bool,
+    kind: Kind,
+    framing: Framing,
+    settings: SendSettings,
+    events: channel::Sender<server::Notification>,
+}
+
+impl Job {
+    pub fn new(
+        id: usize,
+        source: Source,
+        recipients: Vec<(usize, SocketAddr)>,
+        settings: SendSettings,
+        events: channel::Sender<server::Notification>,
+    ) -> Job {
+        let recipients = recipients
+            .into_iter()
+            .map(|(peer, address)| {
+                let recipient = Recipient {
+                    peer,
+                    address,
+                    state: State::Offered,
+                    sent: 0,
+                };
+                (peer, recipient)
+            })
+            .collect();
+        Job {
+            id,
+            source: Arc::new(source),
+            recipients,
+            waiting: vec![],
+            started: false,
+            kind: Kind::Files,
+            framing: Framing::default(),
+            settings,
+            events,
+        }
+    }
+
+    pub fn with_kind(mut self, kind: Kind) -> Job {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_framing(mut self, framing: Framing) -> Job {
+        self.framing = framing;
+        self
+    }
+
+    pub fn offer(&self) -> Message {
+        let manifest = self.source.manifest.clone();
+        match &self.kind {
+            Kind::Files => Message::Offer { manifest },
+            Kind::Sync(removed) => Message::SyncOffer {
+                manifest,
+                removed: removed.clone(),
+            },
+            Kind::Folder(versions, removed) => Message::FolderOffer {
+                manifest,
+                versions: versions.clone(),
+                removed: removed.clone(),
+            },
+        }
+    }
+
+    pub fn has_offered(&self, peer: usize) -> bool {
+        self.recipients
+            .get(&peer)
+            .is_some_and(|recipient| recipient.state == State::Offered)
+    }
+
+    // Once everybody has answered, the accepted recipients share one reader.
+    // Those who answer after it has started read on their own.
+    pub fn answer(&mut self, peer: usize, frames: Option<FrameSender>) {
+        let recipient = match self.recipients.get_mut(&peer) {
+            Some(recipient) if recipient.state == State::Offered => recipient,
+            _ => return,
+        };
+        match frames {
+            Some(frames) => {
+                recipient.state = State::Sending;
+                if self.started {
+                    self.deliver(peer, frames, None);
+                } else {
+                    self.waiting.push((peer, frames));
+                }
+            }
+            None => recipient.state = State::Declined,
+        }
+
+        if !self
+            .recipients
+            .values()
+            .any(|recipient| recipient.state == State::Offered)
+        {
+            self.start();
+        }
+    }
+
+    // Starts the shared reader, even if some recipients haven't answered yet
+    pub fn start(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+
+        let mut queues = vec![];
+        for (peer, frames) in std::mem::take(&mut self.waiting) {
+            let (queue, chunks) = channel::bounded(self.settings.lag.max(1));
+            queues.push(queue);
+            self.deliver(peer, frames, Some(chunks));
+        }
+        if !queues.is_empty() {
+            task::spawn(read_once(
+                self.source.clone(),
+                queues,
+                self.settings.clone(),
+                self.framing,
+            ));
+        }
+    }
+
+    fn deliver(&self, peer: usize, frames: FrameSender, chunks: Option<channel::Receiver<Chunk>>) {
+        task::spawn(deliver(
+            self.source.clone(),
+            frames,
+            chunks,
+            self.settings.chunk_size,
+            self.framing,
+            Reporter {
+                job: self.id,
+                peer,
+                events: self.events.clone(),
+            },
+        ));
+    }
+
+    pub fn update(&mut self, peer: usize, update: Update) {
+        if let Some(recipient) = self.recipients.get_mut(&peer) {
+            match update {
+                Update::Sent(sent) => recipient.sent = sent,
+                Update::Done => recipient.state = State::Done,
+                Update::Failed(error) => recipient.state = State::Failed(error),
+            }
+        }
+    }
+
+    // Peers that leave before answering won't answer anymore. Those receiving notice on their own, as their frames can't be sent.
+    pub fn peer_gone(&mut self, peer: usize) {
+        if self.has_offered(peer) {
+            self.update(peer, Update::Failed(String::from("Disconnected")));
+            if !self
+                .recipients
+                .values()
+                .any(|recipient| recipient.state == State::Offered)
+            {
+                self.start();
+            }
+        }
+    }
+
+    pub fn state(&self, peer: usize) -> Option<&State> {
+        self.recipients.get(&peer).map(|recipient| &recipient.state)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.recipients.values().all(|recipient| {
+            matches!(
+                recipient.state,
+                State::Declined | State::Done | State::Failed(_)
+            )
+        })
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            id: self.id,
+            files: self.source.manifest.len(),
+            total_size: self.source.manifest.total_size(),
+            recipients: self.recipients.values().cloned().collect(),
+        }
+    }
+}
+
+struct Reporter {
+    job: usize,
+    peer: usize,
+    events: channel::Sender<server::Notification>,
+}
+
+impl Reporter {
+    async fn report(&self, update: Update) {
+        let _ = self
+            .events
+            .send(server::Notification::Job(self.job, self.peer, update))
+            .await;
+    }
+}
+
+// Sends everything to one recipient. Chunks come from the shared reader as long as it keeps this recipient around, then straight from disk.
+async fn deliver(
+    source: Arc<Source>,
+    frames: FrameSender,
+    chunks: Option<channel::Receiver<Chunk>>,
+    chunk_size: usize,
+    framing: Framing,
+    reporter: Reporter,
+) {
+    let mut position = Position::default();
+    let mut sent = 0;
+    let mut last_report = time::Instant::now();
+
+    let result = async {
+        if let Some(chunks) = chunks {
+            while let Ok(chunk) = chunks.recv().await {
+                frames.send(chunk.frame).await?;
+                position = chunk.next;
+                sent += chunk.length;
+                if last_report.elapsed() >= time::Duration::from_millis(200) {
+                    reporter.report(Update::Sent(sent)).await;
+                    last_report = time::Instant::now();
+                }
+            }
+        }
+
+        let mut cursor = Cursor::new(source, position, chunk_size, framing);
+        loop {
+            let (returned, chunk) = next_chunk(cursor).await;
+            cursor = returned;
+            let chunk = match chunk? {
+                Some(chunk) => chunk,
+                None => return Ok::<(), anyhow::Error>(()),
+            };
+            frames.send(chunk.frame).await?;
+            sent += chunk.length;
+            if last_report.elapsed() >= time::Duration::from_millis(200) {
+                reporter.report(Update::Sent(sent)).await;
+                last_report = time::Instant::now();
+            }
+        }
+    }
+    .await;
+
+    reporter.report(Update::Sent(sent)).await;
+    match result {
+        Ok(()) => reporter.report(Update::Done).await,
+        Err(error) => {
+            reporter
+                .report(Update::Failed(format!("{:#}", error)))
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::{Download, Piece};
+    use crate::util::TempDir;
+    use std::fs;
+
+    #[test]
+    fn cursor() {
+        let directory = TempDir::new("job-cursor");
+        fs::create_dir(directory.path().join("photos")).unwrap();
+        fs::write(directory.path().join("photos").join("ferris.jpg"), b"crab").unwrap();
+        fs::write(directory.path().join("photos").join("empty"), b"").unwrap();
+        fs::write(directory.path().join("photos").join(".hidden"), b"secret").unwrap();
+        fs::write(directory.path().join("notes.txt"), b"sandwiches").unwrap();
+
+        let source = Source::build(
+            &[
+                directory.path().join("photos"),
+                directory.path().join("notes.txt"),
+            ],
+            &Progress::default(),
+            false,
+        )
+        .unwrap();
+        let paths: Vec<&str> = source
+            .manifest
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect();
+        assert_eq!(paths, ["photos/empty", "photos/ferris.jpg", "notes.txt"]);
+
+        // Whatever the chunks are, they rebuild the files on the other end
+        let source = Arc::new(source);
+        let mut cursor = Cursor::new(source.clone(), Position::default(), 3, Framing::default());
+        let target = TempDir::new("job-cursor-target");
+        let mut download = Download::new(target.path(), source.manifest.clone()).unwrap();
+        let mut chunks = 0;
+        while let Some(chunk) = cursor.next().unwrap() {
+            chunks += 1;
+            let frame = chunk.frame.read().unwrap();
+            match Message::decode(&frame[4..]).unwrap() {
+                Message::FileChunk { file, offset, data } => {
+                    download.write(file, offset, &data).unwrap();
+                }
+                message => panic!("Unexpected message {:?}", message),
+            }
+        }
+        assert_eq!(chunks, 1 + 2 + 4);
+        assert!(download.is_complete());
+        assert_eq!(
+            fs::read(target.path().join("notes.txt")).unwrap(),
+            b"sandwiches"
+        );
+
+        // Picks up in the middle of a file
+        let mut cursor = Cursor::new(
+            source,
+            Position { file: 2, offset: 9 },
+            3,
+            Framing::default(),
+        );
+        let chunk = cursor.next().unwrap().unwrap();
+        assert_eq!(chunk.length, 1);
+        assert_eq!(chunk.next, Position { file: 3, offset: 0 });
+        assert!(cursor.next().unwrap().is_none());
+    }
+
+    // Blocks seen before go out once, and the receiver puts the copies together from what it has
+    #[test]
+    fn dedup() {
+        let block = |byte: u8| vec![byte; dedup::BLOCK_SIZE as usize];
+        let directory = TempDir::new("job-dedup");
+        let first = [block(1), block(2), b"tail".to_vec()].concat();
+        let second = [block(2), block(1), block(1), b"end".to_vec()].concat();
+        fs::write(directory.path().join("first"), &first).unwrap();
+        fs::write(directory.path().join("second"), &second).unwrap();
+        fs::write(directory.path().join("third"), &first).unwrap();
+
+        let paths = ["first", "second", "third"].map(|name| directory.path().join(name));
+        let source = Source::build(&paths, &Progress::default(), true).unwrap();
+        assert_eq!(source.duplicates.len(), 6);
+        let framing = Framing {
+            dedup: true,
+            ..Framing::default()
+        };
+        let mut cursor = Cursor::new(Arc::new(source), Position::default(), 64 * 1024, framing);
+
+        let target = TempDir::new("job-dedup-target");
+        let manifest = cursor.source.manifest.clone();
+        let mut download = Download::new(target.path(), manifest).unwrap();
+        let mut data_sent = 0;
+        while let Some(chunk) = cursor.next().unwrap() {
+            let frame = chunk.frame.read().unwrap();
+            match Message::decode(&frame[4..]).unwrap() {
+                Message::FileChunk { file, offset, data } => {
+                    data_sent += data.len();
+                    download.write(file, offset, &data).unwrap();
+                }
+                Message::FileCopy {
+                    file,
+                    offset,
+                    length,
+                    source,
+                    source_offset,
+                } => {
+                    let piece = Piece::Copy {
+                        file: source,
+                        offset: source_offset,
+                        length,
+                    };
+                    download.write_piece(file, offset, piece).unwrap();
+                }
+                message => panic!("Unexpected message {:?}", message),
+            }
+        }
+        assert!(download.is_complete());
+        // Only the first two blocks go out in full, along with the ends of the files
+        assert_eq!(data_sent, 2 * dedup::BLOCK_SIZE as usize + 4 + 3);
+        assert_eq!(fs::read(target.path().join("second")).unwrap(), second);
+        assert_eq!(fs::read(target.path().join("third")).unwrap(), first);
+    }
+
+    // Holes go out as such and come out as holes on the other end, while the contents stay the same
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sparse() {
+        use std::io::Write;
+        use std::os::unix::fs::MetadataExt;
+
+        let directory = TempDir::new("job-sparse");
+        let path = directory.path().join("disk.img");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"boot").unwrap();
+        file.set_len(16 << 20).unwrap();
+        drop(file);
+
+        let source = Arc::new(Source::build(&[path], &Progress::default(), false).unwrap());
+        let framing = Framing {
+            holes: true,
+            zero_copy: true,
+            dedup: false,
+        };
+        let mut cursor = Cursor::new(source.clone(), Position::default(), 64 * 1024, framing);
+        let target = TempDir::new("job-sparse-target");
+        let mut download = Download::new(target.path(), source.manifest.clone()).unwrap();
+        let mut data_sent = 0;
+        while let Some(chunk) = cursor.next().unwrap() {
+            let frame = chunk.frame.read().unwrap();
+            match Message::decode(&frame[4..]).unwrap() {
+                Message::FileChunk { file, offset, data } => {
+                    data_sent += data.len();
+                    download.write(file, offset, &data).unwrap();
+                }
+                Message::FileHole {
+                    file,
+                    offset,
+                    length,
+                } => {
+                    download.write_hole(file, offset, length).unwrap();
+                }
+                message => panic!("Unexpected message {:?}", message),
+            }
+        }
+        assert!(download.is_complete());
+        assert!(data_sent < 1 << 20);
+
+        let received = target.path().join("disk.img");
+        let metadata = fs::metadata(&received).unwrap();
+        assert_eq!(metadata.len(), 16 << 20);
+        assert!(metadata.blocks() * 512 < 1 << 20);
+        assert_eq!(
+            hashing::hash_file(&received).unwrap().1,
+            source.manifest.entries[0].hash
+        );
+    }
+
+    // A recipient that stops taking chunks is left behind, and the others get everything
+    #[test]
+    fn slow_recipient() {
+        let directory = TempDir::new("job-slow");
+        fs::write(directory.path().join("data"), vec![7; 40]).unwrap();
+        let source = Arc::new(
+            Source::build(
+                &[directory.path().join("data")],
+                &Progress::default(),
+                false,
+            )
+            .unwrap(),
+        );
+        let settings = SendSettings {
+            chunk_size: 4,
+            patience: time::Duration::from_millis(10),
+            ..SendSettings::default()
+        };
+
+        task::block_on(async {
+            let (fast, fast_chunks) = channel::bounded(2);
+            let (slow, slow_chunks) = channel::bounded(2);
+            let reading = task::spawn(read_once(
+                source,
+                vec![fast, slow],
+                settings,
+                Framing::default(),
+            ));
+
+            let mut received = 0;
+            while let Ok(chunk) = fast_chunks.recv().await {
+                received += chunk.length;
+            }
+            assert_eq!(received, 40);
+            reading.await;
+
+            // What was queued is still there, so the slow one knows where to pick up
+            let mut queued = vec![];
+            while let Ok(chunk) = slow_chunks.recv().await {
+                queued.push(chunk.next);
+            }
+            assert!(!queued.is_empty() && queued.len() < 10);
+        });
+    }
+}
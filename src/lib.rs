@@ -1,7 +1,28 @@
 pub mod backend;
+pub mod chat;
+pub mod dedup;
+pub mod download;
 pub mod file_processing;
+pub mod handshake;
+pub mod hashing;
+pub mod history;
+pub mod job;
+pub mod link;
+pub mod manifest;
+pub mod peer;
+pub mod protocol;
+pub mod reachability;
+pub mod sandbox;
 pub mod server;
 pub mod settings;
+pub mod shared;
+pub mod sparse;
+pub mod stream;
+pub mod stun;
+pub mod sync;
+pub mod transport;
 pub mod ui;
 pub mod util;
+pub mod version;
 pub mod widget;
+pub mod zerocopy;
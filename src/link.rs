@@ -0,0 +1,252 @@
+// Links like bitgeon://203.0.113.7:31415,192.168.1.20:31415?key=3f2a9c01d4e5b678&root=<hash> that tell a peer how to reach us.
+// They carry every address we might be reachable at, the fingerprint of our key and optionally the root hash of the manifest
+// we are about to offer. A link is short enough to paste into a chat, and can be passed to `bitgeon` on the command line.
+
+use std::fmt;
+use std::net::SocketAddr;
+
+use thiserror::Error;
+
+pub const SCHEME: &str = "bitgeon://";
+
+// Characters that end a link inside of running text
+const DELIMITERS: [char; 5] = ['"', '\'', '<', '>', ';'];
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LinkError {
+    #[error("Link doesn't start with \"{}\"", SCHEME)]
+    Scheme,
+    #[error("Link contains no address")]
+    NoAddress,
+    #[error("\"{0}\" is not a valid address")]
+    InvalidAddress(String),
+    #[error("Link contains no key fingerprint")]
+    NoFingerprint,
+    #[error("\"{0}\" is not a valid key fingerprint")]
+    InvalidFingerprint(String),
+    #[error("\"{0}\" is not a valid root hash")]
+    InvalidRoot(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Link {
+    pub addresses: Vec<SocketAddr>, // In the order they should be tried
+    pub fingerprint: String,        // Of the key we expect the peer to use. See `fingerprint`
+    pub root: Option<blake3::Hash>, // Of the manifest to download, once the peer offers it
+}
+
+impl Link {
+    pub fn parse(text: &str) -> Result<Link, LinkError> {
+        let text = text.trim();
+        let rest = match text.get(..SCHEME.len()) {
+            Some(scheme) if scheme.eq_ignore_ascii_case(SCHEME) => &text[SCHEME.len()..],
+            _ => return Err(LinkError::Scheme),
+        };
+        let (addresses, query) = match rest.split_once('?') {
+            Some((addresses, query)) => (addresses, query),
+            None => (rest, ""),
+        };
+
+        let addresses = addresses
+            .trim_end_matches('/')
+            .split(',')
+            .filter(|address| !address.is_empty())
+            .map(|address| {
+                address
+                    .parse()
+                    .map_err(|_| LinkError::InvalidAddress(address.to_string()))
+            })
+            .collect::<Result<Vec<SocketAddr>, LinkError>>()?;
+        if addresses.is_empty() {
+            return Err(LinkError::NoAddress);
+        }
+
+        // Parameters we don't know are skipped, so older builds can still follow links from newer ones
+        let mut fingerprint = None;
+        let mut root = None;
+        for parameter in query.split('&') {
+            match parameter.split_once('=') {
+                Some(("key", value)) => {
+                    if value.len() != 16 || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return Err(LinkError::InvalidFingerprint(value.to_string()));
+                    }
+                    fingerprint = Some(value.to_ascii_lowercase());
+                }
+                Some(("root", value)) => {
+                    root = Some(
+                        blake3::Hash::from_hex(value)
+                            .map_err(|_| LinkError::InvalidRoot(value.to_string()))?,
+                    );
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Link {
+            addresses,
+            fingerprint: fingerprint.ok_or(LinkError::NoFingerprint)?,
+            root,
+        })
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addresses: Vec<String> = self
+            .addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect();
+        write!(
+            f,
+            "{}{}?key={}",
+            SCHEME,
+            addresses.join(","),
+            self.fingerprint
+        )?;
+        if let Some(root) = &self.root {
+            write!(f, "&root={}", root.to_hex())?;
+        }
+        Ok(())
+    }
+}
+
+// Short enough to compare by eye, and tells nothing about the key itself
+pub fn fingerprint(secret_key: &str) -> String {
+    let hash = blake3::derive_key("bitgeon 2021-06-01 key fingerprint", secret_key.as_bytes());
+    hash[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Picks the links out of text pasted from somewhere else, like a chat message. Anything that only looks like a link is skipped.
+pub fn find_links(text: &str) -> Vec<Link> {
+    let lowercase = text.to_ascii_lowercase(); // Same byte offsets, as only ASCII changes
+    lowercase
+        .match_indices(SCHEME)
+        .filter_map(|(start, _)| {
+            let candidate = &text[start..];
+            let end = candidate
+                .find(|c: char| c.is_whitespace() || DELIMITERS.contains(&c))
+                .unwrap_or(candidate.len());
+            // Punctuation right after a link belongs to the sentence around it
+            let candidate = candidate[..end].trim_end_matches(|c: char| !c.is_ascii_alphanumeric());
+            Link::parse(candidate).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    #[test]
+    fn links() {
+        let link = Link {
+            addresses: vec![
+                "203.0.113.7:31415".parse().unwrap(),
+                "[fe80::1]:31415".parse().unwrap(),
+            ],
+            fingerprint: fingerprint("Swordfish"),
+            root: Some(blake3::hash(b"manifest")),
+        };
+        assert_eq!(Link::parse(&link.to_string()), Ok(link.clone()));
+
+        let without_root = Link { root: None, ..link };
+        assert_eq!(
+            Link::parse(&without_root.to_string()),
+            Ok(without_root.clone())
+        );
+        assert_eq!(
+            Link::parse(&format!(
+                "BITGEON://203.0.113.7:31415,[fe80::1]:31415/?key={}&future=1",
+                fingerprint("Swordfish").to_uppercase()
+            )),
+            Ok(without_root)
+        );
+
+        let key = fingerprint("Swordfish");
+        let rejected = [
+            (String::from("https://example.com"), LinkError::Scheme),
+            (format!("bitgeon://?key={}", key), LinkError::NoAddress),
+            (
+                format!("bitgeon://example.com:80?key={}", key),
+                LinkError::InvalidAddress(String::from("example.com:80")),
+            ),
+            (
+                String::from("bitgeon://203.0.113.7:31415"),
+                LinkError::NoFingerprint,
+            ),
+            (
+                String::from("bitgeon://203.0.113.7:31415?key=swordfish"),
+                LinkError::InvalidFingerprint(String::from("swordfish")),
+            ),
+            (
+                format!("bitgeon://203.0.113.7:31415?key={}&root=abc", key),
+                LinkError::InvalidRoot(String::from("abc")),
+            ),
+        ];
+        for (text, error) in &rejected {
+            assert_eq!(Link::parse(text).as_ref(), Err(error), "{}", text);
+        }
+    }
+
+    #[test]
+    fn pasted() {
+        let key = fingerprint("Swordfish");
+        let text = format!(
+            "Grab it from bitgeon://203.0.113.7:31415?key={}. Or \"bitgeon://[::1]:80?key={}\", \
+             but not bitgeon://nowhere",
+            key, key
+        );
+        let links = find_links(&text);
+        assert_eq!(links.len(), 2);
+        assert_eq!(
+            links[0].addresses,
+            vec!["203.0.113.7:31415".parse().unwrap()]
+        );
+        assert_eq!(links[1].addresses, vec!["[::1]:80".parse().unwrap()]);
+        assert!(find_links("Nothing to see here").is_empty());
+    }
+
+    fn link() -> impl Strategy<Value = Link> {
+        let address = prop_oneof![
+            any::<([u8; 4], u16)>().prop_map(|(ip, port)| SocketAddr::from((ip, port))),
+            any::<([u8; 16], u16)>().prop_map(|(ip, port)| SocketAddr::from((ip, port))),
+        ];
+        let root = proptest::option::of(any::<[u8; 32]>().prop_map(blake3::Hash::from));
+        (vec(address, 1..4), "[0-9a-f]{16}", root).prop_map(|(addresses, fingerprint, root)| Link {
+            addresses,
+            fingerprint,
+            root,
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(link in link()) {
+            prop_assert_eq!(Link::parse(&link.to_string()), Ok(link));
+        }
+
+        // Links come from chats and the command line. Whatever parses has to come out of `to_string` the same again.
+        #[test]
+        fn parse_anything(rest in "\\PC{0,60}") {
+            if let Ok(link) = Link::parse(&format!("{}{}", SCHEME, rest)) {
+                prop_assert_eq!(Link::parse(&link.to_string()), Ok(link));
+            }
+        }
+
+        #[test]
+        fn find_in_anything(before in "\\PC{0,30}", link in link(), after in "\\PC{0,30}") {
+            let text = format!("{} {} {}", before, link, after);
+            let links = find_links(&text);
+            prop_assert!(links.contains(&link));
+            for found in links {
+                prop_assert_eq!(Link::parse(&found.to_string()), Ok(found));
+            }
+        }
+    }
+}
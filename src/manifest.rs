@@ -0,0 +1,72 @@
+// List of files a sender offers. Paths are relative and use forward slashes, no matter the sender's operating system.
+// The receiver runs them through the sandbox before touching the disk.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub hash: blake3::Hash, // Checked before the file is moved into place
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new(entries: Vec<ManifestEntry>) -> Manifest {
+        Manifest { entries }
+    }
+
+    // A hostile sender could make this overflow, so it saturates instead
+    pub fn total_size(&self) -> u64 {
+        self.entries
+            .iter()
+            .fold(0, |total: u64, entry| total.saturating_add(entry.size))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Identifies the whole manifest, e.g. in a link. Covers paths, sizes and hashes in order, so a changed file changes the root.
+    pub fn root_hash(&self) -> blake3::Hash {
+        let mut hasher = blake3::Hasher::new();
+        for entry in &self.entries {
+            // Length prefixes keep "ab" + "c" apart from "a" + "bc"
+            hasher.update(&(entry.path.len() as u64).to_be_bytes());
+            hasher.update(entry.path.as_bytes());
+            hasher.update(&entry.size.to_be_bytes());
+            hasher.update(entry.hash.as_bytes());
+        }
+        hasher.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_hash() {
+        let entry = |path: &str, size| ManifestEntry {
+            path: String::from(path),
+            size,
+            hash: blake3::hash(path.as_bytes()),
+        };
+        let manifest = Manifest::new(vec![entry("ab", 1), entry("c", 2)]);
+        assert_eq!(manifest.root_hash(), manifest.clone().root_hash());
+        assert_ne!(
+            manifest.root_hash(),
+            Manifest::new(vec![entry("ab", 1), entry("c", 3)]).root_hash()
+        );
+        assert_ne!(
+            manifest.root_hash(),
+            Manifest::new(vec![entry("c", 2), entry("ab", 1)]).root_hash()
+        );
+    }
+}
@@ -0,0 +1,709 @@
+// Every connected peer is served by its own task. It owns the writing half of the connection,
+// while a second task reads frames from the other half. Both feed into a single inbox, together with commands from the server.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::time;
+
+use anyhow::{anyhow, Result};
+use async_std::channel::{self, TrySendError};
+use async_std::task;
+
+use crate::chat;
+use crate::handshake::{self, Agreement};
+use crate::protocol::{self, FrameBuffer};
+use crate::server;
+use crate::settings::HeartbeatSettings;
+use crate::transport::{Connection, Frame, Reader, TransportKind, Writer};
+
+// Incoming peers have connected to us to receive our files. Outgoing peers are those we have connected to in order to receive theirs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionHealth {
+    Healthy,
+    Unresponsive,      // A ping has gone unanswered for longer than the ping interval
+    Reconnecting(u32), // Holds the number of failed reconnect attempts so far
+    Dead,
+}
+
+impl fmt::Display for ConnectionHealth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionHealth::Healthy => write!(f, "Healthy"),
+            ConnectionHealth::Unresponsive => write!(f, "Unresponsive"),
+            ConnectionHealth::Reconnecting(attempts) => {
+                write!(f, "Reconnecting (attempt {})", attempts + 1)
+            }
+            ConnectionHealth::Dead => write!(f, "Connection lost"),
+        }
+    }
+}
+
+// Snapshot of a peer connection, used for displaying connection health
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerStatus {
+    pub id: usize,
+    pub address: SocketAddr,
+    pub direction: Direction,
+    pub transport: TransportKind,
+    pub health: ConnectionHealth,
+    pub round_trip_time: Option<time::Duration>,
+    pub safety_code: Option<String>, // Set once chat is encrypted. Should read the same on both ends.
+    pub agreement: Option<Agreement>, // None until the peer's hello has arrived
+}
+
+impl PeerStatus {
+    // Whether file data can go out to the peer exactly as it is on disk. QUIC encrypts everything it carries,
+    // and compression would change the bytes as well, once it is agreed on. See zerocopy.rs
+    pub fn takes_plain_data(&self) -> bool {
+        self.transport == TransportKind::Tcp
+            && !self.agreement.is_some_and(|agreement| {
+                agreement
+                    .capabilities
+                    .contains(handshake::Capabilities::COMPRESSION)
+            })
+    }
+}
+
+pub enum Event {
+    Status(PeerStatus),
+    Message(protocol::Message),
+    Chat(String), // Already decrypted
+    Snippet(String),
+    Incompatible(handshake::HandshakeError), // The connection is closed, and not tried again
+}
+
+enum Input {
+    Received(protocol::Message),
+    Closed(anyhow::Error),
+    Send(protocol::Message),
+    Text(chat::Kind, String),          // Encrypted before it is sent
+    Frame(Frame, channel::Sender<()>), // Answered once written
+    Disconnect,
+}
+
+// The server's end of a peer task
+pub struct PeerHandle {
+    pub status: PeerStatus,
+    inbox: channel::Sender<Input>,
+}
+
+impl PeerHandle {
+    pub fn send(&self, message: protocol::Message) -> Result<()> {
+        self.deliver(Input::Send(message))
+    }
+
+    pub fn send_text(&self, kind: chat::Kind, text: &str) -> Result<()> {
+        self.deliver(Input::Text(kind, text.to_string()))
+    }
+
+    // The server can't wait for a peer, so a peer that lets its inbox fill up misses out
+    fn deliver(&self, input: Input) -> Result<()> {
+        self.inbox.try_send(input).map_err(|error| match error {
+            TrySendError::Full(_) => anyhow!("Peer {} isn't keeping up.", self.status.address),
+            TrySendError::Closed(_) => anyhow!("Peer {} is gone.", self.status.address),
+        })
+    }
+
+    pub fn frames(&self) -> FrameSender {
+        FrameSender {
+            address: self.status.address,
+            inbox: self.inbox.clone(),
+        }
+    }
+}
+
+// Sends bulk data to a peer from a task of its own. Unlike `PeerHandle::send`, every frame is waited for until it has been written,
+// so a slow peer slows the sender down instead of piling up frames in memory.
+#[derive(Clone)]
+pub struct FrameSender {
+    address: SocketAddr,
+    inbox: channel::Sender<Input>,
+}
+
+impl FrameSender {
+    pub async fn send(&self, frame: Frame) -> Result<()> {
+        let (written, receiver) = channel::bounded(1);
+        self.inbox
+            .send(Input::Frame(frame, written))
+            .await
+            .map_err(|_| anyhow!("Peer {} is gone.", self.address))?;
+        // The sender is dropped without an answer if the connection breaks in the meantime
+        receiver
+            .recv()
+            .await
+            .map_err(|_| anyhow!("Connection to {} broke down.", self.address))
+    }
+}
+
+// Dropping the handle ends the connection
+impl Drop for PeerHandle {
+    fn drop(&mut self) {
+        // The peer task keeps the inbox open itself, so the disconnect has to get through even if the inbox is full
+        if let Err(TrySendError::Full(disconnect)) = self.inbox.try_send(Input::Disconnect) {
+            let inbox = self.inbox.clone();
+            task::spawn(async move {
+                let _ = inbox.send(disconnect).await;
+            });
+        }
+    }
+}
+
+// Starts serving a connection. Events are reported to the server, tagged with the given ID.
+pub fn spawn(
+    id: usize,
+    connection: Connection,
+    direction: Direction,
+    settings: HeartbeatSettings,
+    inbox_capacity: usize,
+    events: channel::Sender<server::Notification>,
+) -> PeerHandle {
+    let (inbox, receiver) = channel::bounded(inbox_capacity);
+    let peer = Peer {
+        id,
+        address: connection.peer_addr(),
+        direction,
+        transport: connection.kind(),
+        writer: None,
+        last_received: time::Instant::now(),
+        pending_ping: None,
+        next_nonce: 0,
+        round_trip_time: None,
+        health: ConnectionHealth::Healthy,
+        closing: false,
+        agreement: None,
+        incompatible: false,
+        keys: None,
+        session: None,
+        unsent: vec![],
+        settings,
+        inbox: inbox.clone(),
+        events,
+    };
+    let status = peer.status();
+
+    task::spawn(peer.run(connection, receiver));
+
+    PeerHandle { status, inbox }
+}
+
+struct Peer {
+    id: usize,
+    address: SocketAddr,
+    direction: Direction,
+    transport: TransportKind,
+    writer: Option<Writer>, // None while the connection is down
+    last_received: time::Instant,
+    pending_ping: Option<(u64, time::Instant)>,
+    next_nonce: u64,
+    round_trip_time: Option<time::Duration>,
+    health: ConnectionHealth,
+    closing: bool, // Set once the server has asked us to disconnect
+    agreement: Option<Agreement>,
+    incompatible: bool, // Set if the peer's protocol version doesn't work with ours. Reconnecting won't change that.
+    keys: Option<chat::KeyPair>, // Our half of the key exchange, until the peer's half arrives
+    session: Option<chat::Session>,
+    unsent: Vec<(chat::Kind, String)>, // Text waiting for the key exchange
+    settings: HeartbeatSettings,
+    inbox: channel::Sender<Input>,
+    events: channel::Sender<server::Notification>,
+}
+
+impl Peer {
+    fn status(&self) -> PeerStatus {
+        PeerStatus {
+            id: self.id,
+            address: self.address,
+            direction: self.direction,
+            transport: self.transport,
+            health: self.health,
+            round_trip_time: self.round_trip_time,
+            safety_code: self
+                .session
+                .as_ref()
+                .map(|session| session.safety_code().to_string()),
+            agreement: self.agreement,
+        }
+    }
+
+    async fn report(&self, event: Event) -> Result<()> {
+        self.events
+            .send(server::Notification::Peer(self.id, event))
+            .await
+            .map_err(|_| anyhow!("Server is gone."))
+    }
+
+    async fn run(mut self, connection: Connection, inbox: channel::Receiver<Input>) {
+        let mut connection = Some(connection);
+        let mut attempts = 0;
+
+        loop {
+            if let Some(connection) = connection.take() {
+                attempts = 0;
+                if self.serve(connection, &inbox).await.is_err() || self.closing {
+                    return; // The server has shut down or doesn't need this peer anymore
+                }
+            }
+
+            // Only we know how to reach peers that we have connected to ourselves. Incoming peers have to reconnect on their own.
+            if self.direction == Direction::Incoming
+                || attempts >= self.settings.reconnect_attempts
+                || self.incompatible
+            {
+                self.health = ConnectionHealth::Dead;
+                let _ = self.report(Event::Status(self.status())).await;
+                return;
+            }
+
+            self.health = ConnectionHealth::Reconnecting(attempts);
+            if self.report(Event::Status(self.status())).await.is_err() {
+                return;
+            }
+            task::sleep(self.settings.reconnect_delay(attempts)).await;
+            while let Ok(input) = inbox.try_recv() {
+                if let Input::Disconnect = input {
+                    return;
+                }
+            }
+
+            // Reconnect using the transport that worked before
+            match Connection::connect(self.transport, self.address, self.settings.connect_timeout)
+                .await
+            {
+                Ok(new_connection) => connection = Some(new_connection),
+                Err(_) => attempts += 1,
+            }
+        }
+    }
+
+    // Serves a connection until it breaks down. Only returns an error if the server is gone.
+    async fn serve(
+        &mut self,
+        connection: Connection,
+        inbox: &channel::Receiver<Input>,
+    ) -> Result<()> {
+        let (reader, writer) = connection.split();
+        self.writer = Some(writer);
+        self.last_received = time::Instant::now();
+        self.health = ConnectionHealth::Healthy;
+        self.report(Event::Status(self.status())).await?;
+
+        // The peer may have been updated in the meantime, so every connection starts over with a hello
+        let _ = self.send(&handshake::hello()).await;
+
+        let receiving = task::spawn(receive(reader, self.inbox.clone()));
+        let result = self.exchange(inbox).await;
+
+        receiving.cancel().await;
+        self.writer = None;
+        self.agreement = None;
+        self.keys = None;
+        self.session = None;
+        self.pending_ping = None;
+        self.round_trip_time = None;
+        result
+    }
+
+    async fn exchange(&mut self, inbox: &channel::Receiver<Input>) -> Result<()> {
+        loop {
+            let status = self.status();
+
+            // Sleep until something arrives or the next heartbeat deadline passes
+            let wait = self
+                .next_deadline()
+                .saturating_duration_since(time::Instant::now());
+            let input = match async_std::future::timeout(wait, inbox.recv()).await {
+                Ok(Ok(input)) => Some(input),
+                Ok(Err(_)) => return Err(anyhow!("Server is gone.")),
+                Err(_) => None,
+            };
+
+            let result = match input {
+                Some(Input::Received(message)) => self.handle(message).await,
+                Some(Input::Send(message)) => self.send(&message).await,
+                Some(Input::Text(kind, text)) => self.send_text(kind, text).await,
+                Some(Input::Frame(frame, written)) => self.send_frame(&frame, written).await,
+                Some(Input::Closed(error)) => Err(error),
+                Some(Input::Disconnect) => {
+                    self.closing = true;
+                    return Ok(());
+                }
+                None => Ok(()),
+            };
+            if result.is_err() || self.heartbeat().await.is_err() {
+                return Ok(());
+            }
+
+            if self.status() != status {
+                self.report(Event::Status(self.status())).await?;
+            }
+        }
+    }
+
+    async fn send(&mut self, message: &protocol::Message) -> Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.write_all(&message.encode()?).await,
+            None => Err(anyhow!("Peer is not connected.")),
+        }
+    }
+
+    async fn send_frame(&mut self, frame: &Frame, written: channel::Sender<()>) -> Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.write_frame(frame).await?,
+            None => return Err(anyhow!("Peer is not connected.")),
+        }
+        let _ = written.try_send(());
+        Ok(())
+    }
+
+    async fn send_text(&mut self, kind: chat::Kind, text: String) -> Result<()> {
+        let message = match &mut self.session {
+            Some(session) => session.seal(kind, &text)?,
+            None => {
+                self.unsent.push((kind, text));
+                return Ok(());
+            }
+        };
+        self.send(&message).await
+    }
+
+    async fn handle(&mut self, message: protocol::Message) -> Result<()> {
+        self.last_received = time::Instant::now();
+        self.health = ConnectionHealth::Healthy;
+
+        match message {
+            protocol::Message::Ping { nonce } => {
+                self.send(&protocol::Message::Pong { nonce }).await?
+            }
+            protocol::Message::Pong { nonce } => {
+                if let Some((expected_nonce, sent)) = self.pending_ping {
+                    if nonce == expected_nonce {
+                        self.round_trip_time = Some(sent.elapsed());
+                        self.pending_ping = None;
+                    }
+                }
+            }
+            // A probe server checking whether we can be reached. Echoing the nonce proves that it reached us and not someone else.
+            // The server is told as well, so it can let the probe server go.
+            protocol::Message::ProbeCallback { nonce } => {
+                self.send(&protocol::Message::ProbeCallback { nonce })
+                    .await?;
+                self.report(Event::Message(protocol::Message::ProbeCallback { nonce }))
+                    .await?
+            }
+            protocol::Message::Hello {
+                version,
+                minimum_version,
+                capabilities,
+                suites,
+            } => {
+                match handshake::negotiate(version, minimum_version, capabilities, &suites) {
+                    Ok(agreement) => self.agreement = Some(agreement),
+                    Err(error) => {
+                        self.incompatible = true;
+                        self.report(Event::Incompatible(error)).await?;
+                        return Err(anyhow!("Peer is incompatible."));
+                    }
+                }
+
+                // Every connection gets fresh chat keys. Without them, or without a suite in common, chat simply stays unavailable.
+                let suite = self.agreement.and_then(|agreement| agreement.suite);
+                if suite == Some(handshake::Suite::X25519ChaCha20Poly1305) {
+                    if let Ok(keys) = chat::KeyPair::generate() {
+                        let public_key = keys.public_key();
+                        self.keys = Some(keys);
+                        self.send(&protocol::Message::KeyExchange { public_key })
+                            .await?;
+                    }
+                }
+            }
+            protocol::Message::KeyExchange { public_key } => {
+                if let Some(keys) = self.keys.take() {
+                    self.session = keys.agree(&public_key, self.direction).ok();
+                    if self.session.is_some() {
+                        for (kind, text) in std::mem::take(&mut self.unsent) {
+                            self.send_text(kind, text).await?;
+                        }
+                    }
+                }
+            }
+            // Messages that don't decrypt are dropped. They are either replayed, damaged or not from our peer.
+            protocol::Message::Chat {
+                counter,
+                ciphertext,
+            } => {
+                if let Some(text) = self.open(chat::Kind::Chat, counter, &ciphertext) {
+                    self.report(Event::Chat(text)).await?
+                }
+            }
+            protocol::Message::Snippet {
+                counter,
+                ciphertext,
+            } => {
+                if let Some(text) = self.open(chat::Kind::Snippet, counter, &ciphertext) {
+                    self.report(Event::Snippet(text)).await?
+                }
+            }
+            message => self.report(Event::Message(message)).await?,
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, kind: chat::Kind, counter: u64, ciphertext: &[u8]) -> Option<String> {
+        self.session
+            .as_mut()
+            .and_then(|session| session.open(kind, counter, ciphertext).ok())
+    }
+
+    // The next point in time at which the heartbeat needs attention
+    fn next_deadline(&self) -> time::Instant {
+        let timeout = self.last_received + self.settings.idle_timeout;
+        let ping = match self.pending_ping {
+            Some((_, sent)) if self.health == ConnectionHealth::Healthy => {
+                sent + self.settings.ping_interval
+            }
+            Some(_) => timeout,
+            None => self.last_received + self.settings.ping_interval,
+        };
+        timeout.min(ping)
+    }
+
+    // Sends pings to idle peers and detects dead connections
+    async fn heartbeat(&mut self) -> Result<()> {
+        if self.last_received.elapsed() >= self.settings.idle_timeout {
+            return Err(anyhow!("Peer timed out."));
+        }
+
+        match self.pending_ping {
+            Some((_, sent)) => {
+                if sent.elapsed() >= self.settings.ping_interval {
+                    self.health = ConnectionHealth::Unresponsive;
+                }
+            }
+            None => {
+                if self.last_received.elapsed() >= self.settings.ping_interval {
+                    let nonce = self.next_nonce;
+                    self.next_nonce = self.next_nonce.wrapping_add(1);
+                    self.pending_ping = Some((nonce, time::Instant::now()));
+                    self.send(&protocol::Message::Ping { nonce }).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reads frames as they arrive and passes the decoded messages on to the peer task
+async fn receive(mut reader: Reader, inbox: channel::Sender<Input>) {
+    let mut frames = FrameBuffer::new();
+    let mut buffer = vec![0; 64 * 1024];
+
+    let error = loop {
+        let read = match reader.read(&mut buffer).await {
+            Ok(0) => break anyhow!("Connection closed by peer."),
+            Ok(read) => read,
+            Err(error) => break error,
+        };
+        frames.extend(&buffer[..read]);
+
+        let mut messages = vec![];
+        let result = loop {
+            match frames.next_frame() {
+                Ok(Some(payload)) => match protocol::Message::decode_known(&payload) {
+                    Ok(Some(message)) => messages.push(message),
+                    Ok(None) => (),
+                    Err(error) => break Err(anyhow!(error)),
+                },
+                Ok(None) => break Ok(()),
+                Err(error) => break Err(anyhow!(error)),
+            }
+        };
+
+        for message in messages {
+            if inbox.send(Input::Received(message)).await.is_err() {
+                return;
+            }
+        }
+        if let Err(error) = result {
+            break error;
+        }
+    };
+
+    let _ = inbox.send(Input::Closed(error)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Listener;
+
+    fn settings() -> HeartbeatSettings {
+        HeartbeatSettings {
+            ping_interval: time::Duration::from_millis(20),
+            idle_timeout: time::Duration::from_millis(200),
+            reconnect_attempts: 2,
+            reconnect_backoff: time::Duration::from_millis(10),
+            max_reconnect_backoff: time::Duration::from_millis(20),
+            connect_timeout: time::Duration::from_millis(200),
+        }
+    }
+
+    // Waits for a status update of the given peer that satisfies the condition
+    async fn wait_for(
+        events: &channel::Receiver<server::Notification>,
+        id: usize,
+        condition: impl Fn(&PeerStatus) -> bool,
+    ) {
+        let timeout = time::Duration::from_secs(5);
+        loop {
+            let event = async_std::future::timeout(timeout, events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let server::Notification::Peer(event_id, Event::Status(status)) = event {
+                if event_id == id && condition(&status) {
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn heartbeat(kind: TransportKind) {
+        let settings = settings();
+        let (events, receiver) = channel::unbounded();
+        let listener = Listener::bind(kind, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // QUIC handshakes only complete while the listener is accepting
+        let accepting = task::spawn(async move {
+            let connection = listener.accept(time::Duration::from_secs(5)).await.unwrap();
+            (listener, connection)
+        });
+        let connection = Connection::connect(kind, address, time::Duration::from_secs(5))
+            .await
+            .unwrap();
+        let outgoing = spawn(
+            0,
+            connection,
+            Direction::Outgoing,
+            settings.clone(),
+            64,
+            events.clone(),
+        );
+        let (listener, connection) = accepting.await;
+        let incoming = spawn(
+            1,
+            connection,
+            Direction::Incoming,
+            settings.clone(),
+            64,
+            events,
+        );
+
+        // Both sides answer each other's pings, so round-trip times get measured
+        wait_for(&receiver, 0, |status| status.round_trip_time.is_some()).await;
+        wait_for(&receiver, 1, |status| status.round_trip_time.is_some()).await;
+
+        // Once the incoming side goes away, the outgoing side tries to reconnect and eventually gives up
+        drop(incoming);
+        drop(listener);
+        wait_for(&receiver, 0, |status| {
+            status.health == ConnectionHealth::Reconnecting(0)
+        })
+        .await;
+        wait_for(&receiver, 0, |status| {
+            status.health == ConnectionHealth::Dead
+        })
+        .await;
+        drop(outgoing);
+    }
+
+    #[test]
+    fn chat() {
+        task::block_on(async {
+            let (events, receiver) = channel::unbounded();
+            let listener =
+                Listener::bind(TransportKind::Tcp, "127.0.0.1:0".parse().unwrap()).unwrap();
+            let address = listener.local_addr().unwrap();
+            let accepting =
+                task::spawn(
+                    async move { listener.accept(time::Duration::from_secs(5)).await.unwrap() },
+                );
+            let connection =
+                Connection::connect(TransportKind::Tcp, address, time::Duration::from_secs(5))
+                    .await
+                    .unwrap();
+
+            // Written before the keys are agreed on, so it has to wait
+            let outgoing = spawn(
+                0,
+                connection,
+                Direction::Outgoing,
+                settings(),
+                64,
+                events.clone(),
+            );
+            outgoing
+                .send_text(chat::Kind::Chat, "Did you get it?")
+                .unwrap();
+            let incoming = spawn(
+                1,
+                accepting.await,
+                Direction::Incoming,
+                settings(),
+                64,
+                events,
+            );
+
+            let mut safety_codes = [None, None];
+            let mut received = None;
+            while safety_codes.iter().any(Option::is_none) || received.is_none() {
+                let event =
+                    async_std::future::timeout(time::Duration::from_secs(5), receiver.recv())
+                        .await
+                        .unwrap()
+                        .unwrap();
+                match event {
+                    server::Notification::Peer(id, Event::Status(status))
+                        if status.safety_code.is_some() =>
+                    {
+                        safety_codes[id] = status.safety_code
+                    }
+                    server::Notification::Peer(id, Event::Chat(text)) => {
+                        received = Some((id, text))
+                    }
+                    _ => (),
+                }
+            }
+            assert_eq!(safety_codes[0], safety_codes[1]);
+            assert_eq!(received, Some((1, String::from("Did you get it?"))));
+            drop((outgoing, incoming));
+        });
+    }
+
+    #[test]
+    fn heartbeat_tcp() {
+        task::block_on(heartbeat(TransportKind::Tcp));
+    }
+
+    #[test]
+    fn heartbeat_quic() {
+        task::block_on(heartbeat(TransportKind::Quic));
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        let settings = settings();
+        assert_eq!(settings.reconnect_delay(0), time::Duration::from_millis(10));
+        assert_eq!(settings.reconnect_delay(1), time::Duration::from_millis(20));
+        assert_eq!(
+            settings.reconnect_delay(40),
+            time::Duration::from_millis(20)
+        );
+    }
+}
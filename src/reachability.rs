@@ -0,0 +1,345 @@
+// Reachability self-test. A successful port mapping doesn't mean that peers can actually reach us,
+// as there may be another NAT in front of the gateway, e.g. carrier-grade NAT at the ISP.
+// So we ask a probe server on the internet to open a connection to our port. It also tells us which address it saw us coming from,
+// and comparing that to what the gateway thinks its external address is reveals NATs the gateway doesn't know about.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time;
+
+use anyhow::{anyhow, Context, Result};
+use async_std::task;
+
+use crate::protocol::{FrameBuffer, Message};
+use crate::stun::MappingBehavior;
+use crate::transport::{Connection, Listener, TransportKind};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NatType {
+    Open,         // We have a public address of our own
+    Single,       // Only the gateway, which we can map ports on
+    Double,       // There is another NAT between the gateway and the internet
+    CarrierGrade, // The gateway's external address is from the range ISPs use for carrier-grade NAT
+    Unknown,      // The gateway didn't tell us its external address, so we can't tell
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NatType::Open => write!(f, "No NAT"),
+            NatType::Single => write!(f, "Single NAT"),
+            NatType::Double => write!(f, "Double NAT"),
+            NatType::CarrierGrade => write!(f, "Carrier-grade NAT"),
+            NatType::Unknown => write!(f, "Unknown NAT"),
+        }
+    }
+}
+
+// What it takes for peers on the internet to connect to us
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Recommendation {
+    Direct,
+    HolePunching,
+    Relay,
+}
+
+impl fmt::Display for Recommendation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Recommendation::Direct => write!(f, "Peers can connect directly"),
+            Recommendation::HolePunching => write!(f, "Peers need hole punching to connect"),
+            Recommendation::Relay => write!(f, "Peers need the relay to connect"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    pub observed: SocketAddr, // Our address as seen by the probe server
+    pub reachable: bool,
+    pub nat: NatType,
+    pub mapping: Option<MappingBehavior>, // As found out through STUN, if it is known
+    pub recommendation: Recommendation,
+}
+
+impl Report {
+    pub fn new(
+        observed: SocketAddr,
+        reachable: bool,
+        local_ip: Option<IpAddr>,
+        gateway_ip: Option<IpAddr>,
+        mapping: Option<MappingBehavior>,
+    ) -> Report {
+        let nat = classify(local_ip, gateway_ip, observed.ip());
+        Report {
+            observed,
+            reachable,
+            nat,
+            mapping,
+            recommendation: recommend(reachable, nat, mapping),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.nat)?;
+        if let Some(mapping) = self.mapping {
+            write!(f, " with {}", mapping)?;
+        }
+        write!(
+            f,
+            ", {} from the internet. {}",
+            if self.reachable {
+                "reachable"
+            } else {
+                "not reachable"
+            },
+            self.recommendation
+        )
+    }
+}
+
+pub fn classify(local_ip: Option<IpAddr>, gateway_ip: Option<IpAddr>, observed: IpAddr) -> NatType {
+    if local_ip == Some(observed) {
+        return NatType::Open;
+    }
+
+    match gateway_ip {
+        None => NatType::Unknown,
+        Some(IpAddr::V4(ip)) if is_shared(ip) => NatType::CarrierGrade,
+        Some(IpAddr::V4(ip)) if ip.is_private() => NatType::Double,
+        Some(ip) if ip != observed => NatType::Double,
+        Some(_) => NatType::Single,
+    }
+}
+
+// Hole punching gets through a single NAT or firewall most of the time. With several layers, or a NAT that picks
+// a new port for every destination, chances are slim.
+fn recommend(reachable: bool, nat: NatType, mapping: Option<MappingBehavior>) -> Recommendation {
+    match nat {
+        _ if reachable => Recommendation::Direct,
+        _ if mapping == Some(MappingBehavior::EndpointDependent) => Recommendation::Relay,
+        NatType::Double | NatType::CarrierGrade => Recommendation::Relay,
+        NatType::Open | NatType::Single | NatType::Unknown => Recommendation::HolePunching,
+    }
+}
+
+// Shared address space for carrier-grade NAT, 100.64.0.0/10 (RFC 6598)
+fn is_shared(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    octets[0] == 100 && (octets[1] & 0b1100_0000) == 64
+}
+
+// Asks the probe server to connect back to the given port. Returns the address the probe server saw us coming from,
+// and whether it managed to reach us. Our listeners need to be accepting while this runs.
+pub async fn self_test(
+    probe_server: SocketAddr,
+    port: u16,
+    timeout: time::Duration,
+) -> Result<(SocketAddr, bool)> {
+    let connection = Connection::connect(TransportKind::Tcp, probe_server, timeout)
+        .await
+        .with_context(|| format!("Unable to reach probe server at {}.", probe_server))?;
+    let (mut reader, mut writer) = connection.split();
+
+    let nonce = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64; // Doesn't need to be secret, just different for every test
+    writer
+        .write_all(&Message::ProbeRequest { nonce, port }.encode()?)
+        .await?;
+
+    // The probe server waits for its own connection attempt first
+    let mut frames = FrameBuffer::new();
+    match async_std::future::timeout(timeout * 2, reader.next_message(&mut frames)).await {
+        Ok(Ok(Message::ProbeResult {
+            observed,
+            reachable,
+        })) => Ok((observed, reachable)),
+        Ok(Ok(message)) => Err(anyhow!(
+            "Unexpected answer from probe server: {:?}",
+            message
+        )),
+        Ok(Err(error)) => Err(error.context("Probe server didn't answer.")),
+        Err(_) => Err(anyhow!("Probe server didn't answer in time.")),
+    }
+}
+
+// Runs a probe server on the given address until something goes wrong
+pub fn run_probe_server(address: SocketAddr, timeout: time::Duration) -> Result<()> {
+    let listener = Listener::bind(TransportKind::Tcp, address)?;
+    task::block_on(serve_probes(listener, timeout))
+}
+
+pub async fn serve_probes(listener: Listener, timeout: time::Duration) -> Result<()> {
+    while let Some(incoming) = listener.next().await {
+        task::spawn(async move {
+            let connection = incoming.establish(timeout).await?;
+            answer_probe(connection, timeout).await
+        });
+    }
+    Err(anyhow!("Stopped listening for probes."))
+}
+
+async fn answer_probe(connection: Connection, timeout: time::Duration) -> Result<()> {
+    let observed = connection.peer_addr();
+    let (mut reader, mut writer) = connection.split();
+
+    let mut frames = FrameBuffer::new();
+    let (nonce, port) =
+        match async_std::future::timeout(timeout, reader.next_message(&mut frames)).await {
+            Ok(Ok(Message::ProbeRequest { nonce, port })) => (nonce, port),
+            _ => return Err(anyhow!("No probe request from {}.", observed)),
+        };
+
+    // Only ever connect back to the address the request came from, so the probe server can't be used to scan others.
+    // The callback goes over TCP, since that is the mapping peers fall back to when QUIC doesn't get through.
+    let callback = call_back(SocketAddr::new(observed.ip(), port), nonce, timeout);
+    let reachable = matches!(
+        async_std::future::timeout(timeout, callback).await,
+        Ok(Ok(()))
+    );
+
+    writer
+        .write_all(
+            &Message::ProbeResult {
+                observed,
+                reachable,
+            }
+            .encode()?,
+        )
+        .await
+}
+
+async fn call_back(address: SocketAddr, nonce: u64, timeout: time::Duration) -> Result<()> {
+    let connection = Connection::connect(TransportKind::Tcp, address, timeout).await?;
+    let (mut reader, mut writer) = connection.split();
+    writer
+        .write_all(&Message::ProbeCallback { nonce }.encode()?)
+        .await?;
+
+    // Whoever accepted the connection needs to echo the nonce. Anything else, like a heartbeat, is skipped.
+    let mut frames = FrameBuffer::new();
+    loop {
+        if let Message::ProbeCallback { nonce: echoed } = reader.next_message(&mut frames).await? {
+            if echoed == nonce {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::{self, Direction};
+    use crate::settings::HeartbeatSettings;
+    use async_std::channel;
+
+    const LOOPBACK: [u8; 4] = [127, 0, 0, 1];
+
+    // Stands in for the probe server on the internet
+    fn probe_server() -> SocketAddr {
+        let listener = Listener::bind(TransportKind::Tcp, SocketAddr::from((LOOPBACK, 0))).unwrap();
+        let address = listener.local_addr().unwrap();
+        task::spawn(serve_probes(listener, time::Duration::from_secs(2)));
+        address
+    }
+
+    #[test]
+    fn reachable() {
+        task::block_on(async {
+            let probe_server = probe_server();
+
+            // Our side serves incoming connections with peer tasks, just like the server does
+            let listener =
+                Listener::bind(TransportKind::Tcp, SocketAddr::from((LOOPBACK, 0))).unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (events, _receiver) = channel::unbounded();
+            let accepting = task::spawn(async move {
+                let connection = listener.accept(time::Duration::from_secs(5)).await.unwrap();
+                peer::spawn(
+                    0,
+                    connection,
+                    Direction::Incoming,
+                    HeartbeatSettings::default(),
+                    64,
+                    events,
+                )
+            });
+
+            let (observed, reachable) = self_test(probe_server, port, time::Duration::from_secs(2))
+                .await
+                .unwrap();
+            assert!(reachable);
+            assert_eq!(observed.ip(), IpAddr::from(LOOPBACK));
+            drop(accepting.await);
+
+            let report = Report::new(observed, reachable, Some(observed.ip()), None, None);
+            assert_eq!(report.nat, NatType::Open);
+            assert_eq!(report.recommendation, Recommendation::Direct);
+        });
+    }
+
+    #[test]
+    fn unreachable() {
+        task::block_on(async {
+            let probe_server = probe_server();
+
+            // Grab a port that nobody listens on
+            let port = Listener::bind(TransportKind::Tcp, SocketAddr::from((LOOPBACK, 0)))
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+
+            let (_, reachable) = self_test(probe_server, port, time::Duration::from_secs(1))
+                .await
+                .unwrap();
+            assert!(!reachable);
+        });
+    }
+
+    #[test]
+    fn nat_types() {
+        let local = Some("192.168.1.20".parse().unwrap());
+        let public: IpAddr = "203.0.113.7".parse().unwrap();
+
+        assert_eq!(classify(Some(public), None, public), NatType::Open);
+        assert_eq!(classify(local, Some(public), public), NatType::Single);
+        assert_eq!(
+            classify(local, Some("100.72.0.1".parse().unwrap()), public),
+            NatType::CarrierGrade
+        );
+        assert_eq!(
+            classify(local, Some("10.0.0.2".parse().unwrap()), public),
+            NatType::Double
+        );
+        assert_eq!(
+            classify(local, Some("198.51.100.1".parse().unwrap()), public),
+            NatType::Double
+        );
+        assert_eq!(classify(local, None, public), NatType::Unknown);
+
+        let independent = Some(MappingBehavior::EndpointIndependent);
+        let dependent = Some(MappingBehavior::EndpointDependent);
+        assert_eq!(
+            recommend(true, NatType::CarrierGrade, dependent),
+            Recommendation::Direct
+        );
+        assert_eq!(
+            recommend(false, NatType::Single, independent),
+            Recommendation::HolePunching
+        );
+        assert_eq!(
+            recommend(false, NatType::Single, dependent),
+            Recommendation::Relay
+        );
+        assert_eq!(
+            recommend(false, NatType::Double, None),
+            Recommendation::Relay
+        );
+    }
+}
@@ -0,0 +1,321 @@
+// Keeps received files inside the download directory. Paths in incoming manifests are chosen by the sender,
+// so they are treated as hostile: anything that could point outside the download root, or that means something
+// special to Windows, is rejected instead of being cleaned up. A rejected file is better than one that lands somewhere unexpected.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use thiserror::Error;
+
+use crate::download;
+
+// Most file systems don't allow longer file names than this
+const MAX_NAME_LENGTH: usize = 255;
+
+// Names that refer to devices on Windows, no matter the extension
+const RESERVED_NAMES: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+const RESERVED_PREFIXES: [&str; 2] = ["COM", "LPT"]; // Followed by a digit
+
+#[derive(Debug, Error, PartialEq)]
+pub enum SandboxError {
+    #[error("Path is empty")]
+    Empty,
+    #[error("Path is absolute")]
+    Absolute,
+    #[error("Path starts with drive letter \"{0}\"")]
+    DriveLetter(String),
+    #[error("Path leaves its directory through \"..\"")]
+    ParentDirectory,
+    #[error("\"{0}\" is a reserved device name")]
+    ReservedName(String),
+    #[error("File name contains invalid character {0:?}")]
+    InvalidCharacter(char),
+    #[error("File name \"{0}\" ends in a dot or a space")]
+    TrailingDotOrSpace(String),
+    #[error("File name is longer than {} bytes", MAX_NAME_LENGTH)]
+    NameTooLong,
+    #[error("\"{0}\" is a symbolic link")]
+    Symlink(PathBuf),
+    #[error("\"{0}\" is not a directory")]
+    NotADirectory(PathBuf),
+    #[error("Unable to inspect \"{0}\"")]
+    Inaccessible(PathBuf),
+}
+
+// Splits a path from a manifest into checked components. Both kinds of slashes count as separators,
+// since the sender may be running a different operating system than we do.
+pub fn sanitize(path: &str) -> Result<Vec<&str>, SandboxError> {
+    if path.starts_with('/') || path.starts_with('\\') {
+        return Err(SandboxError::Absolute); // Also catches UNC paths like \\server\share
+    }
+
+    let mut components = vec![];
+    for (index, component) in path.split(['/', '\\']).enumerate() {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(SandboxError::ParentDirectory),
+            _ => (),
+        }
+
+        let mut characters = component.chars();
+        if let (Some(letter), Some(':')) = (characters.next(), characters.next()) {
+            if index == 0 && letter.is_ascii_alphabetic() {
+                return Err(SandboxError::DriveLetter(format!("{}:", letter)));
+            }
+        }
+        check_name(component)?;
+        components.push(component);
+    }
+
+    if components.is_empty() {
+        return Err(SandboxError::Empty);
+    }
+    Ok(components)
+}
+
+fn check_name(name: &str) -> Result<(), SandboxError> {
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(SandboxError::NameTooLong);
+    }
+
+    // Characters Windows doesn't allow in names. The colon would also open alternate data streams.
+    if let Some(character) = name
+        .chars()
+        .find(|c| c.is_control() || ['<', '>', ':', '"', '|', '?', '*'].contains(c))
+    {
+        return Err(SandboxError::InvalidCharacter(character));
+    }
+
+    // Windows quietly drops these, which would turn ".. " into ".."
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Err(SandboxError::TrailingDotOrSpace(name.to_string()));
+    }
+
+    // "nul.txt" and "COM1 .tar.gz" are just as much a device as "NUL"
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    let upper = stem.to_uppercase();
+    let reserved = RESERVED_NAMES.contains(&upper.as_str())
+        || RESERVED_PREFIXES.iter().any(|prefix| {
+            upper.strip_prefix(prefix).is_some_and(|rest| {
+                rest.chars().count() == 1
+                    && rest
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || ['¹', '²', '³'].contains(&c))
+            })
+        });
+    // Partial files are ours. A sender could otherwise slip in a "finished" partial file, or get ours cleaned up.
+    if reserved || download::is_part_file(Path::new(name)) {
+        return Err(SandboxError::ReservedName(name.to_string()));
+    }
+
+    Ok(())
+}
+
+// The download directory. Everything resolved through it stays inside of it.
+#[derive(Debug)]
+pub struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    pub fn new(root: &Path) -> Result<Sandbox> {
+        let root = root.canonicalize().with_context(|| {
+            format!("Unable to find download directory \"{}\".", root.display())
+        })?;
+        if !root.is_dir() {
+            return Err(anyhow!(
+                "Download directory \"{}\" is not a directory.",
+                root.display()
+            ));
+        }
+        Ok(Sandbox { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Turns a path from a manifest into a path below the root. Parts of the path that exist already may not be symbolic links,
+    // as those could lead anywhere. Parts that don't exist yet are ours to create.
+    // This can't guard against a link being swapped in between this check and writing the file.
+    // Only local users with write access to the download directory could do that, though, not the sender.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, SandboxError> {
+        let components = sanitize(path)?;
+
+        let mut resolved = self.root.clone();
+        for (index, component) in components.iter().enumerate() {
+            resolved.push(component);
+
+            let metadata = match fs::symlink_metadata(&resolved) {
+                Ok(metadata) => metadata,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    // Nothing further down can exist either
+                    for component in &components[index + 1..] {
+                        resolved.push(component);
+                    }
+                    return Ok(resolved);
+                }
+                Err(_) => return Err(SandboxError::Inaccessible(resolved)),
+            };
+
+            if metadata.file_type().is_symlink() {
+                return Err(SandboxError::Symlink(resolved));
+            }
+            if index + 1 < components.len() && !metadata.is_dir() {
+                return Err(SandboxError::NotADirectory(resolved));
+            }
+        }
+
+        Ok(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+    use std::path::Component;
+
+    #[test]
+    fn hostile_paths() {
+        let accepted = [
+            ("photos/ferris.jpg", vec!["photos", "ferris.jpg"]),
+            (
+                "photos\\2021\\ferris.jpg",
+                vec!["photos", "2021", "ferris.jpg"],
+            ),
+            ("./a//b/./c", vec!["a", "b", "c"]),
+            (".hidden", vec![".hidden"]),
+            ("...and more", vec!["...and more"]),
+            ("console.log", vec!["console.log"]),
+            ("COM10", vec!["COM10"]),
+            ("smørrebrød.txt", vec!["smørrebrød.txt"]),
+        ];
+        for (path, components) in &accepted {
+            assert_eq!(sanitize(path).as_ref(), Ok(components), "{}", path);
+        }
+
+        let rejected = [
+            ("", SandboxError::Empty),
+            ("./", SandboxError::Empty),
+            ("/etc/passwd", SandboxError::Absolute),
+            ("\\\\server\\share\\file", SandboxError::Absolute),
+            ("..", SandboxError::ParentDirectory),
+            ("a/../../b", SandboxError::ParentDirectory),
+            ("a\\..\\..\\b", SandboxError::ParentDirectory),
+            (
+                "C:\\Windows\\win.ini",
+                SandboxError::DriveLetter(String::from("C:")),
+            ),
+            ("c:relative", SandboxError::DriveLetter(String::from("c:"))),
+            ("file.txt:stream", SandboxError::InvalidCharacter(':')),
+            ("a/b\0c", SandboxError::InvalidCharacter('\0')),
+            ("what?", SandboxError::InvalidCharacter('?')),
+            (
+                "a/.. ",
+                SandboxError::TrailingDotOrSpace(String::from(".. ")),
+            ),
+            ("a/b.", SandboxError::TrailingDotOrSpace(String::from("b."))),
+            ("nul", SandboxError::ReservedName(String::from("nul"))),
+            (
+                "a/Con.txt",
+                SandboxError::ReservedName(String::from("Con.txt")),
+            ),
+            ("LPT1", SandboxError::ReservedName(String::from("LPT1"))),
+            (
+                "COM¹.tar.gz",
+                SandboxError::ReservedName(String::from("COM¹.tar.gz")),
+            ),
+            (
+                "aux .txt",
+                SandboxError::ReservedName(String::from("aux .txt")),
+            ),
+            (
+                "a.jpg.bitgeon.part",
+                SandboxError::ReservedName(String::from("a.jpg.bitgeon.part")),
+            ),
+        ];
+        for (path, error) in &rejected {
+            assert_eq!(sanitize(path).as_ref(), Err(error), "{}", path);
+        }
+        assert_eq!(sanitize(&"a".repeat(256)), Err(SandboxError::NameTooLong));
+    }
+
+    // Throws random combinations of nasty fragments at the resolver. Whatever it accepts has to stay below the root.
+    #[test]
+    fn random_manifests() {
+        let directory = TempDir::new("sandbox-fuzz");
+        let sandbox = Sandbox::new(directory.path()).unwrap();
+        let fragments = [
+            "..", ".", "", "/", "\\", "a", "b.txt", "C:", ":", "CON", "nul.txt", " ", "\0", "..\\",
+            "../", "~", "%2e%2e", "\u{202e}", "é", "*", "\\\\?\\", "//", "a.", ".a",
+        ];
+
+        // xorshift keeps the test reproducible without pulling in a random number generator
+        let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..20_000 {
+            let length = next() % 8;
+            let path: String = (0..length)
+                .map(|_| fragments[(next() % fragments.len() as u64) as usize])
+                .collect();
+
+            if let Ok(resolved) = sandbox.resolve(&path) {
+                let relative = resolved.strip_prefix(sandbox.root()).unwrap();
+                assert!(
+                    relative
+                        .components()
+                        .all(|component| matches!(component, Component::Normal(_))),
+                    "{:?} resolved to {:?}",
+                    path,
+                    resolved
+                );
+                assert!(relative.components().next().is_some());
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks() {
+        let directory = TempDir::new("sandbox-links");
+        let outside = TempDir::new("sandbox-outside");
+        let sandbox = Sandbox::new(directory.path()).unwrap();
+        let root = sandbox.root().to_path_buf();
+
+        fs::create_dir(root.join("photos")).unwrap();
+        fs::write(root.join("notes.txt"), "").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(root.join("photos"), root.join("inside")).unwrap();
+
+        assert_eq!(
+            sandbox.resolve("photos/new/ferris.jpg"),
+            Ok(root.join("photos").join("new").join("ferris.jpg"))
+        );
+        assert_eq!(
+            sandbox.resolve("escape/passwd"),
+            Err(SandboxError::Symlink(root.join("escape")))
+        );
+        assert_eq!(
+            sandbox.resolve("escape"),
+            Err(SandboxError::Symlink(root.join("escape")))
+        );
+        // Even links that stay inside are refused. Checking where they lead would just invite mistakes.
+        assert_eq!(
+            sandbox.resolve("inside/ferris.jpg"),
+            Err(SandboxError::Symlink(root.join("inside")))
+        );
+        assert_eq!(
+            sandbox.resolve("notes.txt/ferris.jpg"),
+            Err(SandboxError::NotADirectory(root.join("notes.txt")))
+        );
+    }
+}
@@ -1,17 +1,45 @@
 // https://github.com/ctz/rustls
 
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
-use std::net::{IpAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
 use std::time;
 
 use anyhow::{anyhow, Context, Result};
+use async_std::channel;
+use async_std::task;
+use crossbeam_channel::TrySendError;
 use thiserror::Error;
 
 use crate::backend;
+use crate::chat;
+use crate::download;
+use crate::handshake::Capabilities;
+use crate::hashing;
+use crate::history::{self, History, Severity};
+use crate::job::{self, Job};
+use crate::link::{self, Link};
+use crate::manifest::Manifest;
+use crate::peer::{self, ConnectionHealth, Direction, PeerHandle};
+use crate::protocol;
+use crate::reachability;
 use crate::settings::ServerSettings;
+use crate::shared;
+use crate::stun;
+use crate::sync;
+use crate::transport::{Connection, Listener, TransportKind};
 use crate::ui;
 use crate::util;
+use crate::version::{self, Version};
+
+// How often the progress of sends being hashed is shown
+const HASHING_REFRESH: time::Duration = time::Duration::from_millis(250);
+// Chunks come in far more often than progress needs to be shown
+const DOWNLOAD_REFRESH: time::Duration = time::Duration::from_millis(200);
 
 // TODO: Figure out how this error handling should actually be done
 #[derive(Debug, Error)]
@@ -28,6 +56,92 @@ pub enum ServerStatus {
     PublicIpError(anyhow::Error),
     #[error("Unable to create TCPListener")]
     TcpBindError(anyhow::Error),
+    #[error("Unable to create QUIC endpoint")]
+    QuicBindError(anyhow::Error),
+    #[error("Unable to connect to peer")]
+    ConnectionError(anyhow::Error),
+    #[error("Refreshing connection")]
+    Refreshing,
+}
+
+impl ServerStatus {
+    // The status together with the chain of errors that caused it
+    pub fn report(&self) -> String {
+        match self {
+            ServerStatus::InternalPortError(error)
+            | ServerStatus::ExternalPortError(error)
+            | ServerStatus::LocalIpError(error)
+            | ServerStatus::PublicIpError(error)
+            | ServerStatus::TcpBindError(error)
+            | ServerStatus::QuicBindError(error)
+            | ServerStatus::ConnectionError(error) => format!("{}: {:#}", self, error),
+            ServerStatus::Ok | ServerStatus::Refreshing => format!("{}", self),
+        }
+    }
+}
+
+// How peers can reach us
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reachability {
+    Internet(SocketAddr),     // Through the port mapping on the gateway
+    LocalNetwork(SocketAddr), // Only from within the local network
+    Unreachable,
+}
+
+impl fmt::Display for Reachability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reachability::Internet(address) => {
+                write!(f, "{} (reachable from the internet)", address)
+            }
+            Reachability::LocalNetwork(address) => {
+                write!(f, "{} (reachable from the local network only)", address)
+            }
+            Reachability::Unreachable => write!(f, "Not reachable"),
+        }
+    }
+}
+
+// Everything the user needs to know about our end of the connection
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub reachability: Reachability,
+    pub status: String,
+    pub lease_expiry: Option<time::Instant>, // When the port mapping on the gateway runs out
+    pub self_test: Option<reachability::Report>, // None until the reachability self-test has finished
+    pub secret_key: String,
+    pub link: Option<Link>, // For peers to connect to us. None while we are unreachable
+}
+
+// Files a peer would like to send us, waiting for the user to decide
+#[derive(Clone, Debug)]
+pub struct Offer {
+    pub peer: usize,
+    pub address: SocketAddr,
+    pub files: usize,
+    pub total_size: u64,
+    pub preflight: Option<download::Preflight>, // None if the free space couldn't be determined
+    pub sync: bool,         // Accepting means taking whatever changes come after, too
+    pub two_way: bool,      // Accepting means sending our changes back as well
+    pub directory: PathBuf, // Where the files go, unless another place is chosen when accepting. Syncs always go here
+}
+
+impl Offer {
+    // Offers that don't fit can only be declined
+    pub fn acceptable(&self) -> bool {
+        self.preflight
+            .as_ref()
+            .is_none_or(|preflight| preflight.space() != download::Space::Insufficient)
+    }
+}
+
+// Text a peer has sent us to copy. It is only kept around until the user has dealt with it.
+#[derive(Clone, Debug)]
+pub struct Snippet {
+    pub peer: usize,
+    pub address: SocketAddr,
+    pub received: time::SystemTime,
+    pub text: String,
 }
 
 pub trait Data {}
@@ -57,26 +171,110 @@ pub mod data {
 pub mod event {
     use super::*;
 
-    pub enum Backend {}
+    pub enum Backend {
+        Connect(SocketAddr),
+        OpenLink(Link),
+        RefreshConnection,
+    }
 
-    pub enum Ui {}
+    pub enum Ui {
+        Connect(SocketAddr),
+        OpenLink(Link),
+        ExportHistory,
+        AnswerOffer {
+            peer: usize,
+            accept: bool,
+            directory: Option<PathBuf>, // Where to put the files instead of the download directory
+        },
+        Chat {
+            peer: usize,
+            text: String,
+        },
+        SendSnippet {
+            peer: usize,
+            text: String,
+        },
+        SaveSnippet(Snippet),
+        SendFiles {
+            peers: Vec<usize>,
+            paths: Vec<PathBuf>,
+        },
+        SyncFiles {
+            peers: Vec<usize>,
+            paths: Vec<PathBuf>,
+            two_way: bool,
+        },
+        ResolveConflict {
+            conflict: usize,
+            resolution: shared::Resolution,
+        },
+        CancelHashing, // Drops every send that is still being hashed
+    }
 
     impl Event for Backend {}
     impl Event for Ui {}
 }
 
-struct Job {}
+// Everything the server's main task reacts to
+pub enum Notification {
+    Application(Message<data::Backend, event::Backend>),
+    Ui(Message<data::Ui, event::Ui>),
+    Connected(Connection, Direction),
+    ConnectionFailed(anyhow::Error),
+    SelfTest(Result<(SocketAddr, bool)>),
+    Peer(usize, peer::Event),
+    Hashing(usize), // Holds the ID of the send being hashed, whose progress is due for display
+    SourceBuilt(usize, Vec<usize>, Result<job::Source>), // Holds the IDs of the send being hashed and the peers to send to
+    AnswerDeadline(usize),                               // Holds the ID of the job
+    Job(usize, usize, job::Update),                      // Holds the IDs of the job and the peer
+    SyncChanged(usize),                                  // Holds the ID of the sync
+    SyncScanned(usize, sync::Scanner, Result<job::Source>),
+    Addresses(usize, &'static str, Addresses), // Holds the number of the connection refresh and what it was for
+    PortMapped(usize, Result<u16>), // Holds the number of the connection refresh the mapping was renewed in
+}
+
+// What a connection refresh finds out about our addresses. Asking the gateway and the STUN servers takes a few round trips,
+// so it happens on a blocking task.
+pub struct Addresses {
+    discovery: Result<stun::Discovery>,
+    local_ip: Result<IpAddr>,
+    gateway_ip: Result<IpAddr>,
+    external_port: Result<u16>,
+}
 
 pub struct Server {
-    listener: Option<TcpListener>,
+    listeners: Vec<Arc<Listener>>,
+    acceptors: Vec<task::JoinHandle<()>>,
     local_ip: Option<IpAddr>,
     public_ip: Option<IpAddr>,
+    gateway_ip: Option<IpAddr>, // External IP according to the gateway. Not ours, if there is another NAT in front of it
+    mapping: Option<stun::MappingBehavior>,
     internal_port: Option<u16>,
     external_port: Option<u16>,
     upnp_lease_clock: time::Instant,
     upnp_lease_duration: time::Duration,
-    peers: Vec<Peer>,
-    status: ServerStatus, // Should we have a vector of errors or only store one at a time?
+    renewing: bool, // Whether a renewal of the port mapping is on its way
+    refresh: usize, // Counts connection refreshes, so the late results of an earlier one can be told apart
+    self_test: Option<reachability::Report>,
+    peers: BTreeMap<usize, PeerHandle>,
+    next_peer_id: usize,
+    offers: BTreeMap<usize, (Manifest, job::Kind)>, // By peer. Each peer can only have one offer pending
+    links: Vec<Link>, // Followed links with a manifest root hash, until the peer offers something
+    jobs: BTreeMap<usize, Job>, // Files we are sending, until every recipient is done with them
+    next_job_id: usize,
+    hashing: BTreeMap<usize, Arc<hashing::Progress>>, // Sends that can't be offered before their files are hashed
+    next_hashing_id: usize,
+    downloads: BTreeMap<usize, download::Download>, // By peer. Offers we have accepted
+    downloads_shown: time::Instant, // When the progress of the downloads was last shown
+    syncs: BTreeMap<usize, sync::Session>, // Paths we keep in sync with a peer
+    next_sync_id: usize,
+    synced: BTreeMap<usize, BTreeSet<String>>, // By peer. Paths we got through their sync, the only ones it may remove
+    replica: u64, // Tells our edits apart from the peers' in two-way syncs
+    conflicts: BTreeMap<usize, shared::Conflict>, // Until the user resolves them
+    next_conflict_id: usize,
+
+    status: ServerStatus, // Latest status. Earlier ones are kept in the history
+    history: History,
     application: util::ThreadChannel<
         backend::Message<backend::data::Server, backend::event::Server>,
         Message<data::Backend, event::Backend>,
@@ -85,10 +283,12 @@ pub struct Server {
         ui::Message<ui::data::Server, ui::event::Server>,
         Message<data::Ui, event::Ui>,
     >,
+    progress: util::Latest<ui::data::Server>,
+    notifications: (
+        channel::Sender<Notification>,
+        channel::Receiver<Notification>,
+    ),
     secret_key: String,
-    clock: time::Instant,
-    queue: VecDeque<Job>,
-    frame_count: u128,
     settings: ServerSettings,
 }
 
@@ -102,119 +302,1655 @@ impl Server {
             ui::Message<ui::data::Server, ui::event::Server>,
             Message<data::Ui, event::Ui>,
         >,
+        progress: util::Latest<ui::data::Server>,
     ) -> Self {
         Self {
-            listener: None,
+            listeners: vec![],
+            acceptors: vec![],
             local_ip: None,
             public_ip: None,
+            gateway_ip: None,
+            mapping: None,
             internal_port: None,
             external_port: None,
             upnp_lease_clock: time::Instant::now(),
             upnp_lease_duration: time::Duration::from_secs(60 * 15), // TODO: Don't hard code this. Read from config file but also provide default value
-            peers: vec![],
+            renewing: false,
+            refresh: 0,
+            self_test: None,
+            peers: BTreeMap::new(),
+            next_peer_id: 0,
+            offers: BTreeMap::new(),
+            links: vec![],
+            jobs: BTreeMap::new(),
+            next_job_id: 0,
+            hashing: BTreeMap::new(),
+            next_hashing_id: 0,
+            downloads: BTreeMap::new(),
+            downloads_shown: time::Instant::now(),
+            syncs: BTreeMap::new(),
+            next_sync_id: 0,
+            synced: BTreeMap::new(),
+            replica: version::new_replica(),
+            conflicts: BTreeMap::new(),
+            next_conflict_id: 0,
             status: ServerStatus::Ok,
+            history: History::new(ServerSettings::default().history_capacity),
             application,
             ui,
+            progress,
+            notifications: channel::bounded(ServerSettings::default().notification_capacity),
             secret_key: String::from("Swordfish"),
-            clock: time::Instant::now(),
-            queue: VecDeque::new(),
-            frame_count: 0,
             settings: ServerSettings::default(),
         }
     }
 
     pub fn run(&mut self) -> Result<()> {
+        task::block_on(self.serve())
+    }
+
+    // Instead of polling, the server sleeps until one of its tasks, the application or the UI has something for it
+    async fn serve(&mut self) -> Result<()> {
         // Initialize server
-        self.refresh_connection();
-        self.display_connection();
+        self.clean_up_downloads()?;
+        self.refresh_connection("Connection setup")?;
+        self.display_connection()?;
+        self.listen().await;
+
+        // The other threads talk to us through blocking channels, so each of those gets a thread that forwards into our event queue.
+        // Forwarding waits while the event queue is full, so the other threads notice when we fall behind.
+        let notifications = self.notifications.0.clone();
+        forward(self.application.receiver.clone(), move |message| {
+            task::block_on(notifications.send(Notification::Application(message))).is_ok()
+        });
+        let notifications = self.notifications.0.clone();
+        forward(self.ui.receiver.clone(), move |message| {
+            task::block_on(notifications.send(Notification::Ui(message))).is_ok()
+        });
 
         loop {
-            // Listen for incoming connections
-            // Listen for incoming messages from peers
-            // Listen for messages from application or UI
-            // Work on jobs
-            // Sleep
-
-            util::sleep_remaining_frame(
-                &self.clock,
-                &mut self.frame_count,
-                self.settings.logic_refresh_rate,
-            );
+            // Renew the port mapping shortly before it runs out
+            let notification = match self.lease_renewal() {
+                Some(renewal) => {
+                    let wait = renewal.saturating_duration_since(time::Instant::now());
+                    async_std::future::timeout(wait, self.notifications.1.recv()).await
+                }
+                None => Ok(self.notifications.1.recv().await),
+            };
+            let notification = match notification {
+                Ok(Ok(notification)) => notification,
+                Ok(Err(_)) => break,
+                Err(_) => {
+                    self.renew_port_mapping();
+                    continue;
+                }
+            };
+
+            match notification {
+                // Messages from application or UI
+                Notification::Application(Message::Event(event::Backend::Connect(address))) => {
+                    self.establish_connection(address)
+                }
+                Notification::Application(Message::Event(event::Backend::OpenLink(link))) => {
+                    self.open_link(link)?
+                }
+                Notification::Application(Message::Event(event::Backend::RefreshConnection)) => {
+                    self.status = ServerStatus::Refreshing;
+                    self.display_connection()?;
+                    self.refresh_connection("Connection refresh")?;
+                    self.listen().await;
+                    self.display_connection()?;
+                }
+                Notification::Application(Message::Data(data)) => match data {},
+                Notification::Ui(message) => match message {
+                    Message::Data(data) => match data {},
+                    Message::Event(event::Ui::ExportHistory) => self.export_history()?,
+                    Message::Event(event::Ui::Connect(address)) => {
+                        self.establish_connection(address)
+                    }
+                    Message::Event(event::Ui::OpenLink(link)) => self.open_link(link)?,
+                    Message::Event(event::Ui::AnswerOffer {
+                        peer,
+                        accept,
+                        directory,
+                    }) => self.answer_offer(peer, accept, directory)?,
+                    Message::Event(event::Ui::Chat { peer, text }) => {
+                        self.send_chat(peer, &text)?
+                    }
+                    Message::Event(event::Ui::SendSnippet { peer, text }) => {
+                        self.send_snippet(peer, &text)?
+                    }
+                    Message::Event(event::Ui::SaveSnippet(snippet)) => {
+                        self.save_snippet(&snippet)?
+                    }
+                    Message::Event(event::Ui::SendFiles { peers, paths }) => {
+                        self.prepare_job(peers, paths)?
+                    }
+                    Message::Event(event::Ui::SyncFiles {
+                        peers,
+                        paths,
+                        two_way,
+                    }) => self.start_sync(peers, paths, two_way)?,
+                    Message::Event(event::Ui::ResolveConflict {
+                        conflict,
+                        resolution,
+                    }) => self.resolve_conflict(conflict, resolution)?,
+                    Message::Event(event::Ui::CancelHashing) => {
+                        // The sends go once their hashing has stopped
+                        for progress in self.hashing.values() {
+                            progress.cancel();
+                        }
+                    }
+                },
+
+                // New connections
+                Notification::Connected(connection, direction) => {
+                    let id = self.next_peer_id;
+                    self.next_peer_id += 1;
+                    let peer = peer::spawn(
+                        id,
+                        connection,
+                        direction,
+                        self.settings.heartbeat.clone(),
+                        self.settings.inbox_capacity,
+                        self.notifications.0.clone(),
+                    );
+                    self.record(
+                        Severity::Info,
+                        &format!("Peer {}", peer.status.address),
+                        &format!("Connected via {}", peer.status.transport),
+                    )?;
+                    let address = peer.status.address;
+                    self.peers.insert(id, peer);
+                    self.display_peers()?;
+                    self.display_chat_history(id, address)?;
+                }
+                Notification::ConnectionFailed(error) => {
+                    self.status = ServerStatus::ConnectionError(error);
+                    self.record_status("Outgoing connection")?;
+                    self.display_connection()?;
+                }
+
+                // Late results of a refresh that has been started over are of no use
+                Notification::Addresses(refresh, context, addresses) => {
+                    if refresh == self.refresh {
+                        self.found_addresses(addresses)?;
+                        self.record_status(context)?;
+                        self.display_connection()?;
+                        self.test_reachability();
+                    }
+                }
+                Notification::PortMapped(refresh, result) => {
+                    if refresh == self.refresh {
+                        self.renewed_port_mapping(result)?;
+                    }
+                }
+
+                Notification::SelfTest(result) => {
+                    match result {
+                        Ok((observed, reachable)) => {
+                            let report = reachability::Report::new(
+                                observed,
+                                reachable,
+                                self.local_ip,
+                                self.gateway_ip,
+                                self.mapping,
+                            );
+                            let severity = match report.recommendation {
+                                reachability::Recommendation::Direct => Severity::Info,
+                                _ => Severity::Warning,
+                            };
+                            self.record(severity, "Reachability self-test", &report.to_string())?;
+                            self.self_test = Some(report);
+                        }
+                        Err(error) => self.record(
+                            Severity::Warning,
+                            "Reachability self-test",
+                            &format!("{:#}", error),
+                        )?,
+                    }
+                    self.display_connection()?;
+                }
+
+                // Messages from peers
+                Notification::Peer(id, peer::Event::Status(status)) => {
+                    // Peers we have let go of may still report in while shutting down
+                    let (previous, agreement) = match self.peers.get(&id) {
+                        Some(peer) => (peer.status.health, peer.status.agreement),
+                        None => continue,
+                    };
+                    if let (None, Some(new_agreement)) = (agreement, status.agreement) {
+                        self.record(
+                            Severity::Info,
+                            &format!("Peer {}", status.address),
+                            &new_agreement.to_string(),
+                        )?;
+                    }
+                    if previous != status.health {
+                        let (severity, message) = match status.health {
+                            ConnectionHealth::Healthy => (Severity::Info, String::from("Healthy")),
+                            ConnectionHealth::Unresponsive => {
+                                (Severity::Warning, String::from("Not answering pings"))
+                            }
+                            ConnectionHealth::Reconnecting(attempts) => (
+                                Severity::Warning,
+                                format!("Connection lost. Reconnect attempt {}", attempts + 1),
+                            ),
+                            ConnectionHealth::Dead => {
+                                (Severity::Error, String::from("Connection lost"))
+                            }
+                        };
+                        self.record(severity, &format!("Peer {}", status.address), &message)?;
+                    }
+
+                    if status.health == ConnectionHealth::Dead {
+                        self.peers.remove(&id);
+                        if self.offers.remove(&id).is_some() {
+                            self.show(ui::data::Server::OfferWithdrawn(id))?;
+                        }
+                        if let Some(download) = self.downloads.remove(&id) {
+                            let message = format!(
+                                "Download stopped after {} of {}",
+                                util::format_size(download.received()),
+                                util::format_size(download.manifest().total_size())
+                            );
+                            let state = download::State::Failed(message.clone());
+                            let ended = download.status(id, status.address, state);
+                            self.display_downloads(Some(ended))?;
+                            self.record(
+                                Severity::Warning,
+                                &format!("Peer {}", status.address),
+                                &message,
+                            )?;
+                        }
+                        for job in self.jobs.values_mut() {
+                            job.peer_gone(id);
+                        }
+                        self.syncs.retain(|_, session| session.peer != id);
+                        self.synced.remove(&id);
+                        // A link to the peer has nothing more to offer
+                        self.links
+                            .retain(|link| !link.addresses.contains(&status.address));
+                        self.refresh_jobs()?;
+                    } else if let Some(peer) = self.peers.get_mut(&id) {
+                        peer.status = status;
+                    }
+                    self.display_peers()?;
+                }
+                Notification::Peer(
+                    id,
+                    peer::Event::Message(protocol::Message::ProbeCallback { .. }),
+                ) => {
+                    // The probe server has got what it came for. It isn't a real peer, so let it go quietly.
+                    if let Some(peer) = self.peers.remove(&id) {
+                        self.record(
+                            Severity::Info,
+                            &format!("Peer {}", peer.status.address),
+                            "Answered reachability probe",
+                        )?;
+                        self.display_peers()?;
+                    }
+                }
+                Notification::Peer(
+                    id,
+                    peer::Event::Message(protocol::Message::Offer { manifest }),
+                ) => self.receive_offer(id, manifest, job::Kind::Files)?,
+                Notification::Peer(
+                    id,
+                    peer::Event::Message(protocol::Message::SyncOffer { manifest, removed }),
+                ) => self.receive_offer(id, manifest, job::Kind::Sync(removed))?,
+                Notification::Peer(
+                    id,
+                    peer::Event::Message(protocol::Message::FolderOffer {
+                        manifest,
+                        versions,
+                        removed,
+                    }),
+                ) => self.receive_folder(id, manifest, versions, removed)?,
+                Notification::Peer(id, peer::Event::Chat(text)) => {
+                    self.store_chat(id, chat::Line::new(chat::Author::Peer, &text))?
+                }
+                Notification::Peer(id, peer::Event::Snippet(text)) => {
+                    self.receive_snippet(id, text)?
+                }
+                Notification::Peer(id, peer::Event::Incompatible(error)) => {
+                    if let Some(peer) = self.peers.get(&id) {
+                        let context = format!("Peer {}", peer.status.address);
+                        self.record(Severity::Error, &context, &error.to_string())?;
+                    }
+                }
+                Notification::Peer(
+                    id,
+                    peer::Event::Message(protocol::Message::OfferAnswer { accepted }),
+                ) => self.receive_answer(id, accepted)?,
+                Notification::Peer(
+                    id,
+                    peer::Event::Message(protocol::Message::FileChunk { file, offset, data }),
+                ) => self.receive_chunk(id, file, offset, download::Piece::Data(&data))?,
+                Notification::Peer(
+                    id,
+                    peer::Event::Message(protocol::Message::FileHole {
+                        file,
+                        offset,
+                        length,
+                    }),
+                ) => self.receive_chunk(id, file, offset, download::Piece::Hole(length))?,
+                Notification::Peer(
+                    id,
+                    peer::Event::Message(protocol::Message::FileCopy {
+                        file,
+                        offset,
+                        length,
+                        source,
+                        source_offset,
+                    }),
+                ) => {
+                    let piece = download::Piece::Copy {
+                        file: source,
+                        offset: source_offset,
+                        length,
+                    };
+                    self.receive_chunk(id, file, offset, piece)?
+                }
+                Notification::Peer(_, peer::Event::Message(_)) => (),
+
+                // Send jobs
+                Notification::Hashing(_) => self.display_hashing()?,
+                Notification::SourceBuilt(id, peers, source) => {
+                    self.hashing.remove(&id);
+                    self.display_hashing()?;
+                    match source {
+                        Ok(source) => {
+                            self.start_job(peers, source, job::Kind::Files)?;
+                        }
+                        Err(error) if error.is::<hashing::Cancelled>() => {
+                            self.record(Severity::Info, "Send files", "Cancelled")?
+                        }
+                        Err(error) => {
+                            self.record(Severity::Error, "Send files", &format!("{:#}", error))?
+                        }
+                    }
+                }
+                Notification::AnswerDeadline(id) => {
+                    if let Some(job) = self.jobs.get_mut(&id) {
+                        job.start();
+                    }
+                }
+                Notification::Job(id, peer, update) => self.update_job(id, peer, update)?,
+
+                // Folder syncs
+                Notification::SyncChanged(id) => {
+                    self.scan_sync(id);
+                    self.display_syncs()?;
+                }
+                Notification::SyncScanned(id, scanner, source) => {
+                    self.sync_scanned(id, scanner, source)?
+                }
+            }
         }
 
         Ok(())
     }
 
-    pub fn refresh_connection(&mut self) {
-        self.status = ServerStatus::Ok;
+    // Accepts incoming connections on every listener in the background
+    async fn listen(&mut self) {
+        for acceptor in self.acceptors.drain(..) {
+            acceptor.cancel().await;
+        }
 
-        // Bind listener to every available interface. Let OS provide an available port.
-        // TODO: Perhaps let the user specify a port on their own
-        match TcpListener::bind("0.0.0.0:0") {
-            Ok(listener) => self.listener = Some(listener),
+        for listener in &self.listeners {
+            let listener = listener.clone();
+            let notifications = self.notifications.0.clone();
+            let timeout = self.settings.heartbeat.connect_timeout;
+            self.acceptors.push(task::spawn(async move {
+                while let Some(incoming) = listener.next().await {
+                    let notifications = notifications.clone();
+                    task::spawn(async move {
+                        if let Ok(connection) = incoming.establish(timeout).await {
+                            let notification =
+                                Notification::Connected(connection, Direction::Incoming);
+                            let _ = notifications.send(notification).await;
+                        }
+                    });
+                }
+            }));
+        }
+    }
+
+    // Has the probe server check whether peers on the internet can reach us, if one is configured.
+    // Reports back with a notification, as this involves a few round trips.
+    fn test_reachability(&self) {
+        let probe_server = match self.settings.probe_server {
+            Some(probe_server) => probe_server,
+            None => return,
+        };
+        let port = match self.external_port.or(self.internal_port) {
+            Some(port) => port,
+            None => return,
+        };
+
+        let timeout = self.settings.heartbeat.ping_interval;
+        let notifications = self.notifications.0.clone();
+        task::spawn(async move {
+            let result = reachability::self_test(probe_server, port, timeout).await;
+            let _ = notifications.send(Notification::SelfTest(result)).await;
+        });
+    }
+
+    // Adds an entry to the history and passes it on to the UI
+    fn record(&mut self, severity: Severity, context: &str, message: &str) -> Result<()> {
+        let entry = history::Entry::new(severity, context, message);
+        self.history.push(entry.clone());
+        self.show(ui::data::Server::HistoryEntry(entry))?;
+        Ok(())
+    }
+
+    fn record_status(&mut self, context: &str) -> Result<()> {
+        let severity = match self.status {
+            ServerStatus::Ok | ServerStatus::Refreshing => Severity::Info,
+            _ => Severity::Error,
+        };
+        self.record(severity, context, &self.status.report())
+    }
+
+    // Checks whether an offer fits onto the disk and lets the user decide. Syncs come with the paths the peer has removed.
+    fn receive_offer(&mut self, id: usize, manifest: Manifest, kind: job::Kind) -> Result<()> {
+        let address = match self.peers.get(&id) {
+            Some(peer) => peer.status.address,
+            None => return Ok(()),
+        };
+        let context = format!("Peer {}", address);
+        let sync = kind != job::Kind::Files;
+        let two_way = matches!(kind, job::Kind::Folder(..));
+        if let job::Kind::Sync(removed) = &kind {
+            self.remove_synced(id, &context, removed)?;
+        }
+
+        let preflight = match download::Preflight::check(
+            &self.settings.download.directory,
+            manifest.total_size(),
+            self.settings.download.free_space_margin,
+        ) {
+            Ok(preflight) => Some(preflight),
             Err(error) => {
-                self.status = ServerStatus::TcpBindError(anyhow!(error));
-                return;
+                self.record(Severity::Warning, &context, &format!("{:#}", error))?;
+                None
             }
+        };
+
+        let offer = Offer {
+            peer: id,
+            address,
+            files: manifest.len(),
+            total_size: manifest.total_size(),
+            preflight,
+            sync,
+            two_way,
+            directory: self.settings.download.directory.clone(),
+        };
+        let severity = match offer.preflight.as_ref().map(|preflight| preflight.space()) {
+            Some(download::Space::Insufficient) => Severity::Warning,
+            _ => Severity::Info,
+        };
+        let mut message = format!(
+            "{} {} files ({})",
+            if sync { "Offers to keep" } else { "Offers" },
+            offer.files,
+            util::format_size(offer.total_size)
+        );
+        if sync {
+            message.push_str(if two_way {
+                " in sync both ways"
+            } else {
+                " in sync"
+            });
         }
+        if let Some(preflight) = &offer.preflight {
+            message.push_str(&format!(". {}", preflight));
+        }
+        self.record(severity, &context, &message)?;
 
-        match get_local_ip() {
-            Ok(ip) => self.local_ip = Some(ip),
+        // Changes to a sync the user has agreed to come in without asking again
+        if sync && !two_way && self.synced.contains_key(&id) {
+            let accept = offer.acceptable();
+            self.offers.insert(id, (manifest, kind));
+            return self.answer_offer(id, accept, None);
+        }
+
+        // The user has already agreed to the download by following a link to it. Only ask again if it isn't what the link promised.
+        if let Some(index) = self
+            .links
+            .iter()
+            .position(|link| link.addresses.contains(&address))
+        {
+            let link = self.links.remove(index);
+            if link.root == Some(manifest.root_hash()) && offer.acceptable() {
+                self.offers.insert(id, (manifest, kind));
+                self.record(Severity::Info, &context, "Offer matches the link")?;
+                return self.answer_offer(id, true, None);
+            }
+            self.record(
+                Severity::Warning,
+                &context,
+                "Offer doesn't match the link, or doesn't fit",
+            )?;
+        }
+
+        self.offers.insert(id, (manifest, kind));
+        self.show(ui::data::Server::Offer(offer))?;
+        Ok(())
+    }
+
+    fn answer_offer(&mut self, id: usize, accept: bool, directory: Option<PathBuf>) -> Result<()> {
+        let (manifest, kind) = match self.offers.remove(&id) {
+            Some(offer) => offer,
+            None => return Ok(()), // Withdrawn in the meantime
+        };
+        let address = match self.peers.get(&id) {
+            Some(peer) => peer.status.address,
+            None => return Ok(()),
+        };
+        let context = format!("Peer {}", address);
+        let wanted = accept;
+        let mut accept = accept;
+        let mut failure = None; // Why an accepted offer was declined after all
+        let sync = match kind {
+            job::Kind::Folder(versions, removed) if accept => {
+                return self.accept_folder(id, manifest, versions, removed)
+            }
+            job::Kind::Files => false,
+            _ => true,
+        };
+
+        // The chunks start coming in right after the answer, so there has to be somewhere to put them.
+        // A sync may leave nothing to download when all that changed is files being removed.
+        let paths: Vec<String> = match sync {
+            true => manifest
+                .entries
+                .iter()
+                .map(|entry| entry.path.clone())
+                .collect(),
+            false => vec![],
+        };
+        // Syncs remove files from the download directory later on, so they have to stay there
+        let default = &self.settings.download.directory;
+        let directory = match directory {
+            Some(directory) if !sync && directory != *default => {
+                // The free space was checked for the download directory, which may be on another drive
+                let fits = download::Preflight::check(
+                    &directory,
+                    manifest.total_size(),
+                    self.settings.download.free_space_margin,
+                )
+                .map_or(true, |preflight| {
+                    preflight.space() != download::Space::Insufficient
+                });
+                if accept && !fits {
+                    let message = format!("Not enough space in \"{}\"", directory.display());
+                    self.record(Severity::Warning, &context, &message)?;
+                    failure = Some(message);
+                }
+                accept = accept && fits;
+                directory
+            }
+            _ => default.clone(),
+        };
+        // Taken before the manifest goes to the download, for telling how an accepted offer ended without one
+        let (files, total_size, empty) =
+            (manifest.len(), manifest.total_size(), manifest.is_empty());
+        if accept && !empty {
+            let download = match sync {
+                true => download::Download::replacing(&directory, manifest),
+                false => download::Download::new(&directory, manifest),
+            };
+            match download {
+                Ok(download) => {
+                    self.downloads.insert(id, download);
+                    self.display_downloads(None)?;
+                }
+                Err(error) => {
+                    let error = format!("{:#}", error);
+                    self.record(Severity::Error, &context, &error)?;
+                    failure = Some(error);
+                    accept = false;
+                }
+            }
+        }
+
+        let message = if accept {
+            "Offer accepted"
+        } else {
+            "Offer declined"
+        };
+        let result = match self.peers.get(&id) {
+            Some(peer) => peer.send(protocol::Message::OfferAnswer { accepted: accept }),
+            None => return Ok(()),
+        };
+        match result {
+            Ok(()) => {
+                if sync && accept {
+                    self.synced.entry(id).or_default().extend(paths);
+                }
+                self.record(Severity::Info, &context, message)?;
+            }
+            Err(error) => {
+                let error = format!("{:#}", error);
+                self.downloads.remove(&id);
+                self.record(Severity::Warning, &context, &error)?;
+                failure = failure.or(Some(error));
+            }
+        }
+
+        // Whoever waits for the accepted offer to turn into a download has to learn when it won't
+        let state = match failure {
+            Some(error) if wanted => download::State::Failed(error),
+            None if accept && empty => download::State::Done,
+            _ => return Ok(()),
+        };
+        self.display_downloads(Some(download::Status {
+            peer: id,
+            address,
+            directory,
+            files,
+            completed: 0,
+            total_size,
+            received: 0,
+            state,
+        }))
+    }
+
+    // Only files the peer has sent through its sync are removed. Everything else in the download directory stays put.
+    fn remove_synced(&mut self, id: usize, context: &str, removed: &[String]) -> Result<()> {
+        let synced = match self.synced.get_mut(&id) {
+            Some(synced) => synced,
+            None => return Ok(()),
+        };
+        let paths: Vec<String> = removed
+            .iter()
+            .filter(|path| synced.remove(path.as_str()))
+            .cloned()
+            .collect();
+        if paths.is_empty() {
+            return Ok(());
+        }
+        match download::remove_files(&self.settings.download.directory, &paths) {
+            Ok(files) => self.record(
+                Severity::Info,
+                context,
+                &format!("Removed {} files the peer no longer has", files.len()),
+            ),
+            Err(error) => self.record(Severity::Error, context, &format!("{:#}", error)),
+        }
+    }
+
+    fn receive_chunk(
+        &mut self,
+        id: usize,
+        file: u32,
+        offset: u64,
+        piece: download::Piece,
+    ) -> Result<()> {
+        let address = match self.peers.get(&id) {
+            Some(peer) => peer.status.address,
+            None => return Ok(()),
+        };
+        let context = format!("Peer {}", address);
+        let download = match self.downloads.get_mut(&id) {
+            Some(download) => download,
+            None => {
+                return self.record(Severity::Warning, &context, "Sent a file nobody asked for")
+            }
+        };
+
+        let result = download.write_piece(file, offset, piece);
+        if let Ok(Some(_)) = result {
+            let entry = &download.manifest().entries[file as usize];
+            if let Some(session) = self
+                .syncs
+                .values_mut()
+                .find(|session| session.peer == id && session.is_shared())
+            {
+                session.arrived(entry);
+            }
+        }
+        match result {
+            Ok(_) if download.is_complete() => {
+                let message = format!(
+                    "Received {} files ({})",
+                    download.manifest().len(),
+                    util::format_size(download.received())
+                );
+                let ended = download.status(id, address, download::State::Done);
+                self.downloads.remove(&id);
+                self.display_downloads(Some(ended))?;
+                self.record(Severity::Info, &context, &message)
+            }
+            Ok(_) if self.downloads_shown.elapsed() >= DOWNLOAD_REFRESH => {
+                self.display_downloads(None)
+            }
+            Ok(_) => Ok(()),
+            // Whatever else comes in for this download won't fit anymore either
+            Err(error) => {
+                let error = format!("{:#}", error);
+                let ended = download.status(id, address, download::State::Failed(error.clone()));
+                self.downloads.remove(&id);
+                self.display_downloads(Some(ended))?;
+                self.record(Severity::Error, &context, &error)
+            }
+        }
+    }
+
+    fn peers_take(&self, peers: &[usize], capability: Capabilities) -> bool {
+        peers
+            .iter()
+            .filter_map(|peer| self.peers.get(peer))
+            .all(|peer| {
+                peer.status
+                    .agreement
+                    .is_some_and(|agreement| agreement.capabilities.contains(capability))
+            })
+    }
+
+    // Hashing takes a while for large files, so it happens off the server task. Its progress is shown until it's done.
+    fn prepare_job(&mut self, peers: Vec<usize>, paths: Vec<PathBuf>) -> Result<()> {
+        // Blocks are only worth hashing when duplicates can go out as references
+        let dedup = self.peers_take(&peers, Capabilities::DEDUP);
+        let id = self.next_hashing_id;
+        self.next_hashing_id += 1;
+        let progress = Arc::new(hashing::Progress::default());
+        self.hashing.insert(id, progress.clone());
+        self.display_hashing()?;
+
+        let notifications = self.notifications.0.clone();
+        task::spawn(async move {
+            let mut building =
+                task::spawn_blocking(move || job::Source::build(&paths, &progress, dedup));
+            let source = loop {
+                match async_std::future::timeout(HASHING_REFRESH, &mut building).await {
+                    Ok(source) => break source,
+                    Err(_) => {
+                        let _ = notifications.send(Notification::Hashing(id)).await;
+                    }
+                }
+            };
+            let _ = notifications
+                .send(Notification::SourceBuilt(id, peers, source))
+                .await;
+        });
+        Ok(())
+    }
+
+    // Neither way towards the UI makes us wait. The queue has no bound, so offers, chat and the like are never lost, while
+    // progress only keeps the latest update of each kind.
+    fn show(&self, data: ui::data::Server) -> Result<()> {
+        self.ui
+            .send(ui::Message::Data(data))
+            .map_err(|error| match error {
+                TrySendError::Full(_) => anyhow!("The user interface fell too far behind."),
+                TrySendError::Disconnected(_) => anyhow!("The user interface is gone."),
+            })
+    }
+
+    fn show_progress(&self, data: ui::data::Server) -> Result<()> {
+        self.progress
+            .send(data)
+            .map_err(|_| anyhow!("The user interface is gone."))
+    }
+
+    // The outcome of a download that has just ended isn't progress that a later update may replace, so it goes with the offers
+    fn display_downloads(&mut self, ended: Option<download::Status>) -> Result<()> {
+        let downloads = self
+            .downloads
+            .iter()
+            .filter_map(|(id, download)| {
+                let address = self.peers.get(id)?.status.address;
+                Some(download.status(*id, address, download::State::Receiving))
+            })
+            .collect();
+        self.show_progress(ui::data::Server::Downloads(downloads))?;
+        if let Some(ended) = ended {
+            self.show(ui::data::Server::DownloadEnded(ended))?;
+        }
+        self.downloads_shown = time::Instant::now();
+        Ok(())
+    }
+
+    fn display_hashing(&self) -> Result<()> {
+        let hashing = self
+            .hashing
+            .iter()
+            .map(|(id, progress)| progress.status(*id))
+            .collect();
+        self.show_progress(ui::data::Server::Hashing(hashing))?;
+        Ok(())
+    }
+
+    // Sync jobs come with the paths the recipient should remove. Returns the ID of the job.
+    fn start_job(
+        &mut self,
+        peers: Vec<usize>,
+        source: job::Source,
+        kind: job::Kind,
+    ) -> Result<usize> {
+        let id = self.next_job_id;
+        self.next_job_id += 1;
+
+        let recipients: Vec<(usize, SocketAddr)> = peers
+            .iter()
+            .filter_map(|peer| self.peers.get(peer))
+            .map(|peer| (peer.status.id, peer.status.address))
+            .collect();
+        // All recipients share one reader, so the chunks can only take a form every one of them takes
+        let statuses: Vec<&peer::PeerStatus> = peers
+            .iter()
+            .filter_map(|peer| self.peers.get(peer))
+            .map(|peer| &peer.status)
+            .collect();
+        // Receivers of two-way syncs may skip files, which then aren't there to copy blocks from
+        let two_way = matches!(kind, job::Kind::Folder(..));
+        let framing = job::Framing {
+            holes: self.peers_take(&peers, Capabilities::SPARSE),
+            zero_copy: self.settings.send.zero_copy
+                && statuses.iter().all(|status| status.takes_plain_data()),
+            dedup: self.peers_take(&peers, Capabilities::DEDUP) && !two_way,
+        };
+        let mut job = Job::new(
+            id,
+            source,
+            recipients,
+            self.settings.send.clone(),
+            self.notifications.0.clone(),
+        )
+        .with_kind(kind)
+        .with_framing(framing);
+
+        // Peers only keep one offer from us at a time. Answers couldn't be told apart otherwise.
+        let offer = job.offer();
+        for recipient in job.status().recipients {
+            let busy = self
+                .jobs
+                .values()
+                .any(|job| job.has_offered(recipient.peer));
+            let result = match self.peers.get(&recipient.peer) {
+                Some(_) if busy => Err(anyhow!("Still deciding on an earlier offer")),
+                Some(peer) => peer.send(offer.clone()),
+                None => Err(anyhow!("Disconnected")),
+            };
+            match result {
+                Ok(()) => self.record(
+                    Severity::Info,
+                    &format!("Peer {}", recipient.address),
+                    &format!("Offered {}", job.status().describe()),
+                )?,
+                Err(error) => {
+                    job.update(recipient.peer, job::Update::Failed(format!("{:#}", error)))
+                }
+            }
+        }
+
+        // Recipients that take too long to answer don't hold up the others
+        let notifications = self.notifications.0.clone();
+        let timeout = self.settings.send.answer_timeout;
+        task::spawn(async move {
+            task::sleep(timeout).await;
+            let _ = notifications.send(Notification::AnswerDeadline(id)).await;
+        });
+
+        self.jobs.insert(id, job);
+        self.refresh_jobs()?;
+        Ok(id)
+    }
+
+    // Answers go to the oldest job still waiting for one from this peer
+    fn receive_answer(&mut self, id: usize, accepted: bool) -> Result<()> {
+        let frames = match self.peers.get(&id) {
+            Some(peer) if accepted => Some(peer.frames()),
+            Some(_) => None,
+            None => return Ok(()),
+        };
+        if let Some(job) = self.jobs.values_mut().find(|job| job.has_offered(id)) {
+            job.answer(id, frames);
+        }
+        self.refresh_jobs()
+    }
+
+    fn update_job(&mut self, id: usize, peer: usize, update: job::Update) -> Result<()> {
+        let job = match self.jobs.get_mut(&id) {
+            Some(job) => job,
+            None => return Ok(()),
+        };
+        let status = job.status();
+        let address = status
+            .recipients
+            .iter()
+            .find(|recipient| recipient.peer == peer)
+            .map(|recipient| recipient.address);
+        let message = match &update {
+            job::Update::Sent(_) => None,
+            job::Update::Done => Some((Severity::Info, format!("Sent {}", status.describe()))),
+            job::Update::Failed(error) => Some((Severity::Error, error.clone())),
+        };
+        job.update(peer, update);
+
+        if let (Some(address), Some((severity, message))) = (address, message) {
+            self.record(severity, &format!("Peer {}", address), &message)?;
+        }
+        self.refresh_jobs()
+    }
+
+    // Finished jobs live on in the history only. Syncs get to see how their jobs ended before they go.
+    fn refresh_jobs(&mut self) -> Result<()> {
+        self.settle_syncs()?;
+        self.jobs.retain(|_, job| !job.is_finished());
+        let jobs = self.jobs.values().map(Job::status).collect();
+        self.show_progress(ui::data::Server::Jobs(jobs))?;
+        Ok(())
+    }
+
+    fn start_sync(&mut self, peers: Vec<usize>, paths: Vec<PathBuf>, two_way: bool) -> Result<()> {
+        // Each end keeps its own account of a folder shared both ways, so there are only ever two
+        if two_way && (peers.len() != 1 || paths.len() != 1) {
+            return self.record(
+                Severity::Warning,
+                "Sync",
+                "Syncing both ways takes one folder and one peer",
+            );
+        }
+        let required = match two_way {
+            true => Capabilities::SHARED,
+            false => Capabilities::SYNC,
+        };
+        for peer in peers {
+            let (address, agreement) = match self.peers.get(&peer) {
+                Some(peer) => (peer.status.address, peer.status.agreement),
+                None => continue,
+            };
+            let context = format!("Peer {}", address);
+            if !agreement.is_some_and(|agreement| agreement.capabilities.contains(required)) {
+                self.record(Severity::Warning, &context, "Can't keep files in sync")?;
+                continue;
+            }
+            if self.syncs.values().any(|session| session.peer == peer) {
+                self.record(Severity::Warning, &context, "Already keeping files in sync")?;
+                continue;
+            }
+
+            let id = self.next_sync_id;
+            self.next_sync_id += 1;
+            let session = match two_way {
+                true => shared::Folder::local(self.replica, &paths[0]).and_then(|folder| {
+                    let session = sync::Session::start(
+                        id,
+                        peer,
+                        address,
+                        vec![folder.path()],
+                        self.settings.sync.debounce,
+                        self.notifications.0.clone(),
+                    )?;
+                    Ok(session.sharing(folder))
+                }),
+                false => sync::Session::start(
+                    id,
+                    peer,
+                    address,
+                    paths.clone(),
+                    self.settings.sync.debounce,
+                    self.notifications.0.clone(),
+                ),
+            };
+            match session {
+                Ok(session) => {
+                    self.syncs.insert(id, session);
+                    let message = match two_way {
+                        true => "Keeping files in sync both ways",
+                        false => "Keeping files in sync",
+                    };
+                    self.record(Severity::Info, &context, message)?;
+                    self.scan_sync(id);
+                }
+                Err(error) => self.record(Severity::Error, &context, &format!("{:#}", error))?,
+            }
+        }
+        self.display_syncs()
+    }
+
+    // Scanning takes a while when there are new files to hash, so it happens off the server task
+    fn scan_sync(&mut self, id: usize) {
+        let session = match self.syncs.get_mut(&id) {
+            Some(session) => session,
+            None => return,
+        };
+        let mut scanner = match session.begin_scan() {
+            Some(scanner) => scanner,
+            None => return,
+        };
+        let paths = session.paths.clone();
+        let notifications = self.notifications.0.clone();
+        task::spawn(async move {
+            let (scanner, source) = task::spawn_blocking(move || {
+                let source = scanner.scan(&paths);
+                (scanner, source)
+            })
+            .await;
+            let _ = notifications
+                .send(Notification::SyncScanned(id, scanner, source))
+                .await;
+        });
+    }
+
+    fn sync_scanned(
+        &mut self,
+        id: usize,
+        scanner: sync::Scanner,
+        source: Result<job::Source>,
+    ) -> Result<()> {
+        let session = match self.syncs.get_mut(&id) {
+            Some(session) => session,
+            None => return Ok(()), // Stopped in the meantime
+        };
+        let peer = session.peer;
+        let context = format!("Peer {}", session.address);
+        match session.scanned(scanner, source, self.settings.sync.deletions) {
+            Ok(Some(round)) => {
+                let job = self.start_job(vec![peer], round.source, round.kind)?;
+                if let Some(session) = self.syncs.get_mut(&id) {
+                    session.sending(job, round.manifest);
+                }
+            }
+            Ok(None) => (),
+            Err(error) => self.record(Severity::Error, &context, &format!("{:#}", error))?,
+        }
+        self.settle_syncs()
+    }
+
+    // Follows up on how the latest round of each sync went, and scans again if changes came in meanwhile
+    fn settle_syncs(&mut self) -> Result<()> {
+        let mut declined = vec![];
+        let mut changed = vec![];
+        for (id, session) in self.syncs.iter_mut() {
+            if let Some(job) = session.job() {
+                match self.jobs.get(&job).and_then(|job| job.state(session.peer)) {
+                    Some(job::State::Offered) | Some(job::State::Sending) => continue,
+                    Some(job::State::Declined) => {
+                        declined.push(*id);
+                        continue;
+                    }
+                    Some(job::State::Done) => session.finished(true),
+                    Some(job::State::Failed(_)) | None => session.finished(false),
+                }
+            }
+            if session.is_dirty() {
+                changed.push(*id);
+            }
+        }
+
+        for id in declined {
+            if let Some(session) = self.syncs.remove(&id) {
+                let context = format!("Peer {}", session.address);
+                self.record(Severity::Warning, &context, "Stopped keeping files in sync")?;
+            }
+        }
+        for id in changed {
+            self.scan_sync(id);
+        }
+        self.display_syncs()
+    }
+
+    // Later rounds of a two-way sync are taken in right away. The first one is an offer like any other.
+    fn receive_folder(
+        &mut self,
+        id: usize,
+        manifest: Manifest,
+        versions: Vec<Version>,
+        removed: Vec<(String, Version)>,
+    ) -> Result<()> {
+        let sync = self
+            .syncs
+            .iter()
+            .find(|(_, session)| session.peer == id && session.is_shared())
+            .map(|(sync, _)| *sync);
+        match sync {
+            Some(sync) => self.take_folder(id, sync, manifest, &versions, &removed),
+            None => self.receive_offer(id, manifest, job::Kind::Folder(versions, removed)),
+        }
+    }
+
+    // Sets up our end of a folder the peer shares, in the download directory, and sends our changes from then on
+    fn accept_folder(
+        &mut self,
+        id: usize,
+        manifest: Manifest,
+        versions: Vec<Version>,
+        removed: Vec<(String, Version)>,
+    ) -> Result<()> {
+        let address = match self.peers.get(&id) {
+            Some(peer) => peer.status.address,
+            None => return Ok(()),
+        };
+        let context = format!("Peer {}", address);
+
+        let sync = self.next_sync_id;
+        self.next_sync_id += 1;
+        let session = shared::Folder::offered(
+            self.replica,
+            &manifest,
+            &removed,
+            &self.settings.download.directory,
+        )
+        .and_then(|folder| {
+            let session = sync::Session::start(
+                sync,
+                id,
+                address,
+                vec![folder.path()],
+                self.settings.sync.debounce,
+                self.notifications.0.clone(),
+            )?;
+            Ok(session.sharing(folder))
+        });
+        match session {
+            Ok(session) => {
+                self.syncs.insert(sync, session);
+                self.record(Severity::Info, &context, "Keeping files in sync both ways")?;
+            }
             Err(error) => {
-                self.status = ServerStatus::LocalIpError(anyhow!(error));
-                return;
+                self.record(Severity::Error, &context, &format!("{:#}", error))?;
+                if let Some(peer) = self.peers.get(&id) {
+                    peer.send(protocol::Message::OfferAnswer { accepted: false })?;
+                }
+                return Ok(());
             }
+        }
+        self.take_folder(id, sync, manifest, &versions, &removed)?;
+        self.scan_sync(sync);
+        self.display_syncs()
+    }
+
+    // Takes in a round of a two-way sync. Files we have the same or a newer version of are skipped as they come in.
+    fn take_folder(
+        &mut self,
+        id: usize,
+        sync: usize,
+        manifest: Manifest,
+        versions: &[Version],
+        removed: &[(String, Version)],
+    ) -> Result<()> {
+        let address = match self.peers.get(&id) {
+            Some(peer) => peer.status.address,
+            None => return Ok(()),
+        };
+        let context = format!("Peer {}", address);
+        let session = match self.syncs.get_mut(&sync) {
+            Some(session) => session,
+            None => return Ok(()),
         };
+        let base = session
+            .folder()
+            .map(|folder| folder.base.clone())
+            .unwrap_or_default();
 
-        match get_public_ip() {
-            Ok(ip) => self.public_ip = Some(ip),
+        let mut accept = true;
+        match session.receive(&manifest, versions, removed, address) {
+            Ok(received) => {
+                if !received.removed.is_empty() {
+                    self.record(
+                        Severity::Info,
+                        &context,
+                        &format!("Removed {} files the peer removed", received.removed.len()),
+                    )?;
+                }
+                for conflict in received.conflicts {
+                    let copy = conflict.copy.file_name().unwrap_or_default();
+                    let message = format!(
+                        "Conflicting edits to \"{}\". Ours are in \"{}\" now",
+                        conflict.path,
+                        copy.to_string_lossy()
+                    );
+                    self.record(Severity::Warning, &context, &message)?;
+                    self.conflicts.insert(self.next_conflict_id, conflict);
+                    self.next_conflict_id += 1;
+                }
+                // Files we changed while the peer removed them go back
+                if !received.kept.is_empty() {
+                    self.scan_sync(sync);
+                }
+
+                // Skipped files still come in, as the peer doesn't know which ones we have
+                if !manifest.is_empty() {
+                    match download::Download::replacing(&base, manifest) {
+                        Ok(mut download) => {
+                            for index in received.skipped {
+                                download.skip(index);
+                            }
+                            self.downloads.insert(id, download);
+                            self.display_downloads(None)?;
+                        }
+                        Err(error) => {
+                            self.record(Severity::Error, &context, &format!("{:#}", error))?;
+                            accept = false;
+                        }
+                    }
+                }
+            }
             Err(error) => {
-                self.status = ServerStatus::PublicIpError(anyhow!(error));
-                return;
+                self.record(Severity::Error, &context, &format!("{:#}", error))?;
+                accept = false;
             }
+        }
+
+        let result = match self.peers.get(&id) {
+            Some(peer) => peer.send(protocol::Message::OfferAnswer { accepted: accept }),
+            None => return Ok(()),
+        };
+        if let Err(error) = result {
+            self.downloads.remove(&id);
+            self.display_downloads(None)?;
+            self.record(Severity::Warning, &context, &format!("{:#}", error))?;
+        }
+        self.display_conflicts()
+    }
+
+    // Either way, the sync passes the outcome on to the peer
+    fn resolve_conflict(&mut self, id: usize, resolution: shared::Resolution) -> Result<()> {
+        let conflict = match self.conflicts.remove(&id) {
+            Some(conflict) => conflict,
+            None => return Ok(()),
+        };
+        let context = format!("Peer {}", conflict.peer);
+        match shared::resolve(&conflict, resolution) {
+            Ok(()) => {
+                let message = match resolution {
+                    shared::Resolution::Ours => "Kept our version of",
+                    shared::Resolution::Theirs => "Kept the peer's version of",
+                };
+                self.record(
+                    Severity::Info,
+                    &context,
+                    &format!("{} \"{}\"", message, conflict.path),
+                )?;
+            }
+            Err(error) => self.record(Severity::Error, &context, &format!("{:#}", error))?,
+        }
+        self.display_conflicts()
+    }
+
+    fn display_conflicts(&self) -> Result<()> {
+        let conflicts = self
+            .conflicts
+            .iter()
+            .map(|(id, conflict)| (*id, conflict.clone()))
+            .collect();
+        self.show(ui::data::Server::Conflicts(conflicts))?;
+        Ok(())
+    }
+
+    fn display_syncs(&self) -> Result<()> {
+        let syncs = self.syncs.values().map(sync::Session::status).collect();
+        self.show_progress(ui::data::Server::Syncs(syncs))?;
+        Ok(())
+    }
+
+    fn send_chat(&mut self, id: usize, text: &str) -> Result<()> {
+        let result = match self.peers.get(&id) {
+            Some(peer) => peer.send_text(chat::Kind::Chat, text),
+            None => return Ok(()), // Gone in the meantime
+        };
+        match result {
+            Ok(()) => self.store_chat(id, chat::Line::new(chat::Author::Us, text)),
+            Err(error) => self.record(Severity::Warning, "Chat", &format!("{:#}", error)),
+        }
+    }
+
+    fn send_snippet(&mut self, id: usize, text: &str) -> Result<()> {
+        let (address, result) = match self.peers.get(&id) {
+            Some(peer) => (
+                peer.status.address,
+                peer.send_text(chat::Kind::Snippet, text),
+            ),
+            None => return Ok(()),
+        };
+        let context = format!("Peer {}", address);
+        match result {
+            Ok(()) => self.record(
+                Severity::Info,
+                &context,
+                &format!("Sent text ({} characters)", text.chars().count()),
+            ),
+            Err(error) => self.record(Severity::Warning, &context, &format!("{:#}", error)),
+        }
+    }
+
+    // The text itself stays out of the history, as it may well be a password or the like
+    fn receive_snippet(&mut self, id: usize, text: String) -> Result<()> {
+        let address = match self.peers.get(&id) {
+            Some(peer) => peer.status.address,
+            None => return Ok(()),
+        };
+        self.record(
+            Severity::Info,
+            &format!("Peer {}", address),
+            &format!("Received text ({} characters)", text.chars().count()),
+        )?;
+
+        let snippet = Snippet {
+            peer: id,
+            address,
+            received: time::SystemTime::now(),
+            text,
+        };
+        self.show(ui::data::Server::Snippet(snippet))?;
+        Ok(())
+    }
+
+    fn save_snippet(&mut self, snippet: &Snippet) -> Result<()> {
+        let seconds = snippet
+            .received
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = format!("text-{}", seconds);
+        match download::save_text(&self.settings.download.directory, &name, &snippet.text) {
+            Ok(path) => self.record(
+                Severity::Info,
+                "Text",
+                &format!("Saved to \"{}\"", path.display()),
+            ),
+            Err(error) => self.record(Severity::Error, "Text", &format!("{:#}", error)),
+        }
+    }
+
+    // Adds a chat message to the history of the given peer and shows it
+    fn store_chat(&mut self, id: usize, line: chat::Line) -> Result<()> {
+        let address = match self.peers.get(&id) {
+            Some(peer) => peer.status.address,
+            None => return Ok(()),
         };
+        if let Err(error) = chat::append_history(&self.settings.chat.directory, address.ip(), &line)
+        {
+            self.record(Severity::Warning, "Chat", &format!("{:#}", error))?;
+        }
+        self.show(ui::data::Server::Chat(id, vec![line]))?;
+        Ok(())
+    }
+
+    // Picks up the conversation where it was left off the last time we talked to that address
+    fn display_chat_history(&mut self, id: usize, address: SocketAddr) -> Result<()> {
+        match chat::load_history(
+            &self.settings.chat.directory,
+            address.ip(),
+            self.settings.chat.backlog,
+        ) {
+            Ok(lines) if lines.is_empty() => Ok(()),
+            Ok(lines) => {
+                self.show(ui::data::Server::Chat(id, lines))?;
+                Ok(())
+            }
+            Err(error) => self.record(Severity::Warning, "Chat", &format!("{:#}", error)),
+        }
+    }
+
+    // Removes what is left of old, interrupted downloads
+    fn clean_up_downloads(&mut self) -> Result<()> {
+        let directory = self.settings.download.directory.clone();
+        match download::clean_up(&directory, self.settings.download.partial_max_age) {
+            Ok(removed) if !removed.is_empty() => self.record(
+                Severity::Info,
+                "Downloads",
+                &format!("Removed {} stale partial files", removed.len()),
+            ),
+            Ok(_) => Ok(()),
+            Err(error) => self.record(Severity::Warning, "Downloads", &format!("{:#}", error)),
+        }
+    }
+
+    // Writes the history to a file in the working directory, so it can be attached to bug reports
+    fn export_history(&mut self) -> Result<()> {
+        let timestamp = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = std::path::PathBuf::from(format!("bitgeon-history-{}.txt", timestamp));
+
+        match self.history.export(&path) {
+            Ok(()) => self.record(
+                Severity::Info,
+                "History export",
+                &format!("Exported to \"{}\"", path.display()),
+            ),
+            Err(error) => self.record(Severity::Error, "History export", &format!("{:#}", error)),
+        }
+    }
+
+    // Keep the UI informed about the health of our connections
+    fn display_peers(&self) -> Result<()> {
+        let status = self
+            .peers
+            .values()
+            .map(|peer| peer.status.clone())
+            .collect();
+        self.show_progress(ui::data::Server::PeerStatus(status))?;
+        Ok(())
+    }
+
+    // Binds the listeners right away. Our addresses come in later, see `found_addresses`.
+    // Problems end up in the status. Errors are only returned if the UI is gone.
+    pub fn refresh_connection(&mut self, context: &'static str) -> Result<()> {
+        self.status = ServerStatus::Ok;
+        self.local_ip = None;
+        self.public_ip = None;
+        self.gateway_ip = None;
+        self.mapping = None;
+        self.internal_port = None;
+        self.external_port = None;
+        self.self_test = None;
+        self.renewing = false;
+        self.refresh += 1;
+
+        // Bind listeners to every available interface. Let OS provide an available port for the first transport.
+        // The other transports listen on the same port number, so peers can reach all of them at the same address.
+        // TODO: Perhaps let the user specify a port on their own
+        self.listeners.clear();
+        let mut address = SocketAddr::from(([0, 0, 0, 0], 0));
+        for kind in &self.settings.transports {
+            match Listener::bind(*kind, address).and_then(|listener| {
+                address = listener.local_addr()?;
+                Ok(listener)
+            }) {
+                Ok(listener) => self.listeners.push(Arc::new(listener)),
+                Err(error) => {
+                    self.status = match kind {
+                        TransportKind::Tcp => ServerStatus::TcpBindError(error),
+                        TransportKind::Quic => ServerStatus::QuicBindError(error),
+                    };
+                    return self.record_status(context);
+                }
+            }
+        }
 
-        // This unwrap will only be reached if the listener is ok above
-        match self.listener.as_ref().unwrap().local_addr() {
-            Ok(address) => self.internal_port = Some(address.port()),
+        let internal_port = match self
+            .listeners
+            .first()
+            .with_context(|| String::from("No transport configured."))
+            .and_then(|listener| listener.local_addr())
+        {
+            Ok(address) => address.port(),
             Err(error) => {
                 self.status = ServerStatus::InternalPortError(anyhow!(error));
-                return;
+                return self.record_status(context);
             }
         };
+        self.internal_port = Some(internal_port);
+        self.status = ServerStatus::Refreshing;
 
-        match self.add_port_mapping() {
-            Ok(port) => self.external_port = Some(port),
+        let refresh = self.refresh;
+        let stun_servers = self.settings.stun_servers.clone();
+        let lease = self.upnp_lease_duration;
+        let quic = self.settings.transports.contains(&TransportKind::Quic);
+        let notifications = self.notifications.0.clone();
+        task::spawn(async move {
+            let addresses = task::spawn_blocking(move || {
+                find_addresses(&stun_servers, internal_port, lease, quic)
+            })
+            .await;
+            let _ = notifications
+                .send(Notification::Addresses(refresh, context, addresses))
+                .await;
+        });
+        Ok(())
+    }
+
+    fn found_addresses(&mut self, addresses: Addresses) -> Result<()> {
+        self.status = ServerStatus::Ok;
+        let discovery = match addresses.discovery {
+            Ok(discovery) => {
+                self.record(Severity::Info, "STUN", &discovery.to_string())?;
+                self.mapping = discovery.mapping;
+                Some(discovery)
+            }
             Err(error) => {
-                self.status = ServerStatus::ExternalPortError(anyhow!(error));
-                return;
+                self.record(Severity::Warning, "STUN", &format!("{:#}", error))?;
+                None
+            }
+        };
+
+        match addresses.local_ip {
+            Ok(ip) => self.local_ip = Some(ip),
+            Err(error) => {
+                self.status = ServerStatus::LocalIpError(error);
+                return Ok(());
+            }
+        };
+
+        match (addresses.gateway_ip, &discovery) {
+            (Ok(ip), _) => self.gateway_ip = Some(ip),
+            (Err(_), Some(_)) => (),
+            (Err(error), None) => {
+                self.status = ServerStatus::PublicIpError(error);
+                return Ok(());
+            }
+        };
+        self.public_ip = discovery
+            .as_ref()
+            .map(|discovery| discovery.public.ip())
+            .or(self.gateway_ip);
+
+        if let (Some(gateway_ip), Some(public_ip)) = (self.gateway_ip, self.public_ip) {
+            if gateway_ip != public_ip {
+                self.record(
+                    Severity::Warning,
+                    "STUN",
+                    &format!(
+                        "Gateway reports {} as its external IP, but STUN sees {}. There is another NAT in front of the gateway.",
+                        gateway_ip, public_ip
+                    ),
+                )?;
+            }
+        }
+
+        match addresses.external_port {
+            Ok(port) => {
+                self.external_port = Some(port);
+                self.upnp_lease_clock = time::Instant::now();
             }
+            Err(error) => self.status = ServerStatus::ExternalPortError(error),
         }
+        Ok(())
     }
 
-    pub fn accept_connection(&mut self) -> Result<Option<Peer>> {
-        // // Should we rely on the existent data (local_ip, public_ip), or should we update it?
-        // // I guess updating doesn't hurt and might be more reliable, in case stuff has changed.
+    // Like the rest of the gateway's business, this takes a few round trips, so the result comes back as a notification
+    fn renew_port_mapping(&mut self) {
+        let (local_ip, internal_port) = match (self.local_ip, self.internal_port) {
+            (Some(local_ip), Some(internal_port)) => (local_ip, internal_port),
+            _ => return,
+        };
+        self.renewing = true;
+        let refresh = self.refresh;
+        let lease = self.upnp_lease_duration;
+        let quic = self.settings.transports.contains(&TransportKind::Quic);
+        let notifications = self.notifications.0.clone();
+        task::spawn(async move {
+            let result =
+                task::spawn_blocking(move || map_port(local_ip, internal_port, lease, quic)).await;
+            let _ = notifications
+                .send(Notification::PortMapped(refresh, result))
+                .await;
+        });
+    }
 
-        // // 1. Update data
-        // // TODO: If we can't get a local or public IP, we shouldn't crash, but the user should be notified.
-        // self.refresh_ips()
-        //     .with_context(|| format!("Unable to listen for connection."))?;
-        // if self.upnp_lease_clock.elapsed() >= self.upnp_lease_duration {
-        //     self.add_port_mapping()
-        //         .with_context(|| format!("Unable to listen for connection."))?;
-        // }
+    fn renewed_port_mapping(&mut self, result: Result<u16>) -> Result<()> {
+        self.renewing = false;
+        match result {
+            Ok(port) => {
+                self.external_port = Some(port);
+                self.upnp_lease_clock = time::Instant::now();
+                self.record(Severity::Info, "Port mapping", "Lease renewed")?;
+            }
+            Err(error) => {
+                self.external_port = None;
+                self.status = ServerStatus::ExternalPortError(error);
+                self.record_status("Port mapping renewal")?;
+            }
+        }
+        self.display_connection()
+    }
 
-        // // self.local_ip = get_local_ip().ok();
-        // // self.public_ip = get_public_ip().ok();
+    // Connects to the peer behind a link. Their key has to be ours, or the connection would be refused anyway.
+    fn open_link(&mut self, link: Link) -> Result<()> {
+        let context = format!("Link to {}", link.addresses[0]);
+        let fingerprint = link::fingerprint(&self.secret_key);
+        if link.fingerprint != fingerprint {
+            return self.record(
+                Severity::Warning,
+                &context,
+                &format!(
+                    "Key fingerprint {} doesn't match ours ({}). Check the key with the sender",
+                    link.fingerprint, fingerprint
+                ),
+            );
+        }
+        self.record(Severity::Info, &context, "Connecting")?;
 
-        // // 2. Forward port via UPnP to allow for incoming connection
-        todo!();
+        // Addresses are tried in order, as the link lists the one most likely to work first
+        let addresses = link.addresses.clone();
+        let transports = self.settings.transports.clone();
+        let timeout = self.settings.heartbeat.connect_timeout;
+        let notifications = self.notifications.0.clone();
+        task::spawn(async move {
+            let mut errors = vec![];
+            for address in addresses {
+                match Connection::negotiate(&transports, address, timeout).await {
+                    Ok(connection) => {
+                        let notification = Notification::Connected(connection, Direction::Outgoing);
+                        let _ = notifications.send(notification).await;
+                        return;
+                    }
+                    Err(error) => errors.push(format!("{:#}", error)),
+                }
+            }
+            let error = anyhow!("Unable to follow link. {}", errors.join(" "));
+            let _ = notifications
+                .send(Notification::ConnectionFailed(error))
+                .await;
+        });
+
+        if link.root.is_some() {
+            self.links.push(link);
+        }
+        Ok(())
     }
 
-    pub fn establish_connection() -> Result<Option<Peer>> {
-        // Open outgoing connection to peer
-        todo!();
+    pub fn establish_connection(&self, address: SocketAddr) {
+        // Open outgoing connection to peer in the background, using the first transport that works
+        let transports = self.settings.transports.clone();
+        let timeout = self.settings.heartbeat.connect_timeout;
+        let notifications = self.notifications.0.clone();
+        task::spawn(async move {
+            let notification = match Connection::negotiate(&transports, address, timeout).await {
+                Ok(connection) => Notification::Connected(connection, Direction::Outgoing),
+                Err(error) => Notification::ConnectionFailed(error),
+            };
+            let _ = notifications.send(notification).await;
+        });
     }
 
     // fn get_free_port() -> Result<u16> {
@@ -224,69 +1960,132 @@ impl Server {
     //     Ok(local_address.port())
     // }
 
-    pub fn add_port_mapping(&mut self) -> Result<u16> {
-        // Add port mapping to gateway device via UPnP
-        // let local_ip = self.local_ip.ok_or_else( || "No local IP address.")?;
-        let internal_port = self
-            .internal_port
-            .with_context(|| String::from("No internal port."))?;
-        let local_ip = self
-            .local_ip
-            .with_context(|| String::from("No local IP address."))?;
-        let local_ip = match local_ip {
-            IpAddr::V4(ipv4) => Ok(ipv4),
-            // IpAddr::V6(ipv6) => Err("Local IP is IPv6, but only IPv4 is supported.".into()),
-            IpAddr::V6(_) => Err(anyhow!(String::from(
-                "Local IP is IPv6, but only IPv4 is supported."
-            ))),
-        }?;
-        let local_address = SocketAddrV4::new(local_ip, internal_port);
-        let gateway = igd::search_gateway(Default::default())
-            .with_context(|| String::from("Unable to find gateway device. Verify connection."))?;
-        let external_port = gateway
-            .add_any_port(
-                igd::PortMappingProtocol::TCP,
-                local_address,
-                self.upnp_lease_duration
-                    .as_secs()
-                    .try_into()
-                    .with_context(|| String::from("UPnP lease duration should fit into u32"))?,
-                "Bitgeon",
-            )
-            .with_context(|| String::from("Unable to add port mapping via UPnP."))?;
-        self.upnp_lease_clock = time::Instant::now();
-        self.external_port = Some(external_port);
-        Ok(external_port)
-    }
-
-    pub fn refresh_ips(&mut self) -> Result<()> {
-        // Refresh connection info. Call this in case of errors trying to establish a connection, but also let the user call this.
-        let local_ip =
-            get_local_ip().with_context(|| String::from("Unable to get local IP address."))?;
-        self.local_ip = Some(local_ip);
-        let public_ip =
-            get_public_ip().with_context(|| String::from("Unable to get public IP address."))?;
-        self.public_ip = Some(public_ip);
-        Ok(())
+    // Point in time at which the port mapping should be renewed, if there is one
+    fn lease_renewal(&self) -> Option<time::Instant> {
+        if self.renewing {
+            return None;
+        }
+        self.external_port?;
+        Some(self.upnp_lease_clock + self.upnp_lease_duration.mul_f32(0.9))
+    }
+
+    pub fn reachability(&self) -> Reachability {
+        match (
+            self.public_ip,
+            self.external_port,
+            self.local_ip,
+            self.internal_port,
+        ) {
+            (Some(ip), Some(port), _, _) => Reachability::Internet(SocketAddr::new(ip, port)),
+            (_, _, Some(ip), Some(port)) => Reachability::LocalNetwork(SocketAddr::new(ip, port)),
+            _ => Reachability::Unreachable,
+        }
+    }
+
+    // Lists the address from the internet first, as it works from anywhere. The local one is faster, if both ends share a network.
+    fn link(&self) -> Option<Link> {
+        let mut addresses = vec![];
+        if let Reachability::Internet(address) = self.reachability() {
+            addresses.push(address);
+        }
+        if let (Some(ip), Some(port)) = (self.local_ip, self.internal_port) {
+            addresses.push(SocketAddr::new(ip, port));
+        }
+        if addresses.is_empty() {
+            return None;
+        }
+        Some(Link {
+            addresses,
+            fingerprint: link::fingerprint(&self.secret_key),
+            root: None,
+        })
     }
 
     pub fn display_connection(&self) -> Result<()> {
-        todo!();
-        let connection_info = ui::data::Server::ConnectionInfo{
-            public_ip: self.public_ip,
-            external_port: self.external_port,
-            status: format!("{}", self.status),
-            secret_key: self.secret_key
+        let connection_info = ConnectionInfo {
+            reachability: self.reachability(),
+            status: self.status.report(),
+            lease_expiry: self
+                .external_port
+                .map(|_| self.upnp_lease_clock + self.upnp_lease_duration),
+            self_test: self.self_test.clone(),
+            secret_key: self.secret_key.clone(),
+            link: self.link(),
         };
 
-        self.ui.send(ui::Message::Data(connection_info))?;
+        self.show(ui::data::Server::ConnectionInfo(connection_info))?;
         Ok(())
     }
 }
 
-#[derive(Debug)]
-pub struct Peer {
-    tcp_stream: TcpStream,
+// Blocks for as long as the gateway and the STUN servers take to answer
+fn find_addresses(
+    stun_servers: &[String],
+    internal_port: u16,
+    lease: time::Duration,
+    quic: bool,
+) -> Addresses {
+    // STUN sees past every NAT on the way and works without UPnP, so it serves as a fallback and a cross-check for the gateway
+    let discovery = stun::discover(stun_servers);
+    // Without a gateway to ask, the interface we reached the STUN server on will do
+    let local_ip = get_local_ip().or_else(|error| match &discovery {
+        Ok(discovery) => Ok(discovery.local.ip()),
+        Err(_) => Err(error),
+    });
+    let gateway_ip = get_public_ip();
+    let external_port = match &local_ip {
+        Ok(local_ip) => map_port(*local_ip, internal_port, lease, quic),
+        Err(_) => Err(anyhow!("No local IP address.")),
+    };
+    Addresses {
+        discovery,
+        local_ip,
+        gateway_ip,
+        external_port,
+    }
+}
+
+// Adds a port mapping to the gateway device via UPnP. Blocks until the gateway has answered.
+fn map_port(
+    local_ip: IpAddr,
+    internal_port: u16,
+    lease: time::Duration,
+    quic: bool,
+) -> Result<u16> {
+    let local_ip = match local_ip {
+        IpAddr::V4(ipv4) => Ok(ipv4),
+        IpAddr::V6(_) => Err(anyhow!(String::from(
+            "Local IP is IPv6, but only IPv4 is supported."
+        ))),
+    }?;
+    let local_address = SocketAddrV4::new(local_ip, internal_port);
+    let gateway = igd::search_gateway(Default::default())
+        .with_context(|| String::from("Unable to find gateway device. Verify connection."))?;
+    let lease = lease
+        .as_secs()
+        .try_into()
+        .with_context(|| String::from("UPnP lease duration should fit into u32"))?;
+    let external_port = gateway
+        .add_any_port(
+            igd::PortMappingProtocol::TCP,
+            local_address,
+            lease,
+            "Bitgeon",
+        )
+        .with_context(|| String::from("Unable to add port mapping via UPnP."))?;
+
+    // QUIC listens on the same port number, but needs its own mapping for UDP.
+    // If that fails, QUIC is still available on the local network, and remote peers fall back to TCP.
+    if quic {
+        let _ = gateway.add_port(
+            igd::PortMappingProtocol::UDP,
+            external_port,
+            local_address,
+            lease,
+            "Bitgeon",
+        );
+    }
+    Ok(external_port)
 }
 
 pub fn get_public_ip() -> Result<IpAddr> {
@@ -318,3 +2117,17 @@ pub fn get_local_ip() -> Result<IpAddr> {
     let local_address = socket.local_addr()?;
     Ok(local_address.ip())
 }
+
+// Passes messages from a blocking channel on, until the receiving end gives up
+fn forward<T: Send + 'static>(
+    receiver: crossbeam_channel::Receiver<T>,
+    forward: impl Fn(T) -> bool + Send + 'static,
+) {
+    thread::spawn(move || {
+        while let Ok(message) = receiver.recv() {
+            if !forward(message) {
+                break;
+            }
+        }
+    });
+}
@@ -1,3 +1,11 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time;
+
+use crate::sync::Deletions;
+use crate::transport::TransportKind;
+use crate::util::FullQueue;
+
 pub struct LogicSettings {
     pub interface_refresh_rate: u128, // Update rate on user interaction in Hz
     pub progress_refresh_rate: u128,  // Update rate for progress bars at idle in Hz
@@ -25,18 +33,273 @@ impl Default for LogicSettings {
 }
 
 pub struct ServerSettings {
-    pub logic_refresh_rate: u128,
+    pub heartbeat: HeartbeatSettings,
+    pub transports: Vec<TransportKind>, // Transports to listen on, in order of preference for outgoing connections
+    pub history_capacity: usize,        // Number of status and error events to remember
+    pub probe_server: Option<SocketAddr>, // Where to run the reachability self-test. See `bitgeon --probe-server`
+    pub stun_servers: Vec<String>, // Asked for our public address. Two are needed to tell how the NAT maps ports
+    pub download: DownloadSettings,
+    pub notification_capacity: usize, // Events from peers, the application and the UI waiting for the server
+    pub inbox_capacity: usize, // Messages waiting for each peer's task, from us and from its connection
+    pub chat: ChatSettings,
+    pub send: SendSettings,
+    pub sync: SyncSettings,
 }
 
 impl ServerSettings {
-    fn new(logic_refresh_rate: u128) -> Self {
-        Self { logic_refresh_rate }
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        heartbeat: HeartbeatSettings,
+        transports: Vec<TransportKind>,
+        history_capacity: usize,
+        probe_server: Option<SocketAddr>,
+        stun_servers: Vec<String>,
+        download: DownloadSettings,
+        notification_capacity: usize,
+        inbox_capacity: usize,
+        chat: ChatSettings,
+        send: SendSettings,
+        sync: SyncSettings,
+    ) -> Self {
+        Self {
+            heartbeat,
+            transports,
+            history_capacity,
+            probe_server,
+            stun_servers,
+            download,
+            notification_capacity,
+            inbox_capacity,
+            chat,
+            send,
+            sync,
+        }
     }
 }
 
 impl Default for ServerSettings {
     fn default() -> Self {
-        Self::new(60)
+        Self::new(
+            HeartbeatSettings::default(),
+            vec![TransportKind::Quic, TransportKind::Tcp],
+            500,
+            None, // There is no public probe server yet
+            vec![
+                String::from("stun.l.google.com:19302"),
+                String::from("stun.cloudflare.com:3478"),
+            ],
+            DownloadSettings::default(),
+            1024,
+            256,
+            ChatSettings::default(),
+            SendSettings::default(),
+            SyncSettings::default(),
+        )
+    }
+}
+
+pub struct DownloadSettings {
+    pub directory: PathBuf,
+    pub partial_max_age: time::Duration, // Partial files of unfinished downloads older than this are removed on startup
+    pub free_space_margin: u64, // Bytes that should stay free on the download drive after accepting an offer
+}
+
+impl DownloadSettings {
+    fn new(directory: PathBuf, partial_max_age: time::Duration, free_space_margin: u64) -> Self {
+        Self {
+            directory,
+            partial_max_age,
+            free_space_margin,
+        }
+    }
+}
+
+impl Default for DownloadSettings {
+    fn default() -> Self {
+        Self::new(
+            PathBuf::from("bitgeon-downloads"),
+            time::Duration::from_secs(60 * 60 * 24 * 7),
+            1024 * 1024 * 1024,
+        )
+    }
+}
+
+pub struct ChatSettings {
+    pub directory: PathBuf, // Holds the chat history, one file per peer
+    pub backlog: usize,     // Number of earlier messages shown when a peer connects again
+}
+
+impl ChatSettings {
+    fn new(directory: PathBuf, backlog: usize) -> Self {
+        Self { directory, backlog }
+    }
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self::new(PathBuf::from("bitgeon-chat"), 200)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SendSettings {
+    pub chunk_size: usize,
+    pub answer_timeout: time::Duration, // Recipients that take longer to accept an offer don't share the disk reads with the others
+    pub lag: usize, // Chunks a recipient may fall behind the fastest one before it is left to read from disk on its own
+    pub patience: time::Duration, // How long the others wait for a recipient that has fallen behind that far
+    pub zero_copy: bool, // Sending file data straight from the page cache where the connection allows it. See zerocopy.rs
+}
+
+impl SendSettings {
+    fn new(
+        chunk_size: usize,
+        answer_timeout: time::Duration,
+        lag: usize,
+        patience: time::Duration,
+        zero_copy: bool,
+    ) -> Self {
+        Self {
+            chunk_size,
+            answer_timeout,
+            lag,
+            patience,
+            zero_copy,
+        }
+    }
+}
+
+impl Default for SendSettings {
+    fn default() -> Self {
+        Self::new(
+            256 * 1024,
+            time::Duration::from_secs(60),
+            64,
+            time::Duration::from_millis(200),
+            true,
+        )
+    }
+}
+
+pub struct SyncSettings {
+    pub debounce: time::Duration, // Changes are sent once there haven't been any for this long
+    pub deletions: Deletions,     // What the peer does with files we delete
+}
+
+impl SyncSettings {
+    fn new(debounce: time::Duration, deletions: Deletions) -> Self {
+        Self {
+            debounce,
+            deletions,
+        }
+    }
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        Self::new(time::Duration::from_millis(500), Deletions::Mirror)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueueSettings {
+    pub capacity: Option<usize>, // Unbounded if None
+    pub policy: FullQueue,
+}
+
+impl QueueSettings {
+    pub fn new(capacity: Option<usize>, policy: FullQueue) -> Self {
+        Self { capacity, policy }
+    }
+}
+
+// Queues between the threads, named after the thread that reads from them.
+// A slow reader makes the writer wait rather than letting the queue eat up memory. Except for the server, which must not
+// hold up its executor: its queue towards the UI is unbounded, as nothing on it may get lost. Progress, the bulk of what
+// it shows, goes around the queue and only keeps the latest update of each kind.
+pub struct ChannelSettings {
+    pub to_backend: QueueSettings,
+    pub to_ui: QueueSettings,
+    pub to_server: QueueSettings,
+    pub server_to_ui: QueueSettings, // Offers, chat, history and the like
+}
+
+impl ChannelSettings {
+    fn new(
+        to_backend: QueueSettings,
+        to_ui: QueueSettings,
+        to_server: QueueSettings,
+        server_to_ui: QueueSettings,
+    ) -> Self {
+        Self {
+            to_backend,
+            to_ui,
+            to_server,
+            server_to_ui,
+        }
+    }
+}
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self::new(
+            QueueSettings::new(Some(256), FullQueue::Block),
+            QueueSettings::new(Some(1024), FullQueue::Block),
+            QueueSettings::new(Some(1024), FullQueue::Block),
+            QueueSettings::new(None, FullQueue::Error),
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct HeartbeatSettings {
+    pub ping_interval: time::Duration, // Time between pings sent to an idle peer
+    pub idle_timeout: time::Duration,  // Peers that stay silent for this long are considered dead
+    pub reconnect_attempts: u32,       // Attempts at reaching a dead peer again before giving up
+    pub reconnect_backoff: time::Duration, // Delay before the first reconnect attempt. Doubles on every further attempt
+    pub max_reconnect_backoff: time::Duration,
+    pub connect_timeout: time::Duration, // Time a transport gets to set up an outgoing connection before the next is tried
+}
+
+impl HeartbeatSettings {
+    fn new(
+        ping_interval: time::Duration,
+        idle_timeout: time::Duration,
+        reconnect_attempts: u32,
+        reconnect_backoff: time::Duration,
+        max_reconnect_backoff: time::Duration,
+        connect_timeout: time::Duration,
+    ) -> Self {
+        Self {
+            ping_interval,
+            idle_timeout,
+            reconnect_attempts,
+            reconnect_backoff,
+            max_reconnect_backoff,
+            connect_timeout,
+        }
+    }
+
+    // Delay before the given reconnect attempt, counting from 0
+    pub fn reconnect_delay(&self, attempt: u32) -> time::Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.reconnect_backoff
+            .checked_mul(factor)
+            .map_or(self.max_reconnect_backoff, |delay| {
+                delay.min(self.max_reconnect_backoff)
+            })
+    }
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        Self::new(
+            time::Duration::from_secs(5),
+            time::Duration::from_secs(20),
+            5,
+            time::Duration::from_secs(1),
+            time::Duration::from_secs(30),
+            time::Duration::from_secs(10),
+        )
     }
 }
 
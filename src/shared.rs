@@ -0,0 +1,480 @@
+// Two-way sync of a folder between two peers. Both ends run a sync (see sync.rs) that sends their changes to the other,
+// and every file and removal comes with its version vector (see version.rs). A change that builds on what we have replaces
+// our file. Changes made on both ends without knowing of each other are conflicts: both ends pick the same winner, and
+// the end whose version lost moves it to a conflict copy next to the file, named after the peer and the time.
+// The copy is an ordinary file from then on, so it reaches the other end with the next round.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time;
+
+use anyhow::{anyhow, Context, Result};
+use path_absolutize::Absolutize;
+
+use crate::download;
+use crate::hashing;
+use crate::manifest::Manifest;
+use crate::sandbox::Sandbox;
+use crate::util;
+use crate::version::{Order, Version};
+
+// A file both ends changed. The file holds the peer's version now, and the copy ours.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub path: String, // In the folder, like in manifests
+    pub file: PathBuf,
+    pub copy: PathBuf,
+    pub peer: SocketAddr,
+    pub time: time::SystemTime,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resolution {
+    Ours,   // Our version goes back in place. That's a new edit, which the peer takes over.
+    Theirs, // The copy goes, and its removal reaches the peer like any other
+}
+
+pub fn resolve(conflict: &Conflict, resolution: Resolution) -> Result<()> {
+    match resolution {
+        Resolution::Ours => fs::rename(&conflict.copy, &conflict.file),
+        Resolution::Theirs => fs::remove_file(&conflict.copy),
+    }
+    .with_context(|| format!("Unable to resolve conflict in \"{}\".", conflict.path))
+}
+
+// Names the copy like "todo (conflict with 192.168.1.20 2026-10-18 15-30-12).md". Colons aren't allowed on every file system.
+fn conflict_copy(file: &Path, peer: SocketAddr, time: time::SystemTime) -> PathBuf {
+    let label = format!(
+        " (conflict with {} {})",
+        peer.ip(),
+        util::format_timestamp(time)
+    )
+    .replace(':', "-");
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match file.extension() {
+        Some(extension) => format!("{}{}.{}", stem, label, extension.to_string_lossy()),
+        None => format!("{}{}", stem, label),
+    };
+    file.with_file_name(name)
+}
+
+// What we know of a file: the edits it includes, and its hash. Files that were removed have no hash.
+#[derive(Clone, Debug, PartialEq)]
+struct Known {
+    version: Version,
+    hash: Option<blake3::Hash>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Take,     // Builds on ours
+    Keep,     // Ours is newer, or wins the conflict
+    Same,     // Same contents, no matter the history
+    Conflict, // Theirs wins, so ours is moved out of the way
+}
+
+// Both ends have to pick the same winner without talking it over. The version with more edits wins, and the hash breaks ties.
+fn decide(ours: Option<&Known>, theirs: &Known) -> Decision {
+    let ours = match ours {
+        Some(ours) => ours,
+        None => return Decision::Take,
+    };
+    if ours.hash == theirs.hash {
+        return Decision::Same;
+    }
+    match theirs.version.compare(&ours.version) {
+        Order::Newer => Decision::Take,
+        Order::Same | Order::Older => Decision::Keep,
+        Order::Concurrent => match (ours.hash, theirs.hash) {
+            (None, _) => Decision::Take, // Changes win over removals
+            (_, None) => Decision::Keep,
+            (Some(our_hash), Some(their_hash)) => {
+                let ours = (ours.version.edits(), our_hash.as_bytes());
+                let theirs = (theirs.version.edits(), their_hash.as_bytes());
+                match theirs > ours {
+                    true => Decision::Conflict,
+                    false => Decision::Keep,
+                }
+            }
+        },
+    }
+}
+
+// What came of an offer from the peer
+#[derive(Debug, Default)]
+pub struct Received {
+    pub skipped: Vec<usize>,   // Manifest entries not to write
+    pub unchanged: Vec<usize>, // Skipped, as we have the same already
+    pub conflicts: Vec<Conflict>,
+    pub removed: Vec<String>, // Removed here as well
+    pub kept: Vec<String>, // Removed there, but changed here. They go out again with the next round.
+}
+
+// Our end of a two-way sync
+pub struct Folder {
+    pub name: String,  // Every path in the sync starts with this
+    pub base: PathBuf, // The directory the folder is in
+    replica: u64,
+    known: HashMap<String, Known>,
+    incoming: HashMap<String, Known>, // Taken from an offer, until the file is in place
+}
+
+impl Folder {
+    pub fn new(replica: u64, name: &str, base: &Path) -> Folder {
+        Folder {
+            name: name.to_string(),
+            base: base.to_path_buf(),
+            replica,
+            known: HashMap::new(),
+            incoming: HashMap::new(),
+        }
+    }
+
+    // Our end of a folder we share. Only whole folders can be shared both ways.
+    pub fn local(replica: u64, path: &Path) -> Result<Folder> {
+        if !path.is_dir() {
+            return Err(anyhow!("\"{}\" is not a folder.", path.display()));
+        }
+        let path = path
+            .absolutize()
+            .with_context(|| format!("Unable to find \"{}\".", path.display()))?;
+        match (
+            path.file_name().and_then(|name| name.to_str()),
+            path.parent(),
+        ) {
+            (Some(name), Some(base)) => Ok(Folder::new(replica, name, base)),
+            _ => Err(anyhow!("\"{}\" can't be shared.", path.display())),
+        }
+    }
+
+    // Our end of a folder the peer offered to share, set up in the given directory. The name is the peer's pick, so it goes
+    // through the sandbox like any other path.
+    pub fn offered(
+        replica: u64,
+        manifest: &Manifest,
+        removed: &[(String, Version)],
+        directory: &Path,
+    ) -> Result<Folder> {
+        let name = manifest
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .chain(removed.iter().map(|(path, _)| path.as_str()))
+            .next()
+            .and_then(|path| path.split('/').next())
+            .ok_or_else(|| anyhow!("Offer doesn't name a folder."))?;
+        let path = Sandbox::new(directory)?
+            .resolve(name)
+            .with_context(|| format!("Refusing to create \"{}\".", name))?;
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Unable to create \"{}\".", path.display()))?;
+        Ok(Folder::new(replica, name, directory))
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.base.join(&self.name)
+    }
+
+    pub fn version(&self, path: &str) -> Version {
+        self.known
+            .get(path)
+            .map(|known| known.version.clone())
+            .unwrap_or_default()
+    }
+
+    // Counts every file that changed since the last scan as an edit of ours, and the same for every file that is gone
+    pub fn scanned(&mut self, manifest: &Manifest) {
+        for entry in &manifest.entries {
+            let known = self.known.entry(entry.path.clone()).or_insert(Known {
+                version: Version::default(),
+                hash: None,
+            });
+            if known.hash != Some(entry.hash) {
+                known.version.bump(self.replica);
+                known.hash = Some(entry.hash);
+            }
+        }
+
+        let present: HashSet<&str> = manifest
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect();
+        for (path, known) in self.known.iter_mut() {
+            // Files moved out of the way for a conflict are about to come back
+            if known.hash.is_some()
+                && !present.contains(path.as_str())
+                && !self.incoming.contains_key(path)
+            {
+                known.version.bump(self.replica);
+                known.hash = None;
+            }
+        }
+    }
+
+    // Decides what to do with each file and removal of an offer, and does what can be done up front:
+    // removing files and moving our versions out of the way. The rest happens as the files arrive.
+    pub fn receive(
+        &mut self,
+        manifest: &Manifest,
+        versions: &[Version],
+        removed: &[(String, Version)],
+        peer: SocketAddr,
+    ) -> Result<Received> {
+        if versions.len() != manifest.len() {
+            return Err(anyhow!(
+                "Offer has {} files, but {} versions.",
+                manifest.len(),
+                versions.len()
+            ));
+        }
+        let paths = manifest
+            .entries
+            .iter()
+            .map(|entry| &entry.path)
+            .chain(removed.iter().map(|(path, _)| path));
+        for path in paths {
+            if path.split('/').next() != Some(self.name.as_str()) {
+                return Err(anyhow!(
+                    "\"{}\" is outside of the shared folder \"{}\".",
+                    path,
+                    self.name
+                ));
+            }
+        }
+
+        // Whatever didn't arrive from the last offer won't anymore
+        self.incoming.clear();
+
+        let sandbox = Sandbox::new(&self.base)?;
+        let mut received = Received::default();
+        for (index, (entry, version)) in manifest.entries.iter().zip(versions).enumerate() {
+            let file = sandbox
+                .resolve(&entry.path)
+                .with_context(|| format!("Refusing to write \"{}\".", entry.path))?;
+            let ours = self.ours(&entry.path, &file)?;
+            let theirs = Known {
+                version: version.clone(),
+                hash: Some(entry.hash),
+            };
+            match decide(ours.as_ref(), &theirs) {
+                Decision::Take => {
+                    self.incoming.insert(entry.path.clone(), theirs);
+                }
+                Decision::Keep => received.skipped.push(index),
+                Decision::Same => {
+                    let version = ours.map(|ours| ours.version).unwrap_or_default();
+                    self.known.insert(
+                        entry.path.clone(),
+                        Known {
+                            version: version.merge(&theirs.version),
+                            hash: theirs.hash,
+                        },
+                    );
+                    received.skipped.push(index);
+                    received.unchanged.push(index);
+                }
+                Decision::Conflict => {
+                    let time = time::SystemTime::now();
+                    let copy = conflict_copy(&file, peer, time);
+                    fs::rename(&file, &copy).with_context(|| {
+                        format!("Unable to move \"{}\" out of the way.", file.display())
+                    })?;
+                    self.incoming.insert(entry.path.clone(), theirs);
+                    received.conflicts.push(Conflict {
+                        path: entry.path.clone(),
+                        file,
+                        copy,
+                        peer,
+                        time,
+                    });
+                }
+            }
+        }
+
+        for (path, version) in removed {
+            let file = sandbox
+                .resolve(path)
+                .with_context(|| format!("Refusing to remove \"{}\".", path))?;
+            let ours = self.ours(path, &file)?;
+            let theirs = Known {
+                version: version.clone(),
+                hash: None,
+            };
+            match decide(ours.as_ref(), &theirs) {
+                Decision::Take => {
+                    if ours.is_some_and(|ours| ours.hash.is_some()) {
+                        download::remove_files(&self.base, std::slice::from_ref(path))?;
+                        received.removed.push(path.clone());
+                    }
+                    self.known.insert(path.clone(), theirs);
+                }
+                Decision::Same => {
+                    let version = ours.map(|ours| ours.version).unwrap_or_default();
+                    self.known.insert(
+                        path.clone(),
+                        Known {
+                            version: version.merge(&theirs.version),
+                            hash: None,
+                        },
+                    );
+                }
+                Decision::Keep | Decision::Conflict => received.kept.push(path.clone()),
+            }
+        }
+        Ok(received)
+    }
+
+    // Taken from the last offer, and not in place yet
+    pub fn is_incoming(&self, path: &str) -> bool {
+        self.incoming.contains_key(path)
+    }
+
+    // The file from the offer is in place
+    pub fn arrived(&mut self, path: &str) {
+        if let Some(known) = self.incoming.remove(path) {
+            self.known.insert(path.to_string(), known);
+        }
+    }
+
+    // What we have of a file. Edits we haven't scanned yet count as well, or they could be overwritten.
+    fn ours(&self, path: &str, file: &Path) -> Result<Option<Known>> {
+        let hash = match fs::symlink_metadata(file) {
+            Ok(metadata) if metadata.is_file() => Some(hashing::hash_file(file)?.1),
+            _ => None,
+        };
+        Ok(match self.known.get(path) {
+            Some(known) if known.hash == hash => Some(known.clone()),
+            Some(known) => Some(Known {
+                version: known.version.bumped(self.replica),
+                hash,
+            }),
+            None if hash.is_some() => Some(Known {
+                version: Version::default().bumped(self.replica),
+                hash,
+            }),
+            None => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ManifestEntry;
+
+    fn entry(path: &str, content: &str) -> ManifestEntry {
+        ManifestEntry {
+            path: String::from(path),
+            size: content.len() as u64,
+            hash: blake3::hash(content.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn decisions() {
+        let known = |counters: Vec<(u64, u64)>, content: Option<&str>| Known {
+            version: Version::from_counters(counters),
+            hash: content.map(|content| blake3::hash(content.as_bytes())),
+        };
+        let base = known(vec![(1, 1)], Some("soup"));
+
+        assert_eq!(decide(None, &base), Decision::Take);
+        assert_eq!(
+            decide(Some(&base), &known(vec![(1, 1), (2, 1)], Some("stew"))),
+            Decision::Take
+        );
+        assert_eq!(
+            decide(Some(&known(vec![(1, 2)], Some("stew"))), &base),
+            Decision::Keep
+        );
+        assert_eq!(
+            decide(Some(&known(vec![(2, 5)], Some("soup"))), &base),
+            Decision::Same
+        );
+
+        // Both ends have to come to opposite conclusions about the same pair of edits
+        let ours = known(vec![(1, 2)], Some("stew"));
+        let theirs = known(vec![(1, 1), (2, 1)], Some("broth"));
+        let decisions = (decide(Some(&ours), &theirs), decide(Some(&theirs), &ours));
+        assert!(
+            decisions == (Decision::Keep, Decision::Conflict)
+                || decisions == (Decision::Conflict, Decision::Keep)
+        );
+
+        // Changes win over removals, whichever end made them
+        let removed = known(vec![(1, 1), (2, 1)], None);
+        assert_eq!(decide(Some(&removed), &ours), Decision::Take);
+        assert_eq!(decide(Some(&ours), &removed), Decision::Keep);
+    }
+
+    #[test]
+    fn conflicts() {
+        let peer = "192.168.1.20:31415".parse().unwrap();
+        let ours = util::TempDir::new("shared-ours");
+        let notes = ours.path().join("notes");
+        fs::create_dir(&notes).unwrap();
+        fs::write(notes.join("todo.md"), "groceries").unwrap();
+        fs::write(notes.join("today.md"), "sandwiches").unwrap();
+
+        let mut folder = Folder::new(1, "notes", ours.path());
+        folder.scanned(&Manifest::new(vec![
+            entry("notes/todo.md", "groceries"),
+            entry("notes/today.md", "sandwiches"),
+        ]));
+
+        // The peer had the same files, edited one and removed the other. Meanwhile, we edited the first one as well.
+        fs::write(notes.join("todo.md"), "groceries, laundry").unwrap();
+        let theirs = Version::from_counters(vec![(1, 1), (2, 3)]);
+        let manifest = Manifest::new(vec![entry("notes/todo.md", "groceries, dishes")]);
+        let received = folder
+            .receive(
+                &manifest,
+                std::slice::from_ref(&theirs),
+                &[(String::from("notes/today.md"), theirs.clone())],
+                peer,
+            )
+            .unwrap();
+
+        assert!(received.skipped.is_empty());
+        assert_eq!(received.removed, vec![String::from("notes/today.md")]);
+        assert!(!notes.join("today.md").exists());
+        let conflict = &received.conflicts[0];
+        assert_eq!(conflict.file, notes.join("todo.md"));
+        assert!(conflict
+            .copy
+            .to_string_lossy()
+            .contains("todo (conflict with 192.168.1.20 "));
+        assert_eq!(
+            fs::read_to_string(&conflict.copy).unwrap(),
+            "groceries, laundry"
+        );
+
+        // Once their version is in place, a scan finds nothing new but the copy
+        fs::write(notes.join("todo.md"), "groceries, dishes").unwrap();
+        folder.arrived("notes/todo.md");
+        assert_eq!(
+            folder.version("notes/todo.md"),
+            Version::from_counters(vec![(1, 1), (2, 3)])
+        );
+
+        resolve(conflict, Resolution::Ours).unwrap();
+        assert_eq!(
+            fs::read_to_string(notes.join("todo.md")).unwrap(),
+            "groceries, laundry"
+        );
+        assert!(!conflict.copy.exists());
+
+        // Files outside of the folder are none of the peer's business
+        assert!(folder
+            .receive(
+                &Manifest::new(vec![entry("photos/ferris.jpg", "crab")]),
+                &[Version::default()],
+                &[],
+                peer,
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,83 @@
+// Sparse files: ranges that were never written take no space on disk and read as zeros. VM images and database files
+// tend to be mostly that. The sender finds them with SEEK_DATA and SEEK_HOLE, and sends them as `FileHole` rather than
+// chunks of zeros. The receiver skips over them, which leaves a hole in its copy as well.
+// File systems that don't keep track of holes report everything as data, so all that's lost there is the savings.
+
+use std::fs::File;
+
+// Longest hole a single `FileHole` may hold. The receiver hashes the zeros a hole reads as in one go, which shouldn't hold up
+// everything else it has to do, so longer holes go out in several pieces.
+pub const MAX_HOLE: u64 = 4 << 20;
+
+// What a file holds from some offset on, and for how long
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Extent {
+    Data(u64),
+    Hole(u64),
+}
+
+// Looks at the file from the offset on, up to the given size. Moves the file position.
+// Whatever can't be found out counts as data, which is never wrong, just larger.
+#[cfg(target_os = "linux")]
+pub fn extent(file: &File, offset: u64, size: u64) -> Extent {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    let remaining = size.saturating_sub(offset);
+    let seek = |whence| -> io::Result<u64> {
+        // Safe, as the descriptor belongs to the file for as long as it is borrowed
+        match unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) } {
+            -1 => Err(io::Error::last_os_error()),
+            position => Ok(position as u64),
+        }
+    };
+
+    let data = match seek(libc::SEEK_DATA) {
+        Ok(data) => data,
+        // There's no data past the offset, so the rest is a hole
+        Err(error) if error.raw_os_error() == Some(libc::ENXIO) => return Extent::Hole(remaining),
+        Err(_) => return Extent::Data(remaining),
+    };
+    if data > offset {
+        return Extent::Hole((data - offset).min(remaining));
+    }
+    match seek(libc::SEEK_HOLE) {
+        Ok(hole) if hole > offset => Extent::Data((hole - offset).min(remaining)),
+        _ => Extent::Data(remaining),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn extent(_file: &File, offset: u64, size: u64) -> Extent {
+    Extent::Data(size.saturating_sub(offset))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[test]
+    fn extents() {
+        let directory = TempDir::new("sparse-extents");
+        let path = directory.path().join("disk.img");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"boot").unwrap();
+        file.seek(SeekFrom::Start(1 << 20)).unwrap();
+        file.write_all(b"root").unwrap();
+        file.set_len(4 << 20).unwrap();
+        let size = file.metadata().unwrap().len();
+
+        // Data comes in whole blocks, so the first one reaches past "boot"
+        let first = match extent(&file, 0, size) {
+            Extent::Data(length) => length,
+            hole => panic!("Expected data, got {:?}", hole),
+        };
+        assert!((4..1 << 20).contains(&first));
+        assert_eq!(extent(&file, first, size), Extent::Hole((1 << 20) - first));
+        assert!(matches!(extent(&file, 1 << 20, size), Extent::Data(_)));
+        assert_eq!(extent(&file, 2 << 20, size), Extent::Hole(2 << 20));
+        assert_eq!(extent(&file, size, size), Extent::Hole(0));
+    }
+}
@@ -0,0 +1,494 @@
+// Headless transfers for scripts, like `tar c photos | bitgeon send` on one end and `bitgeon receive <address> <token> | tar x` on the other.
+// The source can be of any length, so there is no manifest and no file hash up front. Instead, every chunk carries its own hash
+// and the total is checked at the end. Nothing here touches the terminal, as stdout is busy carrying the data.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time;
+
+use anyhow::{anyhow, Context, Result};
+use async_std::channel;
+use async_std::io::{Read, ReadExt, Write, WriteExt};
+use async_std::task;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::protocol::{FrameBuffer, Message};
+use crate::server;
+use crate::settings::ServerSettings;
+use crate::transport::{Connection, Incoming, Listener, Reader, TransportKind, Writer};
+use crate::util;
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Summary {
+    pub length: u64,
+    pub hash: blake3::Hash,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} bytes), BLAKE3 {}",
+            util::format_size(self.length),
+            self.length,
+            self.hash.to_hex()
+        )
+    }
+}
+
+// Handed out by the sender along with its address. Whoever connects has to present it before getting the stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Token([u8; 16]);
+
+impl Token {
+    pub fn generate() -> Result<Token> {
+        let mut token = [0; 16];
+        SystemRandom::new()
+            .fill(&mut token)
+            .map_err(|_| anyhow!("Unable to draw a random stream token."))?;
+        Ok(Token(token))
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Token {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Token> {
+        let invalid = || {
+            anyhow!(
+                "\"{}\" is not a stream token. Copy it from the sender.",
+                text
+            )
+        };
+        if text.len() != 32 || !text.is_ascii() {
+            return Err(invalid());
+        }
+        let mut token = [0; 16];
+        for (byte, digits) in token.iter_mut().zip(text.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+        }
+        Ok(Token(token))
+    }
+}
+
+// Checks incoming chunks one at a time, so nothing damaged or out of order is ever passed on
+pub struct Verifier {
+    length: u64,
+    hasher: blake3::Hasher,
+}
+
+impl Verifier {
+    pub fn new() -> Verifier {
+        Verifier {
+            length: 0,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    pub fn chunk(&mut self, offset: u64, hash: &blake3::Hash, data: &[u8]) -> Result<()> {
+        if offset != self.length {
+            return Err(anyhow!(
+                "Expected chunk at offset {}, but got {}.",
+                self.length,
+                offset
+            ));
+        }
+        if blake3::hash(data) != *hash {
+            return Err(anyhow!("Chunk at offset {} is corrupted.", offset));
+        }
+        self.hasher.update(data);
+        self.length += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn end(self, length: u64, hash: &blake3::Hash) -> Result<Summary> {
+        let summary = Summary {
+            length: self.length,
+            hash: self.hasher.finalize(),
+        };
+        if summary.length != length {
+            return Err(anyhow!(
+                "Stream ended after {} bytes, but the sender sent {}.",
+                summary.length,
+                length
+            ));
+        }
+        if summary.hash != *hash {
+            return Err(anyhow!(
+                "Stream is corrupted. Expected hash {}, but got {}.",
+                hash.to_hex(),
+                summary.hash.to_hex()
+            ));
+        }
+        Ok(summary)
+    }
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Waits for the receiver on any of the listeners and hands them the input. Only returns once the receiver has confirmed
+// what it got, so a script can rely on the exit code.
+pub async fn send(
+    listeners: Vec<Listener>,
+    token: Token,
+    mut input: impl Read + Unpin,
+    timeout: time::Duration,
+) -> Result<Summary> {
+    // The listeners have to stay around for the whole transfer. A QUIC connection ends with its endpoint.
+    let listeners: Vec<_> = listeners.into_iter().map(Arc::new).collect();
+    let Receiver {
+        address,
+        mut reader,
+        mut writer,
+        mut frames,
+    } = accept_receiver(&listeners, token, timeout).await?;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut offset = 0;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = read_chunk(&mut input, &mut buffer)
+            .await
+            .with_context(|| String::from("Unable to read input."))?;
+        if read == 0 {
+            break;
+        }
+        let data = &buffer[..read];
+        hasher.update(data);
+        let chunk = Message::StreamChunk {
+            offset,
+            hash: blake3::hash(data),
+            data: data.to_vec(),
+        };
+        writer.write_all(&chunk.encode()?).await?;
+        offset += read as u64;
+    }
+
+    let summary = Summary {
+        length: offset,
+        hash: hasher.finalize(),
+    };
+    let end = Message::StreamEnd {
+        length: summary.length,
+        hash: summary.hash,
+    };
+    writer.write_all(&end.encode()?).await?;
+
+    match async_std::future::timeout(timeout, reader.next_message(&mut frames)).await {
+        Ok(Ok(Message::StreamAck { length, hash }))
+            if length == summary.length && hash == summary.hash =>
+        {
+            Ok(summary)
+        }
+        Ok(Ok(Message::StreamAck { .. })) => Err(anyhow!(
+            "{} received something other than what was sent.",
+            address
+        )),
+        Ok(Ok(message)) => Err(anyhow!("Unexpected answer from {}: {:?}", address, message)),
+        Ok(Err(error)) => Err(error.context(format!("{} didn't confirm the stream.", address))),
+        Err(_) => Err(anyhow!("{} didn't confirm the stream in time.", address)),
+    }
+}
+
+// Asks the sender at the given address for its stream and writes it to the output as it comes in
+pub async fn receive(
+    transports: &[TransportKind],
+    address: SocketAddr,
+    token: Token,
+    mut output: impl Write + Unpin,
+    timeout: time::Duration,
+) -> Result<Summary> {
+    let connection = Connection::negotiate(transports, address, timeout).await?;
+    let (mut reader, mut writer) = connection.split();
+    let claim = Message::StreamClaim { token: token.0 };
+    writer.write_all(&claim.encode()?).await?;
+
+    let mut frames = FrameBuffer::new();
+    let mut verifier = Verifier::new();
+    let summary = loop {
+        match reader.next_message(&mut frames).await? {
+            Message::StreamChunk { offset, hash, data } => {
+                verifier.chunk(offset, &hash, &data)?;
+                output
+                    .write_all(&data)
+                    .await
+                    .with_context(|| String::from("Unable to write output."))?;
+            }
+            Message::StreamEnd { length, hash } => break verifier.end(length, &hash)?,
+            message => {
+                return Err(anyhow!(
+                    "Unexpected message from {}: {:?}",
+                    address,
+                    message
+                ))
+            }
+        }
+    };
+    output
+        .flush()
+        .await
+        .with_context(|| String::from("Unable to write output."))?;
+
+    let ack = Message::StreamAck {
+        length: summary.length,
+        hash: summary.hash,
+    };
+    writer.write_all(&ack.encode()?).await?;
+
+    // Closing right away could throw away the acknowledgement on QUIC. The sender hangs up once it has it.
+    let _ = async_std::future::timeout(timeout, reader.next_message(&mut frames)).await;
+    Ok(summary)
+}
+
+// Pipes on stdin tend to deliver a few KiB at a time. Filling the buffer keeps the number of chunks down.
+async fn read_chunk(input: &mut (impl Read + Unpin), buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..]).await? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+// The connection that has claimed the stream, ready to be sent to
+struct Receiver {
+    address: SocketAddr,
+    reader: Reader,
+    writer: Writer,
+    frames: FrameBuffer,
+}
+
+// Every connection gets the timeout to claim the stream with the token, while the listeners go on accepting. Anything
+// else is dropped, so neither strangers nor port scans can take the stream or end the wait for the real receiver.
+async fn accept_receiver(
+    listeners: &[Arc<Listener>],
+    token: Token,
+    timeout: time::Duration,
+) -> Result<Receiver> {
+    let (sender, receiver) = channel::bounded(1);
+    let acceptors: Vec<_> = listeners
+        .iter()
+        .map(|listener| {
+            let listener = listener.clone();
+            let sender = sender.clone();
+            task::spawn(async move {
+                while let Some(incoming) = listener.next().await {
+                    task::spawn(claim(incoming, token, timeout, sender.clone()));
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    let claimed = receiver
+        .recv()
+        .await
+        .map_err(|_| anyhow!("Stopped listening."));
+    for acceptor in acceptors {
+        acceptor.cancel().await;
+    }
+    claimed
+}
+
+async fn claim(
+    incoming: Incoming,
+    token: Token,
+    timeout: time::Duration,
+    claimed: channel::Sender<Receiver>,
+) -> Result<()> {
+    let connection = incoming.establish(timeout).await?;
+    let address = connection.peer_addr();
+    let (mut reader, writer) = connection.split();
+    let mut frames = FrameBuffer::new();
+    match async_std::future::timeout(timeout, reader.next_message(&mut frames)).await {
+        Ok(Ok(Message::StreamClaim { token: presented })) if presented == token.0 => {
+            let receiver = Receiver {
+                address,
+                reader,
+                writer,
+                frames,
+            };
+            // Only the first one gets the stream. Anyone after that is hung up on.
+            let _ = claimed.try_send(receiver);
+            Ok(())
+        }
+        _ => Err(anyhow!("{} didn't claim the stream.", address)),
+    }
+}
+
+// `bitgeon send`: streams stdin to the receiver that comes with the token
+pub fn run_send(port: Option<u16>, timeout: time::Duration) -> Result<()> {
+    let token = Token::generate()?;
+    // Every transport listens on the same port, just like the server does
+    let mut address = SocketAddr::from(([0, 0, 0, 0], port.unwrap_or(0)));
+    let mut listeners = vec![];
+    for kind in ServerSettings::default().transports {
+        let listener = Listener::bind(kind, address)?;
+        address = listener.local_addr()?;
+        listeners.push(listener);
+    }
+
+    match server::get_local_ip() {
+        Ok(ip) => eprintln!(
+            "Waiting for a receiver. Run `bitgeon receive {} {}` on the other end.",
+            SocketAddr::new(ip, address.port()),
+            token
+        ),
+        Err(_) => eprintln!(
+            "Waiting for a receiver on port {}. Run `bitgeon receive <address>:{} {}` on the other end.",
+            address.port(),
+            address.port(),
+            token
+        ),
+    }
+
+    let summary = task::block_on(send(listeners, token, async_std::io::stdin(), timeout))?;
+    eprintln!("Sent {}", summary);
+    Ok(())
+}
+
+// `bitgeon receive`: writes the stream from the given sender to stdout
+pub fn run_receive(address: SocketAddr, token: Token, timeout: time::Duration) -> Result<()> {
+    let transports = ServerSettings::default().transports;
+    let summary = task::block_on(receive(
+        &transports,
+        address,
+        token,
+        async_std::io::stdout(),
+        timeout,
+    ))?;
+    eprintln!("Received {}", summary);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOOPBACK: [u8; 4] = [127, 0, 0, 1];
+
+    fn round_trip(kind: TransportKind) {
+        task::block_on(async {
+            let listener = Listener::bind(kind, SocketAddr::from((LOOPBACK, 0))).unwrap();
+            let address = listener.local_addr().unwrap();
+            let timeout = time::Duration::from_secs(2);
+
+            // Not a multiple of the chunk size, so the last chunk is a short one
+            let input: Vec<u8> = (0..3 * CHUNK_SIZE + 1234)
+                .map(|i| (i % 251) as u8)
+                .collect();
+            let token = Token::generate().unwrap();
+            let sending = {
+                let input = input.clone();
+                task::spawn(async move { send(vec![listener], token, &input[..], timeout).await })
+            };
+
+            let mut output = vec![];
+            let received = receive(&[kind], address, token, &mut output, timeout)
+                .await
+                .unwrap();
+            let sent = sending.await.unwrap();
+
+            assert_eq!(output, input);
+            assert_eq!(sent, received);
+            assert_eq!(sent.length, input.len() as u64);
+            assert_eq!(sent.hash, blake3::hash(&input));
+        });
+    }
+
+    #[test]
+    fn tcp() {
+        round_trip(TransportKind::Tcp);
+    }
+
+    #[test]
+    fn quic() {
+        round_trip(TransportKind::Quic);
+    }
+
+    // Connections without the token are hung up on, and the sender goes on waiting for the receiver
+    #[test]
+    fn strangers() {
+        task::block_on(async {
+            let kind = TransportKind::Tcp;
+            let listener = Listener::bind(kind, SocketAddr::from((LOOPBACK, 0))).unwrap();
+            let address = listener.local_addr().unwrap();
+            let timeout = time::Duration::from_millis(500);
+            let token = Token::generate().unwrap();
+            let sending =
+                task::spawn(
+                    async move { send(vec![listener], token, &b"tarball"[..], timeout).await },
+                );
+
+            // A port scan, an old receiver and someone guessing
+            let silent = Connection::connect(kind, address, timeout).await.unwrap();
+            let (_, mut writer) = Connection::connect(kind, address, timeout)
+                .await
+                .unwrap()
+                .split();
+            writer
+                .write_all(&Message::StreamRequest.encode().unwrap())
+                .await
+                .unwrap();
+            let guess = Token::generate().unwrap();
+            assert!(receive(&[kind], address, guess, &mut vec![], timeout)
+                .await
+                .is_err());
+            task::sleep(2 * timeout).await;
+            drop(silent);
+
+            let mut output = vec![];
+            receive(&[kind], address, token, &mut output, timeout)
+                .await
+                .unwrap();
+            assert_eq!(output, b"tarball");
+            assert_eq!(sending.await.unwrap().length, 7);
+        });
+    }
+
+    #[test]
+    fn token() {
+        let token = Token::generate().unwrap();
+        assert_eq!(token.to_string().parse::<Token>().unwrap(), token);
+        assert!("0123".parse::<Token>().is_err());
+        assert!("g".repeat(32).parse::<Token>().is_err());
+        assert!("é".repeat(16).parse::<Token>().is_err());
+    }
+
+    #[test]
+    fn verifier() {
+        let mut verifier = Verifier::new();
+        verifier.chunk(0, &blake3::hash(b"tar"), b"tar").unwrap();
+        assert!(verifier.chunk(0, &blake3::hash(b"ball"), b"ball").is_err()); // Repeated
+        assert!(verifier.chunk(4, &blake3::hash(b"ball"), b"ball").is_err()); // Gap
+        assert!(verifier.chunk(3, &blake3::hash(b"ball"), b"bell").is_err()); // Damaged
+        verifier.chunk(3, &blake3::hash(b"ball"), b"ball").unwrap();
+        let summary = verifier.end(7, &blake3::hash(b"tarball")).unwrap();
+        assert_eq!(summary.length, 7);
+
+        // Chunks went missing at the end
+        let mut verifier = Verifier::new();
+        verifier.chunk(0, &blake3::hash(b"tar"), b"tar").unwrap();
+        assert!(verifier.end(7, &blake3::hash(b"tarball")).is_err());
+    }
+}
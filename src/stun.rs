@@ -0,0 +1,417 @@
+// Minimal STUN client (RFC 5389). Asking a STUN server which address our requests come from tells us our public address,
+// even behind several NATs or with UPnP switched off. Asking a second server from the same socket tells us how the NAT maps ports,
+// which decides whether hole punching stands a chance (RFC 5780).
+
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time;
+
+use anyhow::{anyhow, Context, Result};
+use ring::rand::{SecureRandom, SystemRandom};
+use thiserror::Error;
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LENGTH: usize = 20;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+
+const MAPPED_ADDRESS: u16 = 0x0001;
+const ERROR_CODE: u16 = 0x0009;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+// Retransmissions as in RFC 5389, section 7.2.1, but with fewer attempts, since the server thread waits for us
+const INITIAL_RTO: time::Duration = time::Duration::from_millis(250);
+const ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum StunError {
+    #[error("STUN message is truncated")]
+    Truncated,
+    #[error("Not a STUN message")]
+    NotStun,
+    #[error("Unexpected STUN message type {0:#06x}")]
+    UnexpectedType(u16),
+    #[error("STUN server answered with error {0}")]
+    ErrorResponse(u16),
+    #[error("STUN response contains no mapped address")]
+    NoMappedAddress,
+    #[error("Unknown address family {0}")]
+    UnknownAddressFamily(u8),
+}
+
+// How the NAT in front of us picks the public port for outgoing traffic
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MappingBehavior {
+    EndpointIndependent, // Same public port, no matter where we send to. Hole punching works.
+    EndpointDependent, // A new public port for every destination, aka symmetric NAT. Hole punching rarely works.
+}
+
+impl fmt::Display for MappingBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MappingBehavior::EndpointIndependent => write!(f, "endpoint-independent mapping"),
+            MappingBehavior::EndpointDependent => write!(f, "endpoint-dependent mapping"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Discovery {
+    pub local: SocketAddr, // The address of the interface we used to reach the STUN server
+    pub public: SocketAddr, // Server-reflexive address, i.e. what the STUN server saw
+    pub mapping: Option<MappingBehavior>, // None if only one STUN server answered
+}
+
+impl fmt::Display for Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mapping {
+            Some(mapping) => write!(f, "Public address {} ({})", self.public, mapping),
+            None => write!(f, "Public address {}", self.public),
+        }
+    }
+}
+
+// Asks the given servers for our public address, in order, until one answers.
+// If a second server answers as well, comparing both answers classifies the NAT.
+pub fn discover(servers: &[String]) -> Result<Discovery> {
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .with_context(|| String::from("Unable to bind UDP socket to \"0.0.0.0:0\"."))?;
+
+    let mut answers = vec![];
+    let mut errors = vec![];
+    for server in servers {
+        match resolve(server).and_then(|address| binding_request(&socket, address)) {
+            Ok(answer) => answers.push(answer),
+            Err(error) => errors.push(format!("{}: {:#}", server, error)),
+        }
+        if answers.len() == 2 {
+            break;
+        }
+    }
+
+    let (local, public) = match answers.first() {
+        Some(answer) => *answer,
+        None if servers.is_empty() => return Err(anyhow!("No STUN servers configured.")),
+        None => return Err(anyhow!("No STUN server answered. {}", errors.join(", "))),
+    };
+    let mapping = answers.get(1).map(|(_, other)| {
+        if *other == public {
+            MappingBehavior::EndpointIndependent
+        } else {
+            MappingBehavior::EndpointDependent
+        }
+    });
+
+    Ok(Discovery {
+        local,
+        public,
+        mapping,
+    })
+}
+
+fn resolve(server: &str) -> Result<SocketAddr> {
+    // We only bind to IPv4, so only IPv4 servers can answer
+    server
+        .to_socket_addrs()
+        .with_context(|| format!("Unable to resolve \"{}\".", server))?
+        .find(|address| address.is_ipv4())
+        .with_context(|| format!("\"{}\" has no IPv4 address.", server))
+}
+
+// Sends a binding request and returns the local and the server-reflexive address
+fn binding_request(socket: &UdpSocket, server: SocketAddr) -> Result<(SocketAddr, SocketAddr)> {
+    // Connecting only filters incoming datagrams and picks the interface, it doesn't change the local port
+    socket
+        .connect(server)
+        .with_context(|| format!("Unable to connect UDP socket to {}.", server))?;
+    let local = socket.local_addr()?;
+
+    let transaction_id = transaction_id()?;
+    let request = Message {
+        kind: BINDING_REQUEST,
+        transaction_id,
+        attributes: vec![],
+    }
+    .encode();
+
+    let mut buffer = [0; 1024];
+    let mut timeout = INITIAL_RTO;
+    for _ in 0..ATTEMPTS {
+        socket.send(&request)?;
+        socket.set_read_timeout(Some(timeout))?;
+
+        let deadline = time::Instant::now() + timeout;
+        while time::Instant::now() < deadline {
+            let read = match socket.recv(&mut buffer) {
+                Ok(read) => read,
+                Err(_) => break, // Timed out, or an ICMP error came back. Either way, try again.
+            };
+
+            // Anything that isn't the answer to our request is ignored
+            let response = match Message::decode(&buffer[..read]) {
+                Ok(response) if response.transaction_id == transaction_id => response,
+                _ => continue,
+            };
+            return Ok((local, response.mapped_address()?));
+        }
+        timeout *= 2;
+    }
+
+    Err(anyhow!("No answer from {}.", server))
+}
+
+// Has to be unpredictable, so nobody can slip us a fake answer (RFC 5389, section 6)
+fn transaction_id() -> Result<[u8; 12]> {
+    let mut id = [0; 12];
+    SystemRandom::new()
+        .fill(&mut id)
+        .map_err(|_| anyhow!("Unable to draw a random STUN transaction ID."))?;
+    Ok(id)
+}
+
+#[derive(Debug, PartialEq)]
+struct Message {
+    kind: u16,
+    transaction_id: [u8; 12],
+    attributes: Vec<(u16, Vec<u8>)>,
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut body = vec![];
+        for (kind, value) in &self.attributes {
+            body.extend_from_slice(&kind.to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(value);
+            // Attributes are padded to a multiple of 4 bytes
+            body.resize(body.len() + (4 - value.len() % 4) % 4, 0);
+        }
+
+        let mut message = self.kind.to_be_bytes().to_vec();
+        message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        message.extend_from_slice(&self.transaction_id);
+        message.append(&mut body);
+        message
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Message, StunError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(StunError::Truncated);
+        }
+        let kind = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        let cookie = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+        // The two most significant bits are always zero, which sets STUN apart from other protocols on the same port
+        if kind & 0xC000 != 0 || cookie != MAGIC_COOKIE || !length.is_multiple_of(4) {
+            return Err(StunError::NotStun);
+        }
+        if bytes.len() < HEADER_LENGTH + length {
+            return Err(StunError::Truncated);
+        }
+
+        let mut attributes = vec![];
+        let mut body = &bytes[HEADER_LENGTH..HEADER_LENGTH + length];
+        while !body.is_empty() {
+            if body.len() < 4 {
+                return Err(StunError::Truncated);
+            }
+            let attribute = u16::from_be_bytes([body[0], body[1]]);
+            let length = u16::from_be_bytes([body[2], body[3]]) as usize;
+            let padded = length.div_ceil(4) * 4;
+            if body.len() < 4 + padded {
+                return Err(StunError::Truncated);
+            }
+            attributes.push((attribute, body[4..4 + length].to_vec()));
+            body = &body[4 + padded..];
+        }
+
+        Ok(Message {
+            kind,
+            transaction_id: bytes[8..HEADER_LENGTH].try_into().unwrap(),
+            attributes,
+        })
+    }
+
+    fn attribute(&self, kind: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| *attribute == kind)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn mapped_address(&self) -> Result<SocketAddr, StunError> {
+        match self.kind {
+            BINDING_SUCCESS => (),
+            BINDING_ERROR => {
+                // Class in the hundreds, number below that
+                let code = match self.attribute(ERROR_CODE) {
+                    Some(value) if value.len() >= 4 => {
+                        (value[2] & 0x07) as u16 * 100 + value[3] as u16
+                    }
+                    _ => 0,
+                };
+                return Err(StunError::ErrorResponse(code));
+            }
+            kind => return Err(StunError::UnexpectedType(kind)),
+        }
+
+        // Old servers only know the plain version
+        if let Some(value) = self.attribute(XOR_MAPPED_ADDRESS) {
+            let address = read_address(value)?;
+            Ok(xor_address(address, &self.transaction_id))
+        } else if let Some(value) = self.attribute(MAPPED_ADDRESS) {
+            read_address(value)
+        } else {
+            Err(StunError::NoMappedAddress)
+        }
+    }
+}
+
+fn read_address(value: &[u8]) -> Result<SocketAddr, StunError> {
+    if value.len() < 4 {
+        return Err(StunError::Truncated);
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]);
+    let ip = match (value[1], &value[4..]) {
+        (0x01, ip) if ip.len() == 4 => IpAddr::from(<[u8; 4]>::try_from(ip).unwrap()),
+        (0x02, ip) if ip.len() == 16 => IpAddr::from(<[u8; 16]>::try_from(ip).unwrap()),
+        (0x01, _) | (0x02, _) => return Err(StunError::Truncated),
+        (family, _) => return Err(StunError::UnknownAddressFamily(family)),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+// XOR-MAPPED-ADDRESS hides the address from middleboxes that rewrite anything looking like an IP. Applying it twice undoes it.
+fn xor_address(address: SocketAddr, transaction_id: &[u8; 12]) -> SocketAddr {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let port = address.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match address.ip() {
+        IpAddr::V4(ip) => {
+            let mut octets = ip.octets();
+            for (octet, key) in octets.iter_mut().zip(cookie.iter()) {
+                *octet ^= key;
+            }
+            IpAddr::from(octets)
+        }
+        IpAddr::V6(ip) => {
+            let mut octets = ip.octets();
+            for (octet, key) in octets
+                .iter_mut()
+                .zip(cookie.iter().chain(transaction_id.iter()))
+            {
+                *octet ^= key;
+            }
+            IpAddr::from(octets)
+        }
+    };
+    SocketAddr::new(ip, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Only servers write addresses
+    fn write_address(address: SocketAddr) -> Vec<u8> {
+        let mut value = vec![0];
+        match address.ip() {
+            IpAddr::V4(ip) => {
+                value.push(0x01);
+                value.extend_from_slice(&address.port().to_be_bytes());
+                value.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                value.push(0x02);
+                value.extend_from_slice(&address.port().to_be_bytes());
+                value.extend_from_slice(&ip.octets());
+            }
+        }
+        value
+    }
+
+    // Minimal STUN server on loopback. Shifting the reported port by an offset makes it look like we are behind a symmetric NAT.
+    fn stun_server(port_offset: u16) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while let Ok((read, source)) = socket.recv_from(&mut buffer) {
+                let request = match Message::decode(&buffer[..read]) {
+                    Ok(request) if request.kind == BINDING_REQUEST => request,
+                    _ => continue,
+                };
+                let mapped = SocketAddr::new(source.ip(), source.port() + port_offset);
+                let response = Message {
+                    kind: BINDING_SUCCESS,
+                    transaction_id: request.transaction_id,
+                    attributes: vec![(
+                        XOR_MAPPED_ADDRESS,
+                        write_address(xor_address(mapped, &request.transaction_id)),
+                    )],
+                };
+                let _ = socket.send_to(&response.encode(), source);
+            }
+        });
+        address.to_string()
+    }
+
+    #[test]
+    fn discovery() {
+        let discovery = discover(&[stun_server(0), stun_server(0)]).unwrap();
+        assert_eq!(discovery.public, discovery.local);
+        assert_eq!(
+            discovery.mapping,
+            Some(MappingBehavior::EndpointIndependent)
+        );
+
+        let discovery = discover(&[stun_server(0), stun_server(1)]).unwrap();
+        assert_eq!(discovery.mapping, Some(MappingBehavior::EndpointDependent));
+
+        // Servers that don't answer are skipped
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let discovery =
+            discover(&[silent.local_addr().unwrap().to_string(), stun_server(0)]).unwrap();
+        assert_eq!(discovery.mapping, None);
+        assert!(discover(&[silent.local_addr().unwrap().to_string()]).is_err());
+    }
+
+    #[test]
+    fn messages() {
+        let transaction_id = [7; 12];
+        for address in &["203.0.113.7:31415", "[2001:db8::1]:80"] {
+            let address: SocketAddr = address.parse().unwrap();
+            let message = Message {
+                kind: BINDING_SUCCESS,
+                transaction_id,
+                attributes: vec![
+                    (0x8022, b"odd".to_vec()), // SOFTWARE, to check padding
+                    (
+                        XOR_MAPPED_ADDRESS,
+                        write_address(xor_address(address, &transaction_id)),
+                    ),
+                ],
+            };
+            let decoded = Message::decode(&message.encode()).unwrap();
+            assert_eq!(decoded, message);
+            assert_eq!(decoded.mapped_address(), Ok(address));
+        }
+
+        let error = Message {
+            kind: BINDING_ERROR,
+            transaction_id,
+            attributes: vec![(ERROR_CODE, vec![0, 0, 4, 20])],
+        };
+        assert_eq!(error.mapped_address(), Err(StunError::ErrorResponse(420)));
+
+        assert_eq!(Message::decode(&[0; 8]), Err(StunError::Truncated));
+        assert_eq!(Message::decode(&[0; 20]), Err(StunError::NotStun));
+        let mut truncated = error.encode();
+        truncated.pop();
+        assert_eq!(Message::decode(&truncated), Err(StunError::Truncated));
+    }
+}
@@ -0,0 +1,535 @@
+// Folder sync: the paths picked for sending are kept mirrored on a peer. A watcher notices changes on disk, and once things
+// have been quiet for a moment the paths are scanned again. Only files that are new or differ from what the peer got last
+// time go out, along with the paths of files that are gone. Whether the peer deletes those is up to `Deletions`.
+// The receiving end only ever deletes files it got through the same sync, so a sync can't be used to clear out a download folder.
+// Sessions that share a folder both ways run on both ends, and send versions along. See shared.rs
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time;
+
+use anyhow::{anyhow, Context, Result};
+use async_std::channel;
+use async_std::task;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::dedup::Duplicates;
+use crate::download;
+use crate::file_processing;
+use crate::hashing::{self, Progress};
+use crate::job::{self, Source};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::server;
+use crate::shared::{Folder, Received};
+use crate::util;
+use crate::version::Version;
+
+// What happens on the peer's end to files that are gone on ours
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Deletions {
+    Keep,   // The peer keeps them. Handy for backups.
+    Mirror, // The peer deletes them as well
+}
+
+// Where a new scan differs from what the peer has
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    pub changed: Vec<usize>,  // Indices into the new manifest
+    pub removed: Vec<String>, // Paths only in the old one
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+pub fn diff(old: &Manifest, new: &Manifest) -> Diff {
+    let known: HashMap<&str, &ManifestEntry> = old
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+    let changed = new
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| known.get(entry.path.as_str()) != Some(entry))
+        .map(|(index, _)| index)
+        .collect();
+
+    let current: HashMap<&str, ()> = new
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_str(), ()))
+        .collect();
+    let removed = old
+        .entries
+        .iter()
+        .filter(|entry| !current.contains_key(entry.path.as_str()))
+        .map(|entry| entry.path.clone())
+        .collect();
+    Diff { changed, removed }
+}
+
+struct Hashed {
+    size: u64,
+    modified: time::SystemTime,
+    hash: blake3::Hash,
+}
+
+// Builds sources like `Source::build`, but remembers the hashes. Files with the same size and modification time as on the
+// last scan aren't read again.
+#[derive(Default)]
+pub struct Scanner {
+    hashes: HashMap<PathBuf, Hashed>,
+}
+
+impl Scanner {
+    // Blocks on the disk, so it runs on a blocking task. Paths that are gone altogether count as empty.
+    pub fn scan(&mut self, paths: &[PathBuf]) -> Result<Source> {
+        let mut found = vec![];
+        let mut stale = vec![]; // Files that need hashing, as they are new or have changed
+        for path in paths.iter().filter(|path| path.exists()) {
+            // Downloads in progress aren't files yet
+            let collected = file_processing::collect_files(path)?
+                .into_iter()
+                .filter(|(file, _)| !download::is_part_file(file));
+            for (file, name) in collected {
+                let metadata = fs::metadata(&file)
+                    .with_context(|| format!("Unable to read \"{}\".", file.display()))?;
+                let modified = metadata
+                    .modified()
+                    .with_context(|| format!("Unable to read \"{}\".", file.display()))?;
+                let hashed = match self.hashes.remove(&file) {
+                    Some(hashed)
+                        if hashed.size == metadata.len() && hashed.modified == modified =>
+                    {
+                        Some(hashed)
+                    }
+                    _ => {
+                        stale.push(file.clone());
+                        None
+                    }
+                };
+                found.push((file, name, modified, hashed));
+            }
+        }
+
+        let digests = hashing::hash_files(&stale, &Progress::default(), false)?;
+        let mut fresh: HashMap<PathBuf, hashing::Digest> = stale.into_iter().zip(digests).collect();
+        let mut hashes = HashMap::new();
+        let mut entries = vec![];
+        let mut files = vec![];
+        for (file, name, modified, hashed) in found {
+            let hashed = match hashed {
+                Some(hashed) => hashed,
+                None => {
+                    let digest = fresh
+                        .remove(&file)
+                        .with_context(|| format!("No hash for \"{}\".", file.display()))?;
+                    Hashed {
+                        size: digest.size,
+                        modified,
+                        hash: digest.hash,
+                    }
+                }
+            };
+            entries.push(ManifestEntry {
+                path: name,
+                size: hashed.size,
+                hash: hashed.hash,
+            });
+            hashes.insert(file.clone(), hashed);
+            files.push(file);
+        }
+        self.hashes = hashes;
+        // Rounds are mostly a few changed files, so duplicates among them aren't worth hashing every block for
+        Ok(Source {
+            manifest: Manifest::new(entries),
+            files,
+            duplicates: Duplicates::default(),
+        })
+    }
+}
+
+// Starts watching the paths. Changes are passed on as `SyncChanged` once there haven't been any for `debounce`.
+// Watching stops when the watcher is dropped.
+fn watch(
+    id: usize,
+    paths: &[PathBuf],
+    debounce: time::Duration,
+    events: channel::Sender<server::Notification>,
+) -> Result<RecommendedWatcher> {
+    let (changes, changed) = channel::bounded(1);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Reading files doesn't change them. Sending them would set this off all the time otherwise.
+        match event {
+            Ok(event) if matches!(event.kind, EventKind::Access(_)) => (),
+            _ => {
+                let _ = changes.try_send(());
+            }
+        }
+    })
+    .with_context(|| String::from("Unable to watch for changes."))?;
+
+    for path in paths {
+        // Editors tend to replace files instead of writing to them, which a watch on the file itself loses track of
+        let (target, mode) = match path.parent() {
+            Some(parent) if !path.is_dir() => (parent, RecursiveMode::NonRecursive),
+            _ => (path.as_path(), RecursiveMode::Recursive),
+        };
+        watcher
+            .watch(target, mode)
+            .with_context(|| format!("Unable to watch \"{}\".", target.display()))?;
+    }
+
+    task::spawn(async move {
+        while changed.recv().await.is_ok() {
+            while let Ok(Ok(())) = async_std::future::timeout(debounce, changed.recv()).await {}
+            if events
+                .send(server::Notification::SyncChanged(id))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+// The next round of a sync: the files to send, what to offer them as, and what the peer has once it's through
+pub struct Round {
+    pub source: Source,
+    pub kind: job::Kind,
+    pub manifest: Manifest,
+}
+
+fn note(manifest: &mut Manifest, entry: &ManifestEntry) {
+    manifest.entries.retain(|known| known.path != entry.path);
+    manifest.entries.push(entry.clone());
+}
+
+fn forget(manifest: &mut Manifest, path: &str) {
+    manifest.entries.retain(|known| known.path != path);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activity {
+    Scanning,
+    Sending,
+    Watching,
+}
+
+impl fmt::Display for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Activity::Scanning => write!(f, "Looking for changes"),
+            Activity::Sending => write!(f, "Sending changes"),
+            Activity::Watching => write!(f, "Up to date"),
+        }
+    }
+}
+
+// Snapshot of a sync, for the UI
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub id: usize,
+    pub address: SocketAddr,
+    pub files: usize,
+    pub total_size: u64,
+    pub activity: Activity,
+    pub two_way: bool,
+}
+
+impl Status {
+    pub fn describe(&self) -> String {
+        format!(
+            "{} files ({})",
+            self.files,
+            util::format_size(self.total_size)
+        )
+    }
+}
+
+// One set of paths kept in sync with one peer
+pub struct Session {
+    pub id: usize,
+    pub peer: usize,
+    pub address: SocketAddr,
+    pub paths: Vec<PathBuf>,
+    scanner: Option<Scanner>,           // Taken while a scan runs
+    sent: Manifest,                     // What the peer has from us, as far as we know
+    pending: Option<(usize, Manifest)>, // The job under way, and what the peer has once it's through
+    dirty: bool,                        // Something changed while a scan or a job was under way
+    shared: Option<Folder>,             // Set when syncing both ways
+    _watcher: RecommendedWatcher,
+}
+
+impl Session {
+    pub fn start(
+        id: usize,
+        peer: usize,
+        address: SocketAddr,
+        paths: Vec<PathBuf>,
+        debounce: time::Duration,
+        events: channel::Sender<server::Notification>,
+    ) -> Result<Session> {
+        let watcher = watch(id, &paths, debounce, events)?;
+        Ok(Session {
+            id,
+            peer,
+            address,
+            paths,
+            scanner: Some(Scanner::default()),
+            sent: Manifest::default(),
+            pending: None,
+            dirty: false,
+            shared: None,
+            _watcher: watcher,
+        })
+    }
+
+    // Syncs the folder both ways. The session's path has to be the folder.
+    pub fn sharing(mut self, folder: Folder) -> Session {
+        self.shared = Some(folder);
+        self
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.shared.is_some()
+    }
+
+    pub fn folder(&self) -> Option<&Folder> {
+        self.shared.as_ref()
+    }
+
+    // Hands out the scanner, unless a scan or a job is already under way. The change is picked up once that is done.
+    pub fn begin_scan(&mut self) -> Option<Scanner> {
+        if self.pending.is_some() || self.scanner.is_none() {
+            self.dirty = true;
+            return None;
+        }
+        self.dirty = false;
+        self.scanner.take()
+    }
+
+    // Returns what to send next, if anything changed
+    pub fn scanned(
+        &mut self,
+        scanner: Scanner,
+        current: Result<Source>,
+        deletions: Deletions,
+    ) -> Result<Option<Round>> {
+        self.scanner = Some(scanner);
+        let current = current?;
+        if let Some(folder) = &mut self.shared {
+            folder.scanned(&current.manifest);
+        }
+        let mut diff = diff(&self.sent, &current.manifest);
+        if let Some(folder) = &self.shared {
+            // Files moved aside for a conflict are about to be replaced, not removed
+            diff.removed.retain(|path| !folder.is_incoming(path));
+        }
+        if diff.is_empty() {
+            return Ok(None);
+        }
+
+        let entries = diff
+            .changed
+            .iter()
+            .map(|index| current.manifest.entries[*index].clone())
+            .collect();
+        let files = diff
+            .changed
+            .iter()
+            .map(|index| current.files[*index].clone())
+            .collect();
+        let manifest = Manifest::new(entries);
+        // Removals are changes like any other when both ends edit the folder, so they always go out
+        let kind = match (&self.shared, deletions) {
+            (Some(folder), _) => job::Kind::Folder(
+                manifest
+                    .entries
+                    .iter()
+                    .map(|entry| folder.version(&entry.path))
+                    .collect(),
+                diff.removed
+                    .into_iter()
+                    .map(|path| {
+                        let version = folder.version(&path);
+                        (path, version)
+                    })
+                    .collect(),
+            ),
+            (None, Deletions::Keep) => job::Kind::Sync(vec![]),
+            (None, Deletions::Mirror) => job::Kind::Sync(diff.removed),
+        };
+        Ok(Some(Round {
+            source: Source {
+                manifest,
+                files,
+                duplicates: Duplicates::default(),
+            },
+            kind,
+            manifest: current.manifest,
+        }))
+    }
+
+    pub fn sending(&mut self, job: usize, manifest: Manifest) {
+        self.pending = Some((job, manifest));
+    }
+
+    pub fn job(&self) -> Option<usize> {
+        self.pending.as_ref().map(|(job, _)| *job)
+    }
+
+    // Failed rounds are tried again on the next change, as the diff is still against what the peer had before
+    pub fn finished(&mut self, delivered: bool) {
+        if let Some((_, manifest)) = self.pending.take() {
+            if delivered {
+                self.sent = manifest;
+            }
+        }
+    }
+
+    // Takes in what the peer sent of a folder shared both ways
+    pub fn receive(
+        &mut self,
+        manifest: &Manifest,
+        versions: &[Version],
+        removed: &[(String, Version)],
+        peer: SocketAddr,
+    ) -> Result<Received> {
+        let folder = self
+            .shared
+            .as_mut()
+            .ok_or_else(|| anyhow!("Not syncing both ways."))?;
+        let received = folder.receive(manifest, versions, removed, peer)?;
+
+        // Both ends have the same now, so there's no point in sending it back
+        for index in &received.unchanged {
+            self.note(&manifest.entries[*index]);
+        }
+        for path in received.removed.iter().chain(&received.kept) {
+            self.forget(path);
+        }
+        Ok(received)
+    }
+
+    // A file the peer sent is in place
+    pub fn arrived(&mut self, entry: &ManifestEntry) {
+        if let Some(folder) = &mut self.shared {
+            folder.arrived(&entry.path);
+            self.note(entry);
+        }
+    }
+
+    fn note(&mut self, entry: &ManifestEntry) {
+        note(&mut self.sent, entry);
+        if let Some((_, manifest)) = &mut self.pending {
+            note(manifest, entry);
+        }
+    }
+
+    fn forget(&mut self, path: &str) {
+        forget(&mut self.sent, path);
+        if let Some((_, manifest)) = &mut self.pending {
+            forget(manifest, path);
+        }
+    }
+
+    // Whether changes came in while busy, so another scan is due
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn status(&self) -> Status {
+        let activity = match (&self.scanner, &self.pending) {
+            (_, Some(_)) => Activity::Sending,
+            (None, None) => Activity::Scanning,
+            (Some(_), None) => Activity::Watching,
+        };
+        Status {
+            id: self.id,
+            address: self.address,
+            files: self.sent.len(),
+            total_size: self.sent.total_size(),
+            activity,
+            two_way: self.shared.is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, content: &str) -> ManifestEntry {
+        ManifestEntry {
+            path: String::from(path),
+            size: content.len() as u64,
+            hash: blake3::hash(content.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn diffs() {
+        let old = Manifest::new(vec![
+            entry("notes/today.md", "sandwiches"),
+            entry("notes/todo.md", "groceries"),
+            entry("notes/yesterday.md", "soup"),
+        ]);
+        let new = Manifest::new(vec![
+            entry("notes/today.md", "sandwiches"),
+            entry("notes/todo.md", "groceries, laundry"),
+            entry("notes/tomorrow.md", "pizza"),
+        ]);
+        assert_eq!(
+            diff(&old, &new),
+            Diff {
+                changed: vec![1, 2],
+                removed: vec![String::from("notes/yesterday.md")],
+            }
+        );
+        assert!(diff(&new, &new).is_empty());
+        assert_eq!(diff(&Manifest::default(), &new).changed, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn scanner() {
+        let directory = util::TempDir::new("sync-scanner");
+        let notes = directory.path().join("notes");
+        fs::create_dir(&notes).unwrap();
+        fs::write(notes.join("today.md"), "sandwiches").unwrap();
+        fs::write(notes.join("todo.md"), "groceries").unwrap();
+
+        let paths = vec![notes.clone()];
+        let mut scanner = Scanner::default();
+        let first = scanner.scan(&paths).unwrap();
+        assert_eq!(
+            first.manifest,
+            Manifest::new(vec![
+                entry("notes/today.md", "sandwiches"),
+                entry("notes/todo.md", "groceries"),
+            ])
+        );
+
+        fs::write(notes.join("todo.md"), "groceries, laundry").unwrap();
+        fs::remove_file(notes.join("today.md")).unwrap();
+        let second = scanner.scan(&paths).unwrap();
+        assert_eq!(
+            second.manifest,
+            Manifest::new(vec![entry("notes/todo.md", "groceries, laundry")])
+        );
+
+        // Once the folder is gone, everything in it is
+        fs::remove_dir_all(&notes).unwrap();
+        assert!(scanner.scan(&paths).unwrap().manifest.is_empty());
+        assert!(scanner.hashes.is_empty());
+    }
+}
@@ -0,0 +1,643 @@
+// Transports carry the byte stream of the peer protocol. The protocol layer doesn't care which one is used,
+// as long as a connection can be split into a reading and a writing half.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use anyhow::{anyhow, Context, Result};
+use async_std::io::prelude::*;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+
+use crate::protocol::{FrameBuffer, Message};
+use crate::zerocopy;
+
+// Application-Layer Protocol Negotiation identifier for QUIC connections
+const ALPN: &[u8] = b"bitgeon";
+// Peers only learn about a QUIC stream once data has been sent on it, so the connecting side announces the stream with this byte
+const STREAM_PREAMBLE: u8 = 0xB1;
+// Wait before accepting again after accepting failed, e.g. because we ran out of file descriptors
+const ACCEPT_BACKOFF: time::Duration = time::Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransportKind {
+    Tcp,
+    Quic,
+}
+
+impl fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportKind::Tcp => write!(f, "TCP"),
+            TransportKind::Quic => write!(f, "QUIC"),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Quic(quinn::Endpoint),
+}
+
+impl Listener {
+    // Binds to the given address. Port 0 lets the OS pick a free port.
+    pub fn bind(kind: TransportKind, address: SocketAddr) -> Result<Listener> {
+        match kind {
+            TransportKind::Tcp => {
+                let listener = std::net::TcpListener::bind(address)
+                    .with_context(|| format!("Unable to bind TCP listener to {}.", address))?;
+                Ok(Listener::Tcp(TcpListener::from(listener)))
+            }
+            TransportKind::Quic => {
+                let endpoint = quinn::Endpoint::server(server_config()?, address)
+                    .with_context(|| format!("Unable to bind QUIC endpoint to {}.", address))?;
+                Ok(Listener::Quic(endpoint))
+            }
+        }
+    }
+
+    pub fn kind(&self) -> TransportKind {
+        match self {
+            Listener::Tcp(_) => TransportKind::Tcp,
+            Listener::Quic(_) => TransportKind::Quic,
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?),
+            Listener::Quic(endpoint) => Ok(endpoint.local_addr()?),
+        }
+    }
+
+    // Waits for the next peer to show up. Setting the connection up is left to `Incoming::establish`, which can take
+    // a while, so acceptor loops run it on a task of its own and go on listening.
+    pub async fn incoming(&self) -> Result<Incoming> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (tcp_stream, _) = listener.accept().await?;
+                Ok(Incoming::Tcp(tcp_stream))
+            }
+            Listener::Quic(endpoint) => {
+                let incoming = endpoint
+                    .accept()
+                    .await
+                    .with_context(|| String::from("QUIC endpoint has shut down."))?;
+                Ok(Incoming::Quic(Box::new(incoming)))
+            }
+        }
+    }
+
+    // For acceptor loops, which can't do anything about errors but wait for them to go away. None once the listener is closed.
+    pub async fn next(&self) -> Option<Incoming> {
+        loop {
+            match self {
+                Listener::Tcp(listener) => match listener.accept().await {
+                    Ok((tcp_stream, _)) => return Some(Incoming::Tcp(tcp_stream)),
+                    Err(_) => task::sleep(ACCEPT_BACKOFF).await,
+                },
+                Listener::Quic(endpoint) => {
+                    return endpoint
+                        .accept()
+                        .await
+                        .map(|incoming| Incoming::Quic(Box::new(incoming)))
+                }
+            }
+        }
+    }
+
+    // Waits for the next incoming connection, for when only one is wanted
+    pub async fn accept(&self, timeout: time::Duration) -> Result<Connection> {
+        self.incoming().await?.establish(timeout).await
+    }
+}
+
+// A peer that has shown up at a listener, but whose connection isn't set up yet
+pub enum Incoming {
+    Tcp(TcpStream),
+    Quic(Box<quinn::Incoming>),
+}
+
+impl Incoming {
+    // Peers that don't get through the handshake and open a stream in time are dropped
+    pub async fn establish(self, timeout: time::Duration) -> Result<Connection> {
+        match self {
+            Incoming::Tcp(tcp_stream) => Connection::from_tcp(tcp_stream),
+            Incoming::Quic(incoming) => async_std::future::timeout(timeout, async {
+                let connection = (*incoming).await?;
+                let (send, mut receive) = connection.accept_bi().await?;
+
+                let mut preamble = [0];
+                receive.read_exact(&mut preamble).await?;
+                if preamble[0] != STREAM_PREAMBLE {
+                    connection.close(1u32.into(), b"Unexpected stream preamble");
+                    return Err(anyhow!("Unexpected stream preamble."));
+                }
+
+                Ok(Connection::from_quic(connection, None, send, receive))
+            })
+            .await
+            .map_err(|_| anyhow!("QUIC peer didn't open a stream in time."))?,
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Quic(endpoint) = self {
+            endpoint.close(0u32.into(), b"");
+        }
+    }
+}
+
+pub struct Connection {
+    kind: TransportKind,
+    address: SocketAddr,
+    reader: Reader,
+    writer: Writer,
+}
+
+impl Connection {
+    pub async fn connect(
+        kind: TransportKind,
+        address: SocketAddr,
+        timeout: time::Duration,
+    ) -> Result<Connection> {
+        async_std::future::timeout(timeout, async {
+            match kind {
+                TransportKind::Tcp => {
+                    let tcp_stream = TcpStream::connect(address)
+                        .await
+                        .with_context(|| format!("Unable to connect to {} via TCP.", address))?;
+                    Connection::from_tcp(tcp_stream)
+                }
+                TransportKind::Quic => {
+                    let local_address = match address {
+                        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+                        SocketAddr::V6(_) => SocketAddr::from(([0; 16], 0)),
+                    };
+                    let mut endpoint = quinn::Endpoint::client(local_address)
+                        .with_context(|| String::from("Unable to create QUIC endpoint."))?;
+                    endpoint.set_default_client_config(client_config()?);
+
+                    let connection = endpoint
+                        .connect(address, "bitgeon")?
+                        .await
+                        .with_context(|| format!("Unable to connect to {} via QUIC.", address))?;
+                    let (mut send, receive) = connection.open_bi().await?;
+                    send.write_all(&[STREAM_PREAMBLE]).await?;
+
+                    Ok(Connection::from_quic(
+                        connection,
+                        Some(endpoint),
+                        send,
+                        receive,
+                    ))
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("Timed out connecting to {} via {}.", address, kind))?
+    }
+
+    // Tries the transports in order of preference and settles for the first one that works
+    pub async fn negotiate(
+        kinds: &[TransportKind],
+        address: SocketAddr,
+        timeout: time::Duration,
+    ) -> Result<Connection> {
+        let mut errors = vec![];
+        for kind in kinds {
+            match Connection::connect(*kind, address, timeout).await {
+                Ok(connection) => return Ok(connection),
+                Err(error) => errors.push(format!("{}: {:#}", kind, error)),
+            }
+        }
+        Err(anyhow!(
+            "Unable to connect to {} ({}).",
+            address,
+            errors.join("; ")
+        ))
+    }
+
+    fn from_tcp(tcp_stream: TcpStream) -> Result<Connection> {
+        tcp_stream
+            .set_nodelay(true)
+            .with_context(|| String::from("Unable to disable Nagle's algorithm."))?;
+        Ok(Connection {
+            kind: TransportKind::Tcp,
+            address: tcp_stream.peer_addr()?,
+            reader: Reader::Tcp(tcp_stream.clone()),
+            writer: Writer::Tcp(tcp_stream),
+        })
+    }
+
+    // Outgoing connections own their endpoint, which has to live as long as the connection
+    fn from_quic(
+        connection: quinn::Connection,
+        endpoint: Option<quinn::Endpoint>,
+        send: quinn::SendStream,
+        receive: quinn::RecvStream,
+    ) -> Connection {
+        Connection {
+            kind: TransportKind::Quic,
+            address: connection.remote_address(),
+            reader: Reader::Quic(receive),
+            writer: Writer::Quic {
+                send,
+                connection,
+                _endpoint: endpoint,
+            },
+        }
+    }
+
+    pub fn kind(&self) -> TransportKind {
+        self.kind
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    // Splits the connection, so reading and writing can happen on separate tasks
+    pub fn split(self) -> (Reader, Writer) {
+        (self.reader, self.writer)
+    }
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} connection to {}", self.kind, self.address)
+    }
+}
+
+pub enum Reader {
+    Tcp(TcpStream),
+    Quic(quinn::RecvStream),
+}
+
+impl Reader {
+    // Waits for data. Returns 0 once the peer has closed the connection.
+    pub async fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        match self {
+            Reader::Tcp(tcp_stream) => Ok(tcp_stream.read(buffer).await?),
+            Reader::Quic(receive) => Ok(receive.read(buffer).await?.unwrap_or(0)),
+        }
+    }
+
+    // Reads until the next complete message we know. For short exchanges that don't need a peer task.
+    pub async fn next_message(&mut self, frames: &mut FrameBuffer) -> Result<Message> {
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            if let Some(payload) = frames.next_frame()? {
+                if let Some(message) = Message::decode_known(&payload)? {
+                    return Ok(message);
+                }
+                continue;
+            }
+            match self.read(&mut buffer).await? {
+                0 => return Err(anyhow!("Connection closed.")),
+                read => frames.extend(&buffer[..read]),
+            }
+        }
+    }
+}
+
+// What goes out on a connection: a whole encoded frame, or a file chunk whose data is still in the file
+#[derive(Clone)]
+pub enum Frame {
+    Encoded(Arc<Vec<u8>>),
+    File {
+        header: Arc<Vec<u8>>, // Everything up to the data. See `protocol::file_chunk_header`
+        file: Arc<File>,
+        offset: u64,
+        length: u64,
+    },
+}
+
+impl Frame {
+    // The whole frame in one buffer. Blocks on the disk for file chunks.
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Frame::Encoded(bytes) => Ok(bytes.to_vec()),
+            Frame::File {
+                header,
+                file,
+                offset,
+                length,
+            } => {
+                let mut frame = header.to_vec();
+                frame.append(&mut zerocopy::read(file, *offset, *length)?);
+                Ok(frame)
+            }
+        }
+    }
+}
+
+pub enum Writer {
+    Tcp(TcpStream),
+    Quic {
+        send: quinn::SendStream,
+        connection: quinn::Connection,
+        _endpoint: Option<quinn::Endpoint>,
+    },
+}
+
+impl Writer {
+    pub async fn write_all(&mut self, buffer: &[u8]) -> Result<()> {
+        match self {
+            Writer::Tcp(tcp_stream) => tcp_stream.write_all(buffer).await?,
+            Writer::Quic { send, .. } => send.write_all(buffer).await?,
+        }
+        Ok(())
+    }
+
+    // File chunks go from the file to the socket without a copy where the connection allows it, and through a buffer otherwise
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        let (header, file, offset, length) = match frame {
+            Frame::Encoded(bytes) => return self.write_all(bytes).await,
+            Frame::File {
+                header,
+                file,
+                offset,
+                length,
+            } => (header, file.clone(), *offset, *length),
+        };
+
+        #[cfg(target_os = "linux")]
+        if let Writer::Tcp(tcp_stream) = self {
+            let socket = zerocopy::duplicate(tcp_stream)?;
+            tcp_stream.write_all(header).await?;
+            task::spawn_blocking(move || zerocopy::send_file(&socket, &file, offset, length))
+                .await
+                .with_context(|| String::from("Unable to send file data."))?;
+            return Ok(());
+        }
+
+        let data = task::spawn_blocking(move || zerocopy::read(&file, offset, length))
+            .await
+            .with_context(|| String::from("Unable to read file data."))?;
+        self.write_all(header).await?;
+        self.write_all(&data).await
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        match self {
+            Writer::Tcp(tcp_stream) => {
+                let _ = tcp_stream.shutdown(std::net::Shutdown::Both);
+            }
+            Writer::Quic { connection, .. } => connection.close(0u32.into(), b""),
+        }
+    }
+}
+
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+// QUIC requires TLS. Every run generates a fresh self-signed certificate, as peers authenticate each other through the shared key rather than certificate authorities.
+fn server_config() -> Result<quinn::ServerConfig> {
+    let certificate = rcgen::generate_simple_self_signed(vec![String::from("bitgeon")])
+        .with_context(|| String::from("Unable to generate TLS certificate."))?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certificate.key_pair.serialize_der(),
+    ));
+
+    let mut crypto = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(vec![certificate.cert.der().clone()], key)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(
+        quinn::crypto::rustls::QuicServerConfig::try_from(crypto)?,
+    )))
+}
+
+fn client_config() -> Result<quinn::ClientConfig> {
+    let provider = crypto_provider();
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    Ok(quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?,
+    )))
+}
+
+// Accepts any certificate, but still checks that the handshake is signed by it. There is nothing to check certificates
+// against: every listener makes one up when it binds (see `server_config`), and links only carry the fingerprint of the
+// shared key, not of a certificate. QUIC therefore only keeps out passive eavesdroppers. Anyone in the path can pose as
+// the peer, just like with plain TCP, as no transport authenticates peers. What does get checked happens further up:
+// chat keys are confirmed by comparing safety codes (see chat.rs), and files from a link are only taken without asking
+// if their manifest has the root hash the link promised, after which every file is checked against its hash (see
+// `Server::receive_offer` and download.rs).
+#[derive(Debug)]
+struct AnyCertificate(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            certificate,
+            signature,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes a message on one end and waits for it to arrive at the other
+    async fn exchange(kind: TransportKind) {
+        let listener = Listener::bind(kind, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        let timeout = time::Duration::from_secs(5);
+        // QUIC handshakes only complete while the listener is accepting
+        let accepting =
+            async_std::task::spawn(
+                async move { listener.accept(time::Duration::from_secs(5)).await },
+            );
+        let client = Connection::connect(kind, address, timeout).await.unwrap();
+        assert_eq!(client.kind(), kind);
+
+        let (_, mut writer) = client.split();
+        writer.write_all(b"Hello").await.unwrap();
+
+        let (mut reader, _writer) = accepting.await.unwrap().split();
+        let mut received = vec![];
+        let mut buffer = [0; 16];
+        while received.len() < 5 {
+            let read = async_std::future::timeout(timeout, reader.read(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            received.extend_from_slice(&buffer[..read]);
+        }
+        assert_eq!(received, b"Hello");
+    }
+
+    #[test]
+    fn tcp() {
+        async_std::task::block_on(exchange(TransportKind::Tcp));
+    }
+
+    #[test]
+    fn quic() {
+        async_std::task::block_on(exchange(TransportKind::Quic));
+    }
+
+    // File frames have to arrive just like encoded ones, whether they went out with sendfile or through a buffer
+    async fn send_file(kind: TransportKind, directory: &std::path::Path) {
+        let path = directory.join(format!("{}", kind));
+        let contents: Vec<u8> = (0..8 << 20).map(|index| (index % 251) as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+        let frame = Frame::File {
+            header: Arc::new(crate::protocol::file_chunk_header(
+                0,
+                1,
+                contents.len() as u32 - 1,
+            )),
+            file: Arc::new(File::open(&path).unwrap()),
+            offset: 1,
+            length: contents.len() as u64 - 1,
+        };
+        let expected = frame.read().unwrap();
+
+        let listener = Listener::bind(kind, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        let timeout = time::Duration::from_secs(5);
+        // The listener has to stay around, or QUIC closes the connections it accepted
+        let accepting = async_std::task::spawn(async move {
+            let connection = listener.accept(time::Duration::from_secs(5)).await;
+            (listener, connection)
+        });
+        let client = Connection::connect(kind, address, timeout).await.unwrap();
+
+        // The sender blocks once the socket is full, so the receiving end has to read at the same time
+        let (_, mut writer) = client.split();
+        let sending = async_std::task::spawn(async move {
+            writer.write_frame(&frame).await.unwrap();
+            writer
+        });
+
+        let (_listener, connection) = accepting.await;
+        let (mut reader, _writer) = connection.unwrap().split();
+        let mut received = vec![];
+        let mut buffer = vec![0; 64 * 1024];
+        while received.len() < expected.len() {
+            let read = async_std::future::timeout(timeout, reader.read(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            received.extend_from_slice(&buffer[..read]);
+        }
+        assert!(received == expected);
+        sending.await;
+    }
+
+    #[test]
+    fn file_frames() {
+        let directory = crate::util::TempDir::new("file_frames");
+        async_std::task::block_on(send_file(TransportKind::Tcp, directory.path()));
+        async_std::task::block_on(send_file(TransportKind::Quic, directory.path()));
+    }
+
+    // A peer that gets through the handshake but never opens a stream is given up on, and doesn't hold up the next one
+    #[test]
+    fn silent_quic_peer() {
+        async_std::task::block_on(async {
+            let listener =
+                Listener::bind(TransportKind::Quic, "127.0.0.1:0".parse().unwrap()).unwrap();
+            let address = listener.local_addr().unwrap();
+            let timeout = time::Duration::from_millis(500);
+
+            let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            endpoint.set_default_client_config(client_config().unwrap());
+            let connecting = endpoint.connect(address, "bitgeon").unwrap();
+            let silent = listener.incoming().await.unwrap();
+            let establishing = async_std::task::spawn(silent.establish(timeout));
+            let _silent = connecting.await.unwrap();
+
+            let connecting =
+                async_std::task::spawn(Connection::connect(TransportKind::Quic, address, timeout));
+            let incoming = listener.incoming().await.unwrap();
+            assert!(incoming.establish(timeout).await.is_ok());
+            assert!(connecting.await.is_ok());
+            assert!(establishing.await.is_err());
+        });
+    }
+
+    // Acceptor loops end with the listener instead of spinning on it
+    #[test]
+    fn closed_listener() {
+        async_std::task::block_on(async {
+            let listener =
+                Listener::bind(TransportKind::Quic, "127.0.0.1:0".parse().unwrap()).unwrap();
+            if let Listener::Quic(endpoint) = &listener {
+                endpoint.close(0u32.into(), b"");
+            }
+            assert!(listener.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn negotiation_falls_back() {
+        // Nothing listens for QUIC here, so the connection has to fall back to TCP
+        let listener = Listener::bind(TransportKind::Tcp, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let connection = async_std::task::block_on(Connection::negotiate(
+            &[TransportKind::Quic, TransportKind::Tcp],
+            listener.local_addr().unwrap(),
+            time::Duration::from_millis(500),
+        ))
+        .unwrap();
+        assert_eq!(connection.kind(), TransportKind::Tcp);
+    }
+}
@@ -1,5 +1,7 @@
+use std::collections::BTreeMap;
 use std::io;
-use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time;
 
 use anyhow::{self, Result};
@@ -7,7 +9,17 @@ use crossterm::{self, event::KeyCode, ExecutableCommand};
 use tui::{self, backend::CrosstermBackend};
 
 use crate::backend;
+use crate::chat;
+use crate::download;
+use crate::hashing;
+use crate::history::{self, History};
+use crate::job;
+use crate::link;
+use crate::peer;
 use crate::server;
+use crate::settings::ServerSettings;
+use crate::shared;
+use crate::sync;
 use crate::util;
 use crate::widget;
 use scene::Scene;
@@ -37,12 +49,19 @@ pub mod data {
 
     #[derive(Clone)]
     pub enum Server {
-        ConnectionInfo {
-            public_ip: Option<IpAddr>,
-            external_port: Option<u16>,
-            status: String,
-            secret_key: String,
-        },
+        Chat(usize, Vec<chat::Line>), // Holds the ID of the peer and the new lines, oldest first
+        Conflicts(Vec<(usize, shared::Conflict)>), // Every conflict that isn't resolved yet, by ID
+        ConnectionInfo(server::ConnectionInfo),
+        DownloadEnded(download::Status), // Sent once, with the outcome
+        Downloads(Vec<download::Status>), // Every download in progress
+        Hashing(Vec<hashing::Status>),   // Every send that is still being hashed
+        HistoryEntry(history::Entry),
+        Jobs(Vec<job::Status>), // Every job that isn't finished yet
+        Offer(server::Offer),
+        OfferWithdrawn(usize), // Holds the ID of the peer, who has gone away
+        PeerStatus(Vec<peer::PeerStatus>),
+        Snippet(server::Snippet),
+        Syncs(Vec<sync::Status>),
     }
 
     impl Data for Backend {}
@@ -92,10 +111,18 @@ pub mod event {
 
 #[derive(Clone)]
 pub enum AppState {
+    Chat,
+    Conflicts,
     EditFiles(StyledPathList),
     End,
+    History,
     Home(String),
     Initialization,
+    Receive,
+    ReceiveOffer(Vec<SocketAddr>), // Holds the addresses of the sender
+    ReceiveProgress(usize),        // Holds the ID of the peer we receive from
+    SendFiles(Vec<PathBuf>),
+    SendText,
 }
 
 pub struct Ui {
@@ -107,8 +134,21 @@ pub struct Ui {
         server::Message<server::data::Ui, server::event::Ui>,
         Message<data::Server, event::Server>,
     >,
+    pub progress: util::Latest<data::Server>, // The latest update of each kind from the server
     pub application_state: AppState,
     pub scene: Scene,
+    pub peers: Vec<peer::PeerStatus>,
+    pub connection_info: Option<server::ConnectionInfo>,
+    pub history: History,
+    pub offers: Vec<server::Offer>, // Waiting for an answer, oldest first
+    pub chats: BTreeMap<usize, Vec<chat::Line>>, // By peer
+    pub snippets: Vec<server::Snippet>, // Not looked at yet, oldest first
+    pub hashing: Vec<hashing::Status>,
+    pub jobs: Vec<job::Status>,
+    pub downloads: Vec<download::Status>,
+    pub ended: BTreeMap<usize, download::Status>, // Outcome of the last download by peer, until another offer is answered
+    pub syncs: Vec<sync::Status>,
+    pub conflicts: Vec<(usize, shared::Conflict)>,
     pub ui_refresh_rate: u128,
     pub clock: time::Instant,
     pub frame_count: u128,
@@ -126,12 +166,32 @@ impl Ui {
             server::Message<server::data::Ui, server::event::Ui>,
             Message<data::Server, event::Server>,
         >,
+        progress: util::Latest<data::Server>,
     ) -> Self {
         Self {
             application,
             server,
+            progress,
             application_state: AppState::Initialization,
-            scene: Scene::Home(scene::Home::new()),
+            scene: Scene::Home(scene::Home::new(
+                None,
+                vec![],
+                scene::Activity::default(),
+                None,
+                None,
+            )),
+            peers: vec![],
+            connection_info: None,
+            history: History::new(ServerSettings::default().history_capacity),
+            offers: vec![],
+            chats: BTreeMap::new(),
+            snippets: vec![],
+            hashing: vec![],
+            jobs: vec![],
+            downloads: vec![],
+            ended: BTreeMap::new(),
+            syncs: vec![],
+            conflicts: vec![],
             ui_refresh_rate: 60,
             clock: time::Instant::now(),
             frame_count: 0,
@@ -149,10 +209,11 @@ impl Ui {
 
         // TODO: Figure out what is happening here and document that in a comment
         while std::mem::discriminant(&self.scene) != std::mem::discriminant(&Scene::End) {
-            self.update();
+            self.update()?;
             if self.frame_changed {
                 // Visual change necessitates redraw
                 self.frame_changed = false;
+                self.last_frame = time::Instant::now();
                 self.draw(&mut terminal)?;
             }
             self.interact()?; // User interaction
@@ -171,34 +232,74 @@ impl Ui {
         &mut self,
         terminal: &mut tui::Terminal<CrosstermBackend<io::Stdout>>,
     ) -> Result<()> {
+        let queues = self.queues();
         match &mut self.scene {
+            Scene::Chat(scene) => scene.draw(terminal),
+            Scene::Conflicts(scene) => scene.draw(terminal),
             Scene::EditFiles(scene) => scene.draw(terminal),
+            Scene::History(scene) => scene.draw(terminal, &queues),
             Scene::Home(scene) => scene.draw(terminal),
-            _ => {
-                todo!();
-                Ok(())
-            }
+            Scene::Receive(scene) => scene.draw(terminal),
+            Scene::ReceiveOffer(scene) => scene.draw(terminal),
+            Scene::ReceiveProgress(scene) => scene.draw(terminal),
+            Scene::SendFiles(scene) => scene.draw(terminal),
+            Scene::SendText(scene) => scene.draw(terminal),
+            // Nothing to show until the backend has picked a scene, or once it's over
+            Scene::End | Scene::Initialization => Ok(()),
         }
     }
 
+    // How far behind the threads are in reading what this one has to do with
+    fn queues(&self) -> Vec<(&'static str, util::QueueMetrics)> {
+        vec![
+            ("To server", self.server.metrics()),
+            ("From server", self.server.incoming_metrics()),
+            ("Progress", self.progress.metrics()),
+            ("To backend", self.application.metrics()),
+            ("From backend", self.application.incoming_metrics()),
+        ]
+    }
+
     pub fn interact(&mut self) -> Result<()> {
         if crossterm::event::poll(time::Duration::from_secs(0))? {
             let event = crossterm::event::read()?;
-            match event {
-                crossterm::event::Event::Key(key) => match key.code {
+            if let crossterm::event::Event::Key(key) = event {
+                match key.code {
                     KeyCode::Backspace
                     | KeyCode::Char(_)
                     | KeyCode::Delete
                     | KeyCode::Down
+                    | KeyCode::End
                     | KeyCode::Enter
+                    | KeyCode::Home
+                    | KeyCode::Left
+                    | KeyCode::Right
+                    | KeyCode::Tab
                     | KeyCode::Up
                     | KeyCode::Esc => {
-                        let message;
-                        match &mut self.scene {
-                            Scene::EditFiles(scene) => message = scene.interact(event)?,
-                            Scene::Home(scene) => message = scene.interact(event)?,
-                            _ => {
-                                todo!();
+                        let message = match &mut self.scene {
+                            Scene::Chat(scene) => scene.interact(event, &self.server)?,
+                            Scene::Conflicts(scene) => scene.interact(event, &self.server)?,
+                            Scene::EditFiles(scene) => scene.interact(event)?,
+                            Scene::History(scene) => scene.interact(event, &self.server)?,
+                            Scene::Home(scene) => scene.interact(event, &self.server)?,
+                            Scene::Receive(scene) => scene.interact(event, &self.server)?,
+                            Scene::ReceiveOffer(scene) => scene.interact(event, &self.server)?,
+                            Scene::ReceiveProgress(scene) => scene.interact(event)?,
+                            Scene::SendFiles(scene) => scene.interact(event, &self.server)?,
+                            Scene::SendText(scene) => scene.interact(event, &self.server)?,
+                            Scene::End | Scene::Initialization => None,
+                        };
+
+                        // The Home scene shows one offer and one text at a time. Once dealt with, the next one is up.
+                        if let Scene::Home(scene) = &mut self.scene {
+                            if scene.offer.is_none() && !self.offers.is_empty() {
+                                self.offers.remove(0);
+                                scene.offer = self.offers.first().cloned();
+                            }
+                            if scene.snippet.is_none() && !self.snippets.is_empty() {
+                                self.snippets.remove(0);
+                                scene.snippet = self.snippets.first().cloned();
                             }
                         }
 
@@ -209,14 +310,13 @@ impl Ui {
                         self.frame_changed = true;
                     }
                     _ => (),
-                },
-                _ => (),
+                }
             }
         }
         Ok(())
     }
 
-    pub fn update(&mut self) {
+    pub fn update(&mut self) -> Result<()> {
         // Get updates from logic of the program. Progress for progress bars for example
         let app_updates = self.application.receive();
 
@@ -227,22 +327,211 @@ impl Ui {
                         event::Backend::StateChange(state) => {
                             self.application_state = state;
                             self.scene = match &self.application_state {
+                                AppState::Chat => Scene::Chat(scene::Chat::new(
+                                    self.peers.clone(),
+                                    self.chats.clone(),
+                                )),
+                                AppState::Conflicts => {
+                                    Scene::Conflicts(scene::Conflicts::new(self.conflicts.clone()))
+                                }
                                 AppState::EditFiles(file_list) => {
                                     Scene::EditFiles(scene::EditFiles::new(file_list.to_owned()))
                                 }
                                 AppState::End => Scene::End,
-                                AppState::Home(connection_info) => {
-                                    Scene::Home(scene::Home::new())
+                                AppState::History => {
+                                    Scene::History(scene::History::new(&self.history))
+                                }
+                                AppState::Home(_connection_info) => Scene::Home(scene::Home::new(
+                                    self.connection_info.clone(),
+                                    self.peers.clone(),
+                                    scene::Activity {
+                                        hashing: self.hashing.clone(),
+                                        jobs: self.jobs.clone(),
+                                        downloads: self.downloads.clone(),
+                                        syncs: self.syncs.clone(),
+                                    },
+                                    self.offers.first().cloned(),
+                                    self.snippets.first().cloned(),
+                                )),
+                                AppState::Initialization => Scene::Initialization,
+                                AppState::Receive => Scene::Receive(scene::Receive::new()),
+                                AppState::ReceiveOffer(addresses) => {
+                                    self.ended.clear();
+                                    let mut scene = scene::ReceiveOffer::new(
+                                        addresses.clone(),
+                                        self.peers.clone(),
+                                        &self.downloads,
+                                    );
+                                    // The offer may be here already. It is answered in the scene, so Home doesn't show it anymore.
+                                    if let Some(index) =
+                                        self.offers.iter().position(|offer| scene.takes(offer))
+                                    {
+                                        scene.set_offer(self.offers.remove(index));
+                                    }
+                                    Scene::ReceiveOffer(scene)
+                                }
+                                AppState::ReceiveProgress(peer) => {
+                                    Scene::ReceiveProgress(scene::ReceiveProgress::new(
+                                        *peer,
+                                        &self.downloads,
+                                        self.ended.get(peer),
+                                    ))
+                                }
+                                AppState::SendFiles(paths) => Scene::SendFiles(
+                                    scene::SendFiles::new(paths.clone(), self.peers.clone()),
+                                ),
+                                AppState::SendText => {
+                                    Scene::SendText(scene::SendText::new(self.peers.clone()))
+                                }
+                            }
+                        }
+                    },
+                    Message::Data(data::Backend::FilePathList(file_paths)) => {
+                        if let Scene::EditFiles(scene) = &mut self.scene {
+                            scene.file_paths = file_paths;
+                        }
+                    }
+                }
+            }
+            self.frame_changed = true;
+        }
+
+        // Get updates from the server. Connection health for example.
+        // Progress first, since it may have been sent before the state changes that came in on the other queue.
+        let mut server_updates: Vec<_> = self
+            .progress
+            .receive()
+            .into_iter()
+            .map(Message::Data)
+            .collect();
+        server_updates.extend(self.server.receive());
+
+        if !server_updates.is_empty() {
+            for message in server_updates {
+                match message {
+                    Message::Data(data::Server::PeerStatus(peers)) => {
+                        match &mut self.scene {
+                            Scene::Home(scene) => scene.peers = peers.clone(),
+                            Scene::Chat(scene) => scene.set_peers(peers.clone()),
+                            Scene::SendFiles(scene) => scene.set_peers(peers.clone()),
+                            Scene::SendText(scene) => scene.set_peers(peers.clone()),
+                            Scene::ReceiveOffer(scene) => scene.peers = peers.clone(),
+                            _ => (),
+                        }
+                        // Conversations with peers that are gone live on in the chat history only
+                        self.chats
+                            .retain(|id, _| peers.iter().any(|peer| peer.id == *id));
+                        self.peers = peers;
+                    }
+                    Message::Data(data::Server::Chat(peer, lines)) => {
+                        if let Scene::Chat(scene) = &mut self.scene {
+                            scene.chats.entry(peer).or_default().extend(lines.clone());
+                        }
+                        self.chats.entry(peer).or_default().extend(lines);
+                    }
+                    Message::Data(data::Server::Conflicts(conflicts)) => {
+                        if let Scene::Conflicts(scene) = &mut self.scene {
+                            scene.set_conflicts(conflicts.clone());
+                        }
+                        self.conflicts = conflicts;
+                    }
+                    Message::Data(data::Server::ConnectionInfo(connection_info)) => {
+                        if let Scene::Home(scene) = &mut self.scene {
+                            scene.connection_info = Some(connection_info.clone());
+                        }
+                        self.connection_info = Some(connection_info);
+                    }
+                    Message::Data(data::Server::HistoryEntry(entry)) => {
+                        match &mut self.scene {
+                            Scene::History(scene) => scene.push(&entry),
+                            Scene::ReceiveOffer(scene) => scene.push(&entry),
+                            _ => (),
+                        }
+                        self.history.push(entry);
+                    }
+                    Message::Data(data::Server::Offer(offer)) => match &mut self.scene {
+                        Scene::ReceiveOffer(scene) if scene.takes(&offer) => scene.set_offer(offer),
+                        scene => {
+                            if let Scene::Home(scene) = scene {
+                                if scene.offer.is_none() {
+                                    scene.offer = Some(offer.clone());
                                 }
-                                AppState::Initialization => todo!(),
                             }
+                            self.offers.push(offer);
                         }
                     },
-                    _ => todo!(),
+                    Message::Data(data::Server::DownloadEnded(status)) => {
+                        if let Scene::ReceiveProgress(scene) = &mut self.scene {
+                            scene.ended(&status);
+                        }
+                        self.ended.insert(status.peer, status);
+                    }
+                    Message::Data(data::Server::Downloads(downloads)) => {
+                        match &mut self.scene {
+                            Scene::Home(scene) => scene.downloads = downloads.clone(),
+                            Scene::ReceiveProgress(scene) => scene.update(&downloads),
+                            // Offers that match the link are accepted without asking, so the download just starts
+                            Scene::ReceiveOffer(scene) => {
+                                if let Some(peer) = scene.started(&downloads) {
+                                    self.application.send(backend::Message::Event(
+                                        backend::event::Ui::Receiving(peer),
+                                    ))?;
+                                }
+                            }
+                            _ => (),
+                        }
+                        self.downloads = downloads;
+                    }
+                    Message::Data(data::Server::Hashing(hashing)) => {
+                        if let Scene::Home(scene) = &mut self.scene {
+                            scene.hashing = hashing.clone();
+                        }
+                        self.hashing = hashing;
+                    }
+                    Message::Data(data::Server::Jobs(jobs)) => {
+                        if let Scene::Home(scene) = &mut self.scene {
+                            scene.jobs = jobs.clone();
+                        }
+                        self.jobs = jobs;
+                    }
+                    Message::Data(data::Server::Syncs(syncs)) => {
+                        if let Scene::Home(scene) = &mut self.scene {
+                            scene.syncs = syncs.clone();
+                        }
+                        self.syncs = syncs;
+                    }
+                    Message::Data(data::Server::Snippet(snippet)) => {
+                        if let Scene::Home(scene) = &mut self.scene {
+                            if scene.snippet.is_none() {
+                                scene.snippet = Some(snippet.clone());
+                            }
+                        }
+                        self.snippets.push(snippet);
+                    }
+                    Message::Data(data::Server::OfferWithdrawn(peer)) => {
+                        if let Scene::ReceiveOffer(scene) = &mut self.scene {
+                            scene.withdrawn(peer);
+                        }
+                        self.offers.retain(|offer| offer.peer != peer);
+                        if let Scene::Home(scene) = &mut self.scene {
+                            if scene.offer.as_ref().map(|offer| offer.peer) == Some(peer) {
+                                scene.offer = self.offers.first().cloned();
+                            }
+                        }
+                    }
+                    Message::Event(event) => match event {},
                 }
             }
             self.frame_changed = true;
         }
+
+        // The Home scene counts down the lifetime of the port mapping, so it needs a fresh frame every now and then
+        if let Scene::Home(_) = self.scene {
+            if self.last_frame.elapsed() >= time::Duration::from_secs(1) {
+                self.frame_changed = true;
+            }
+        }
+        Ok(())
     }
 
     pub fn period_elapsed(&self, count: &u64, rate: &u16) -> bool {
@@ -262,40 +551,86 @@ mod scene {
     use tui::{
         self,
         backend::CrosstermBackend,
-        layout::{Constraint, Direction, Layout},
+        layout::{Constraint, Direction, Layout, Rect},
         style,
-        widgets::{Block, Borders, List, ListItem},
+        widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Wrap},
     };
 
     // use crate::backend;
+    use crate::download;
+    use crate::peer;
     // use crate::widget;
 
-    use widget::{ScrollList, StyledPathList};
+    use widget::{ScrollList, StyledPathList, TextEditor};
 
+    #[allow(clippy::large_enum_variant)] // There is only ever one scene around
     pub enum Scene {
+        Chat(Chat),
+        Conflicts(Conflicts),
         EditFiles(EditFiles),
         End,
+        History(History),
         Home(Home),
         Initialization,
+        Receive(Receive),
+        ReceiveOffer(ReceiveOffer),
+        ReceiveProgress(ReceiveProgress),
+        SendFiles(SendFiles),
+        SendText(SendText),
+    }
+
+    // What is going on in the background, shown on Home below the menu
+    #[derive(Clone, Default)]
+    pub struct Activity {
+        pub hashing: Vec<hashing::Status>,
+        pub jobs: Vec<job::Status>,
+        pub downloads: Vec<download::Status>,
+        pub syncs: Vec<sync::Status>,
     }
 
     pub struct Home {
         pub menu: ScrollList,
-        pub connection_info: Option<String>,
+        pub connection_info: Option<server::ConnectionInfo>,
+        pub peers: Vec<peer::PeerStatus>,
+        pub hashing: Vec<hashing::Status>,
+        pub jobs: Vec<job::Status>,
+        pub downloads: Vec<download::Status>,
+        pub syncs: Vec<sync::Status>,
+        pub offer: Option<server::Offer>, // Shown in a dialog on top of everything else
+        pub snippet: Option<server::Snippet>, // Shown in a dialog, unless there is an offer
     }
 
     impl Home {
-        pub fn new() -> Home {
+        pub fn new(
+            connection_info: Option<server::ConnectionInfo>,
+            peers: Vec<peer::PeerStatus>,
+            activity: Activity,
+            offer: Option<server::Offer>,
+            snippet: Option<server::Snippet>,
+        ) -> Home {
             let mut scene = Home {
                 menu: ScrollList::new(
                     String::from("Choose an option:"),
                     vec![
                         String::from("Add or remove files"),
+                        String::from("Send files"),
                         String::from("Receive"),
+                        String::from("Send text"),
+                        String::from("Chat"),
+                        String::from("Refresh connection"),
+                        String::from("Show status history"),
+                        String::from("Resolve conflicts"),
                         String::from("End"),
                     ],
                 ),
-                connection_info: None,
+                connection_info,
+                peers,
+                hashing: activity.hashing,
+                jobs: activity.jobs,
+                downloads: activity.downloads,
+                syncs: activity.syncs,
+                offer,
+                snippet,
             };
             scene.menu.next();
             scene
@@ -304,11 +639,47 @@ mod scene {
         pub fn interact(
             &mut self,
             event: crossterm::event::Event,
+            server: &util::ThreadChannel<
+                server::Message<server::data::Ui, server::event::Ui>,
+                Message<data::Server, event::Server>,
+            >,
         ) -> Result<Option<backend::Message<backend::data::Ui, backend::event::Ui>>> {
             if let crossterm::event::Event::Key(event) = event {
+                // An open offer dialog takes all input until it is answered
+                if let Some(offer) = &self.offer {
+                    let accept = match event.code {
+                        KeyCode::Char('y') if offer.acceptable() => true,
+                        KeyCode::Char('n') | KeyCode::Esc => false,
+                        _ => return Ok(None),
+                    };
+                    server.send(server::Message::Event(server::event::Ui::AnswerOffer {
+                        peer: offer.peer,
+                        accept,
+                        directory: None,
+                    }))?;
+                    self.offer = None;
+                    return Ok(None);
+                }
+
+                // Text is gone once the dialog is closed, unless it has been saved
+                if let Some(snippet) = &self.snippet {
+                    match event.code {
+                        KeyCode::Char('s') => server.send(server::Message::Event(
+                            server::event::Ui::SaveSnippet(snippet.clone()),
+                        ))?,
+                        KeyCode::Esc => (),
+                        _ => return Ok(None),
+                    }
+                    self.snippet = None;
+                    return Ok(None);
+                }
+
                 match event.code {
                     KeyCode::Up => self.menu.previous(),
                     KeyCode::Down => self.menu.next(),
+                    KeyCode::Char('x') if !self.hashing.is_empty() => {
+                        server.send(server::Message::Event(server::event::Ui::CancelHashing))?
+                    }
                     KeyCode::Enter => {
                         if let Some(option) = self.menu.state.selected() {
                             return Ok(Some(backend::Message::Event(
@@ -331,7 +702,7 @@ mod scene {
                 let split_horizontal = Layout::default()
                     .direction(Direction::Vertical)
                     .margin(1)
-                    .constraints([Constraint::Percentage(90), Constraint::Percentage(10)].as_ref())
+                    .constraints([Constraint::Min(0), Constraint::Length(7)].as_ref())
                     .split(f.size());
 
                 let split_vertical = Layout::default()
@@ -366,14 +737,1120 @@ mod scene {
                 // f.render_widget(menu_frame, split_vertical[0]);
                 f.render_stateful_widget(menu, split_vertical[0], &mut self.menu.state);
 
-                let info = Block::default().title("Info").borders(Borders::ALL);
+                let info = List::new(self.connection_details())
+                    .block(Block::default().title("Info").borders(Borders::ALL));
                 f.render_widget(info, split_horizontal[1]);
 
-                let sending = Block::default().title("Sending").borders(Borders::ALL);
+                let mut sending = self.peer_health(peer::Direction::Incoming);
+                sending.extend(self.hashing_progress());
+                sending.extend(self.job_progress());
+                sending.extend(self.sync_progress());
+                let title = match self.hashing.is_empty() {
+                    true => "Sending",
+                    false => "Sending | X: Cancel hashing",
+                };
+                let sending =
+                    List::new(sending).block(Block::default().title(title).borders(Borders::ALL));
                 f.render_widget(sending, split_horizontal_1[0]);
 
-                let receiving = Block::default().title("Receiving").borders(Borders::ALL);
+                let mut receiving = self.peer_health(peer::Direction::Outgoing);
+                receiving.extend(self.download_progress());
+                let receiving = List::new(receiving)
+                    .block(Block::default().title("Receiving").borders(Borders::ALL));
                 f.render_widget(receiving, split_horizontal_1[1]);
+
+                if let Some(snippet) = self.snippet.as_ref().filter(|_| self.offer.is_none()) {
+                    let area = centered(f.size(), 80, 20);
+                    let dialog = Paragraph::new(snippet.text.as_str())
+                        .wrap(Wrap { trim: false })
+                        .block(Block::default().borders(Borders::ALL).title(format!(
+                            "Text from {} | S: Save to file | Esc: Close",
+                            snippet.address
+                        )));
+                    f.render_widget(Clear, area);
+                    f.render_widget(dialog, area);
+                }
+
+                if let Some(offer) = &self.offer {
+                    let area = centered(f.size(), 70, 6);
+                    let dialog = List::new(offer_details(offer)).block(
+                        Block::default()
+                            .title("Incoming offer")
+                            .borders(Borders::ALL),
+                    );
+                    f.render_widget(Clear, area);
+                    f.render_widget(dialog, area);
+                }
+            })?;
+            Ok(())
+        }
+
+        fn connection_details(&self) -> Vec<ListItem<'static>> {
+            let connection_info = match &self.connection_info {
+                Some(connection_info) => connection_info,
+                None => return vec![ListItem::new("Looking up connection details...")],
+            };
+
+            let lease = match connection_info.lease_expiry {
+                Some(expiry) => {
+                    let remaining = expiry.saturating_duration_since(time::Instant::now());
+                    format!(
+                        "Port mapping expires in {}:{:02}",
+                        remaining.as_secs() / 60,
+                        remaining.as_secs() % 60
+                    )
+                }
+                None => String::from("No port mapping"),
+            };
+
+            let self_test = match &connection_info.self_test {
+                Some(report) => format!("Reachability: {}", report),
+                None => String::from("Reachability: Not tested"),
+            };
+
+            vec![
+                ListItem::new(format!("Address: {}", connection_info.reachability)),
+                ListItem::new(format!("Status: {}", connection_info.status)),
+                ListItem::new(lease),
+                ListItem::new(self_test),
+                ListItem::new(format!("Key: {}", connection_info.secret_key)),
+                ListItem::new(match &connection_info.link {
+                    Some(link) => format!("Link: {}", link),
+                    None => String::from("Link: Not reachable yet"),
+                }),
+            ]
+        }
+
+        // One line of connection health per peer. Incoming peers receive from us, so they belong in the "Sending" panel.
+        fn peer_health(&self, direction: peer::Direction) -> Vec<ListItem<'static>> {
+            self.peers
+                .iter()
+                .filter(|peer| peer.direction == direction)
+                .map(|peer| {
+                    let round_trip_time = match peer.round_trip_time {
+                        Some(duration) => format!("{} ms", duration.as_millis()),
+                        None => String::from("-"),
+                    };
+                    let color = match peer.health {
+                        peer::ConnectionHealth::Healthy => style::Color::Green,
+                        peer::ConnectionHealth::Unresponsive
+                        | peer::ConnectionHealth::Reconnecting(_) => style::Color::Yellow,
+                        peer::ConnectionHealth::Dead => style::Color::Red,
+                    };
+                    let version = match peer.agreement {
+                        Some(agreement) => format!("v{}", agreement.version),
+                        None => String::from("v?"),
+                    };
+                    ListItem::new(format!(
+                        "{} ({}, {}) | {} | RTT {}",
+                        peer.address, peer.transport, version, peer.health, round_trip_time
+                    ))
+                    .style(style::Style::default().fg(color))
+                })
+                .collect()
+        }
+
+        // Sends can't be offered before all of their files are hashed
+        fn hashing_progress(&self) -> Vec<ListItem<'static>> {
+            self.hashing
+                .iter()
+                .map(|status| {
+                    ListItem::new(format!(
+                        "Hashing {} files ({}) | {}%",
+                        status.files,
+                        util::format_size(status.total_size),
+                        status.percent()
+                    ))
+                })
+                .collect()
+        }
+
+        // One line per recipient of each job
+        fn job_progress(&self) -> Vec<ListItem<'static>> {
+            self.jobs
+                .iter()
+                .flat_map(|job| {
+                    job.recipients.iter().map(move |recipient| {
+                        let percent = match job.total_size {
+                            0 => 100,
+                            total => recipient.sent * 100 / total,
+                        };
+                        let color = match recipient.state {
+                            job::State::Failed(_) => style::Color::Red,
+                            job::State::Declined => style::Color::Yellow,
+                            _ => style::Color::Reset,
+                        };
+                        ListItem::new(format!(
+                            "{} | {} | {} | {}%",
+                            recipient.address,
+                            job.describe(),
+                            recipient.state,
+                            percent
+                        ))
+                        .style(style::Style::default().fg(color))
+                    })
+                })
+                .collect()
+        }
+
+        fn download_progress(&self) -> Vec<ListItem<'static>> {
+            self.downloads
+                .iter()
+                .filter(|download| download.state == download::State::Receiving)
+                .map(|download| {
+                    ListItem::new(format!(
+                        "{} | {} of {} files ({}) | {}%",
+                        download.address,
+                        download.completed,
+                        download.files,
+                        util::format_size(download.total_size),
+                        download.percent()
+                    ))
+                })
+                .collect()
+        }
+
+        fn sync_progress(&self) -> Vec<ListItem<'static>> {
+            self.syncs
+                .iter()
+                .map(|sync| {
+                    ListItem::new(format!(
+                        "{} | Keeping {} in sync{} | {}",
+                        sync.address,
+                        sync.describe(),
+                        if sync.two_way { " both ways" } else { "" },
+                        sync.activity
+                    ))
+                })
+                .collect()
+        }
+    }
+
+    fn offer_details(offer: &server::Offer) -> Vec<ListItem<'static>> {
+        let help = if offer.acceptable() {
+            "Y: Accept | N: Decline"
+        } else {
+            "Not enough space. N: Decline"
+        };
+        let mut lines = offer_summary(offer);
+        lines.extend(vec![ListItem::new(""), ListItem::new(help)]);
+        lines
+    }
+
+    // Who offers what, and whether it fits
+    fn offer_summary(offer: &server::Offer) -> Vec<ListItem<'static>> {
+        let (space, color) = match &offer.preflight {
+            Some(preflight) => (
+                preflight.to_string(),
+                match preflight.space() {
+                    download::Space::Sufficient => style::Color::Green,
+                    download::Space::Tight => style::Color::Yellow,
+                    download::Space::Insufficient => style::Color::Red,
+                },
+            ),
+            None => (
+                String::from("Unable to check free space"),
+                style::Color::Yellow,
+            ),
+        };
+        let what = match (offer.sync, offer.two_way) {
+            (true, true) => "wants to share",
+            (true, false) => "wants to keep",
+            (false, _) => "offers",
+        };
+        let mut lines = vec![ListItem::new(format!(
+            "{} {} {} files ({})",
+            offer.address,
+            what,
+            offer.files,
+            util::format_size(offer.total_size)
+        ))];
+        if offer.two_way {
+            lines.push(ListItem::new(
+                "both ways. Changes on either end reach the other, and conflicting edits are kept as copies",
+            ));
+        } else if offer.sync {
+            lines.push(ListItem::new(
+                "in sync. Later changes come in without asking, and files removed on their end are removed here",
+            ));
+        }
+        lines.push(ListItem::new(space).style(style::Style::default().fg(color)));
+        lines
+    }
+
+    // Area of the given size in the middle of the screen, shrunk to fit if need be
+    fn centered(area: Rect, width: u16, height: u16) -> Rect {
+        let width = width.min(area.width);
+        let height = height.min(area.height);
+        Rect::new(
+            area.x + (area.width - width) / 2,
+            area.y + (area.height - height) / 2,
+            width,
+            height,
+        )
+    }
+
+    // Conversation with one of the connected peers at a time
+    pub struct Chat {
+        pub peers: Vec<peer::PeerStatus>,
+        pub chats: BTreeMap<usize, Vec<chat::Line>>,
+        pub selected: usize, // Index into the peers
+        pub input: String,
+    }
+
+    impl Chat {
+        pub fn new(peers: Vec<peer::PeerStatus>, chats: BTreeMap<usize, Vec<chat::Line>>) -> Chat {
+            Chat {
+                peers,
+                chats,
+                selected: 0,
+                input: String::new(),
+            }
+        }
+
+        pub fn set_peers(&mut self, peers: Vec<peer::PeerStatus>) {
+            self.selected = follow_selection(&self.peers, self.selected, &peers);
+            self.peers = peers;
+        }
+
+        pub fn interact(
+            &mut self,
+            event: crossterm::event::Event,
+            server: &util::ThreadChannel<
+                server::Message<server::data::Ui, server::event::Ui>,
+                Message<data::Server, event::Server>,
+            >,
+        ) -> Result<Option<backend::Message<backend::data::Ui, backend::event::Ui>>> {
+            if let crossterm::event::Event::Key(event) = event {
+                let control = event
+                    .modifiers
+                    .contains(crossterm::event::KeyModifiers::CONTROL);
+                match event.code {
+                    // Follows the newest link the peer has sent
+                    KeyCode::Char('o') if control => {
+                        let link = self
+                            .peers
+                            .get(self.selected)
+                            .and_then(|peer| self.chats.get(&peer.id))
+                            .and_then(|lines| {
+                                lines
+                                    .iter()
+                                    .rev()
+                                    .filter(|line| line.author == chat::Author::Peer)
+                                    .find_map(|line| link::find_links(&line.text).pop())
+                            });
+                        if let Some(link) = link {
+                            server
+                                .send(server::Message::Event(server::event::Ui::OpenLink(link)))?;
+                        }
+                    }
+                    KeyCode::Up if !self.peers.is_empty() => {
+                        self.selected = (self.selected + self.peers.len() - 1) % self.peers.len()
+                    }
+                    KeyCode::Down if !self.peers.is_empty() => {
+                        self.selected = (self.selected + 1) % self.peers.len()
+                    }
+                    KeyCode::Char(character) => self.input.push(character),
+                    KeyCode::Backspace => {
+                        self.input.pop();
+                    }
+                    KeyCode::Enter if !self.input.trim().is_empty() => {
+                        if let Some(peer) = self.peers.get(self.selected) {
+                            server.send(server::Message::Event(server::event::Ui::Chat {
+                                peer: peer.id,
+                                text: std::mem::take(&mut self.input),
+                            }))?;
+                        }
+                    }
+                    KeyCode::Esc => {
+                        return Ok(Some(backend::Message::Event(backend::event::Ui::Back)))
+                    }
+                    _ => (),
+                }
+            }
+            Ok(None)
+        }
+
+        pub fn draw(
+            &mut self,
+            terminal: &mut tui::Terminal<CrosstermBackend<io::Stdout>>,
+        ) -> Result<()> {
+            terminal.draw(|f| {
+                let split_horizontal = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints([Constraint::Min(0), Constraint::Length(3)].as_ref())
+                    .split(f.size());
+
+                let split_vertical = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(30), Constraint::Percentage(70)].as_ref())
+                    .split(split_horizontal[0]);
+
+                let split_conversation = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Min(0), Constraint::Length(3)].as_ref())
+                    .split(split_vertical[1]);
+
+                let style = style::Style::default();
+                let peers: Vec<ListItem> = self
+                    .peers
+                    .iter()
+                    .map(|peer| ListItem::new(peer.address.to_string()))
+                    .collect();
+                let mut state = tui::widgets::ListState::default();
+                if !self.peers.is_empty() {
+                    state.select(Some(self.selected));
+                }
+                let peers = List::new(peers)
+                    .block(Block::default().borders(Borders::ALL).title("Peers"))
+                    .highlight_style(
+                        style
+                            .fg(style::Color::Rgb(253, 3, 166))
+                            .add_modifier(style::Modifier::BOLD),
+                    )
+                    .highlight_symbol("> ");
+                f.render_stateful_widget(peers, split_vertical[0], &mut state);
+
+                // Only the newest messages that fit are shown
+                let peer = self.peers.get(self.selected);
+                let lines = peer
+                    .and_then(|peer| self.chats.get(&peer.id))
+                    .map(Vec::as_slice)
+                    .unwrap_or_default();
+                let height = split_conversation[0].height.saturating_sub(2) as usize;
+                let messages: Vec<ListItem> = lines[lines.len().saturating_sub(height)..]
+                    .iter()
+                    .map(|line| {
+                        let color = match line.author {
+                            chat::Author::Us => style::Color::Reset,
+                            chat::Author::Peer => style::Color::Cyan,
+                        };
+                        ListItem::new(line.to_string()).style(style.fg(color))
+                    })
+                    .collect();
+                let title = match peer {
+                    Some(peer) => match &peer.safety_code {
+                        Some(safety_code) => {
+                            format!("{} | Safety code {}", peer.address, safety_code)
+                        }
+                        None => format!("{} | Setting up encryption...", peer.address),
+                    },
+                    None => String::from("No peers connected"),
+                };
+                let messages =
+                    List::new(messages).block(Block::default().borders(Borders::ALL).title(title));
+                f.render_widget(messages, split_conversation[0]);
+
+                let input = List::new(vec![ListItem::new(format!("{}_", self.input))])
+                    .block(Block::default().borders(Borders::ALL).title("Message"));
+                f.render_widget(input, split_conversation[1]);
+
+                let help = List::new(vec![ListItem::new(
+                    "Up/Down: Choose peer | Enter: Send | Ctrl+O: Follow link | Esc: Back | Compare the safety code with your peer to make sure nobody listens in",
+                )])
+                .block(Block::default().borders(Borders::ALL));
+                f.render_widget(help, split_horizontal[1]);
+            })?;
+            Ok(())
+        }
+    }
+
+    // Stays with the selected peer, if it is still around
+    fn follow_selection(
+        peers: &[peer::PeerStatus],
+        selected: usize,
+        new_peers: &[peer::PeerStatus],
+    ) -> usize {
+        let selected = peers.get(selected).map(|peer| peer.id);
+        new_peers
+            .iter()
+            .position(|peer| Some(peer.id) == selected)
+            .unwrap_or(0)
+    }
+
+    // Asks for the sender's connection details. A bitgeon:// link, or text containing one, can be pasted right in.
+    pub struct Receive {
+        pub input: String,
+        pub error: Option<String>,
+    }
+
+    impl Receive {
+        pub fn new() -> Receive {
+            Receive {
+                input: String::new(),
+                error: None,
+            }
+        }
+
+        pub fn interact(
+            &mut self,
+            event: crossterm::event::Event,
+            server: &util::ThreadChannel<
+                server::Message<server::data::Ui, server::event::Ui>,
+                Message<data::Server, event::Server>,
+            >,
+        ) -> Result<Option<backend::Message<backend::data::Ui, backend::event::Ui>>> {
+            if let crossterm::event::Event::Key(event) = event {
+                match event.code {
+                    KeyCode::Char(character) => self.input.push(character),
+                    KeyCode::Backspace => {
+                        self.input.pop();
+                    }
+                    KeyCode::Enter => {
+                        let (event, addresses) = match link::find_links(&self.input)
+                            .into_iter()
+                            .next()
+                        {
+                            Some(link) => {
+                                let addresses = link.addresses.clone();
+                                (server::event::Ui::OpenLink(link), addresses)
+                            }
+                            None => match self.input.trim().parse() {
+                                Ok(address) => (server::event::Ui::Connect(address), vec![address]),
+                                Err(_) => {
+                                    // Say what is wrong with the link, if it is meant to be one
+                                    self.error = Some(match link::Link::parse(&self.input) {
+                                        Err(link::LinkError::Scheme) | Ok(_) => String::from(
+                                            "Enter a bitgeon:// link or an address like 203.0.113.7:31415",
+                                        ),
+                                        Err(error) => error.to_string(),
+                                    });
+                                    return Ok(None);
+                                }
+                            },
+                        };
+                        server.send(server::Message::Event(event))?;
+                        return Ok(Some(backend::Message::Event(
+                            backend::event::Ui::Connecting(addresses),
+                        )));
+                    }
+                    KeyCode::Esc => {
+                        return Ok(Some(backend::Message::Event(backend::event::Ui::Back)))
+                    }
+                    _ => (),
+                }
+            }
+            Ok(None)
+        }
+
+        pub fn draw(
+            &mut self,
+            terminal: &mut tui::Terminal<CrosstermBackend<io::Stdout>>,
+        ) -> Result<()> {
+            terminal.draw(|f| {
+                let split = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints(
+                        [
+                            Constraint::Length(3),
+                            Constraint::Min(0),
+                            Constraint::Length(3),
+                        ]
+                        .as_ref(),
+                    )
+                    .split(f.size());
+
+                let input = Paragraph::new(format!("{}_", self.input))
+                    .wrap(Wrap { trim: false })
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .title("Link or address"),
+                    );
+                f.render_widget(input, split[0]);
+
+                let message = match &self.error {
+                    Some(error) => ListItem::new(error.clone())
+                        .style(style::Style::default().fg(style::Color::Red)),
+                    None => {
+                        ListItem::new("Paste the link the sender has given you, e.g. in the chat")
+                    }
+                };
+                let message =
+                    List::new(vec![message]).block(Block::default().borders(Borders::ALL));
+                f.render_widget(message, split[1]);
+
+                let help = List::new(vec![ListItem::new("Enter: Connect | Esc: Back")])
+                    .block(Block::default().borders(Borders::ALL));
+                f.render_widget(help, split[2]);
+            })?;
+            Ok(())
+        }
+    }
+
+    // Waits for the sender to connect and make an offer. Accepting it here lets the user pick where the files go.
+    pub struct ReceiveOffer {
+        pub addresses: Vec<SocketAddr>, // Of the sender, as they were entered
+        pub peers: Vec<peer::PeerStatus>,
+        pub offer: Option<server::Offer>,
+        pub directory: String, // Filled in with the download directory once the offer is here
+        pub events: Vec<String>, // What happened with the connection so far, oldest first
+        pub downloading: Vec<usize>, // Peers that were sending already, so their downloads aren't taken for this one
+        pub error: Option<String>,
+    }
+
+    impl ReceiveOffer {
+        pub fn new(
+            addresses: Vec<SocketAddr>,
+            peers: Vec<peer::PeerStatus>,
+            downloads: &[download::Status],
+        ) -> ReceiveOffer {
+            ReceiveOffer {
+                addresses,
+                peers,
+                offer: None,
+                directory: String::new(),
+                events: vec![],
+                downloading: downloads.iter().map(|download| download.peer).collect(),
+                error: None,
+            }
+        }
+
+        // Syncs always go to the download directory, so they are answered on Home like before
+        pub fn takes(&self, offer: &server::Offer) -> bool {
+            !offer.sync && self.offer.is_none() && self.addresses.contains(&offer.address)
+        }
+
+        pub fn set_offer(&mut self, offer: server::Offer) {
+            self.directory = offer.directory.display().to_string();
+            self.offer = Some(offer);
+            self.error = None;
+        }
+
+        pub fn withdrawn(&mut self, peer: usize) {
+            if self.offer.as_ref().map(|offer| offer.peer) == Some(peer) {
+                self.offer = None;
+                self.error = Some(String::from("The sender has gone away"));
+            }
+        }
+
+        // The peer whose download has just started without asking, if any
+        pub fn started(&self, downloads: &[download::Status]) -> Option<usize> {
+            if self.offer.is_some() {
+                return None;
+            }
+            downloads
+                .iter()
+                .find(|download| {
+                    download.state == download::State::Receiving
+                        && self.addresses.contains(&download.address)
+                        && !self.downloading.contains(&download.peer)
+                })
+                .map(|download| download.peer)
+        }
+
+        pub fn push(&mut self, entry: &history::Entry) {
+            let relevant = entry.context == "Outgoing connection"
+                || self
+                    .addresses
+                    .iter()
+                    .any(|address| entry.context == format!("Peer {}", address));
+            if relevant {
+                self.events
+                    .push(format!("{}: {}", entry.severity, entry.message));
+            }
+        }
+
+        pub fn interact(
+            &mut self,
+            event: crossterm::event::Event,
+            server: &util::ThreadChannel<
+                server::Message<server::data::Ui, server::event::Ui>,
+                Message<data::Server, event::Server>,
+            >,
+        ) -> Result<Option<backend::Message<backend::data::Ui, backend::event::Ui>>> {
+            if let crossterm::event::Event::Key(event) = event {
+                let offer = match &self.offer {
+                    Some(offer) => offer,
+                    None => {
+                        if event.code == KeyCode::Esc {
+                            return Ok(Some(backend::Message::Event(backend::event::Ui::Back)));
+                        }
+                        return Ok(None);
+                    }
+                };
+                match event.code {
+                    KeyCode::Char(character) => self.directory.push(character),
+                    KeyCode::Backspace => {
+                        self.directory.pop();
+                    }
+                    KeyCode::Enter => {
+                        let directory = PathBuf::from(self.directory.trim());
+                        if directory.as_os_str().is_empty() {
+                            self.error =
+                                Some(String::from("Enter the directory to save the files in"));
+                            return Ok(None);
+                        }
+                        // The free space was only checked for the download directory. Another one is checked on accepting.
+                        if directory == offer.directory && !offer.acceptable() {
+                            self.error = Some(String::from(
+                                "Not enough space. Choose another directory or decline",
+                            ));
+                            return Ok(None);
+                        }
+                        let peer = offer.peer;
+                        server.send(server::Message::Event(server::event::Ui::AnswerOffer {
+                            peer,
+                            accept: true,
+                            directory: Some(directory),
+                        }))?;
+                        return Ok(Some(backend::Message::Event(
+                            backend::event::Ui::Receiving(peer),
+                        )));
+                    }
+                    KeyCode::Esc => {
+                        server.send(server::Message::Event(server::event::Ui::AnswerOffer {
+                            peer: offer.peer,
+                            accept: false,
+                            directory: None,
+                        }))?;
+                        return Ok(Some(backend::Message::Event(backend::event::Ui::Back)));
+                    }
+                    _ => (),
+                }
+            }
+            Ok(None)
+        }
+
+        pub fn draw(
+            &mut self,
+            terminal: &mut tui::Terminal<CrosstermBackend<io::Stdout>>,
+        ) -> Result<()> {
+            terminal.draw(|f| {
+                let split = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints(
+                        [
+                            Constraint::Min(0),
+                            Constraint::Length(3),
+                            Constraint::Length(3),
+                        ]
+                        .as_ref(),
+                    )
+                    .split(f.size());
+
+                let style = style::Style::default();
+                let (title, mut lines, help) = match &self.offer {
+                    Some(offer) => (
+                        "Incoming offer",
+                        offer_summary(offer),
+                        "Enter: Accept | Esc: Decline",
+                    ),
+                    None => {
+                        let connected = self.peers.iter().find(|peer| {
+                            peer.direction == peer::Direction::Outgoing
+                                && self.addresses.contains(&peer.address)
+                        });
+                        let status = match connected {
+                            Some(peer) => {
+                                format!("Connected to {}. Waiting for the offer...", peer.address)
+                            }
+                            None => format!(
+                                "Connecting to {}...",
+                                self.addresses
+                                    .iter()
+                                    .map(ToString::to_string)
+                                    .collect::<Vec<String>>()
+                                    .join(", ")
+                            ),
+                        };
+                        let mut lines = vec![ListItem::new(status), ListItem::new("")];
+                        lines.extend(self.events.iter().map(|event| ListItem::new(event.clone())));
+                        ("Waiting for the sender", lines, "Esc: Back")
+                    }
+                };
+                if let Some(error) = &self.error {
+                    lines.push(ListItem::new(""));
+                    lines.push(ListItem::new(error.clone()).style(style.fg(style::Color::Red)));
+                }
+                let details =
+                    List::new(lines).block(Block::default().borders(Borders::ALL).title(title));
+                f.render_widget(details, split[0]);
+
+                let directory = match &self.offer {
+                    Some(_) => format!("{}_", self.directory),
+                    None => String::new(),
+                };
+                let directory = Paragraph::new(directory).block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title("Save files in"),
+                );
+                f.render_widget(directory, split[1]);
+
+                let help = List::new(vec![ListItem::new(help)])
+                    .block(Block::default().borders(Borders::ALL));
+                f.render_widget(help, split[2]);
+            })?;
+            Ok(())
+        }
+    }
+
+    // Follows one download until it is done. Leaving early doesn't stop it.
+    pub struct ReceiveProgress {
+        pub peer: usize,
+        pub status: Option<download::Status>, // None until the first update comes in
+    }
+
+    impl ReceiveProgress {
+        pub fn new(
+            peer: usize,
+            downloads: &[download::Status],
+            ended: Option<&download::Status>,
+        ) -> ReceiveProgress {
+            let mut scene = ReceiveProgress { peer, status: None };
+            scene.update(downloads);
+            if let Some(status) = ended {
+                scene.ended(status);
+            }
+            scene
+        }
+
+        // Progress can come in after the download has ended, as it is sent on a queue of its own. The outcome stays.
+        pub fn update(&mut self, downloads: &[download::Status]) {
+            if let Some(download::State::Done | download::State::Failed(_)) =
+                self.status.as_ref().map(|status| &status.state)
+            {
+                return;
+            }
+            if let Some(status) = downloads.iter().find(|download| download.peer == self.peer) {
+                self.status = Some(status.clone());
+            }
+        }
+
+        pub fn ended(&mut self, status: &download::Status) {
+            if status.peer == self.peer {
+                self.status = Some(status.clone());
+            }
+        }
+
+        pub fn interact(
+            &mut self,
+            event: crossterm::event::Event,
+        ) -> Result<Option<backend::Message<backend::data::Ui, backend::event::Ui>>> {
+            if let crossterm::event::Event::Key(event) = event {
+                if let KeyCode::Esc | KeyCode::Enter = event.code {
+                    return Ok(Some(backend::Message::Event(backend::event::Ui::Back)));
+                }
+            }
+            Ok(None)
+        }
+
+        pub fn draw(
+            &mut self,
+            terminal: &mut tui::Terminal<CrosstermBackend<io::Stdout>>,
+        ) -> Result<()> {
+            terminal.draw(|f| {
+                let split = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints([Constraint::Min(0), Constraint::Length(3)].as_ref())
+                    .split(f.size());
+
+                let style = style::Style::default();
+                let (lines, help) = match &self.status {
+                    Some(status) => {
+                        let color = match status.state {
+                            download::State::Receiving => style::Color::Reset,
+                            download::State::Done => style::Color::Green,
+                            download::State::Failed(_) => style::Color::Red,
+                        };
+                        let help = match status.state {
+                            download::State::Receiving => "Esc: Back, the download goes on",
+                            _ => "Enter/Esc: Back",
+                        };
+                        let lines = vec![
+                            ListItem::new(format!("From {}", status.address)),
+                            ListItem::new(format!("To {}", status.directory.display())),
+                            ListItem::new(""),
+                            ListItem::new(format!(
+                                "{} of {} files | {} of {} | {}%",
+                                status.completed,
+                                status.files,
+                                util::format_size(status.received),
+                                util::format_size(status.total_size),
+                                status.percent()
+                            )),
+                            ListItem::new(status.state.to_string()).style(style.fg(color)),
+                        ];
+                        (lines, help)
+                    }
+                    None => (
+                        vec![ListItem::new("Waiting for the download to start...")],
+                        "Esc: Back",
+                    ),
+                };
+                let progress = List::new(lines)
+                    .block(Block::default().borders(Borders::ALL).title("Receiving"));
+                f.render_widget(progress, split[0]);
+
+                let help = List::new(vec![ListItem::new(help)])
+                    .block(Block::default().borders(Borders::ALL));
+                f.render_widget(help, split[1]);
+            })?;
+            Ok(())
+        }
+    }
+
+    // Picks the peers to send the files from the list to. They all get the same offer, and the files are read once for all of them.
+    // The files can also be kept in sync with the peers instead, so that later changes follow on their own.
+    pub struct SendFiles {
+        pub paths: Vec<PathBuf>,
+        pub peers: Vec<peer::PeerStatus>,
+        pub selected: usize,        // Index into the peers
+        pub recipients: Vec<usize>, // IDs of the peers that are ticked
+    }
+
+    impl SendFiles {
+        pub fn new(paths: Vec<PathBuf>, peers: Vec<peer::PeerStatus>) -> SendFiles {
+            SendFiles {
+                paths,
+                peers,
+                selected: 0,
+                recipients: vec![],
+            }
+        }
+
+        pub fn set_peers(&mut self, peers: Vec<peer::PeerStatus>) {
+            self.selected = follow_selection(&self.peers, self.selected, &peers);
+            self.recipients
+                .retain(|id| peers.iter().any(|peer| peer.id == *id));
+            self.peers = peers;
+        }
+
+        pub fn interact(
+            &mut self,
+            event: crossterm::event::Event,
+            server: &util::ThreadChannel<
+                server::Message<server::data::Ui, server::event::Ui>,
+                Message<data::Server, event::Server>,
+            >,
+        ) -> Result<Option<backend::Message<backend::data::Ui, backend::event::Ui>>> {
+            if let crossterm::event::Event::Key(event) = event {
+                match event.code {
+                    KeyCode::Up if !self.peers.is_empty() => {
+                        self.selected = (self.selected + self.peers.len() - 1) % self.peers.len()
+                    }
+                    KeyCode::Down if !self.peers.is_empty() => {
+                        self.selected = (self.selected + 1) % self.peers.len()
+                    }
+                    KeyCode::Char(' ') => {
+                        if let Some(peer) = self.peers.get(self.selected) {
+                            match self.recipients.iter().position(|id| *id == peer.id) {
+                                Some(index) => {
+                                    self.recipients.remove(index);
+                                }
+                                None => self.recipients.push(peer.id),
+                            }
+                        }
+                    }
+                    KeyCode::Enter if !self.recipients.is_empty() && !self.paths.is_empty() => {
+                        server.send(server::Message::Event(server::event::Ui::SendFiles {
+                            peers: self.recipients.clone(),
+                            paths: self.paths.clone(),
+                        }))?;
+                        return Ok(Some(backend::Message::Event(backend::event::Ui::Back)));
+                    }
+                    KeyCode::Char('s') if !self.recipients.is_empty() && !self.paths.is_empty() => {
+                        server.send(server::Message::Event(server::event::Ui::SyncFiles {
+                            peers: self.recipients.clone(),
+                            paths: self.paths.clone(),
+                            two_way: false,
+                        }))?;
+                        return Ok(Some(backend::Message::Event(backend::event::Ui::Back)));
+                    }
+                    // Both ways works for a single folder and peer only. The server says so otherwise.
+                    KeyCode::Char('b') if !self.recipients.is_empty() && !self.paths.is_empty() => {
+                        server.send(server::Message::Event(server::event::Ui::SyncFiles {
+                            peers: self.recipients.clone(),
+                            paths: self.paths.clone(),
+                            two_way: true,
+                        }))?;
+                        return Ok(Some(backend::Message::Event(backend::event::Ui::Back)));
+                    }
+                    KeyCode::Esc => {
+                        return Ok(Some(backend::Message::Event(backend::event::Ui::Back)))
+                    }
+                    _ => (),
+                }
+            }
+            Ok(None)
+        }
+
+        pub fn draw(
+            &mut self,
+            terminal: &mut tui::Terminal<CrosstermBackend<io::Stdout>>,
+        ) -> Result<()> {
+            terminal.draw(|f| {
+                let split = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints([Constraint::Min(0), Constraint::Length(3)].as_ref())
+                    .split(f.size());
+
+                let split_vertical = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(40), Constraint::Percentage(60)].as_ref())
+                    .split(split[0]);
+
+                let style = style::Style::default();
+                let peers: Vec<ListItem> = self
+                    .peers
+                    .iter()
+                    .map(|peer| {
+                        let tick = if self.recipients.contains(&peer.id) {
+                            "[x]"
+                        } else {
+                            "[ ]"
+                        };
+                        ListItem::new(format!("{} {}", tick, peer.address))
+                    })
+                    .collect();
+                let mut state = tui::widgets::ListState::default();
+                if !self.peers.is_empty() {
+                    state.select(Some(self.selected));
+                }
+                let title = match self.peers.len() {
+                    0 => String::from("No peers connected"),
+                    _ => format!("Send to ({} chosen)", self.recipients.len()),
+                };
+                let peers = List::new(peers)
+                    .block(Block::default().borders(Borders::ALL).title(title))
+                    .highlight_style(
+                        style
+                            .fg(style::Color::Rgb(253, 3, 166))
+                            .add_modifier(style::Modifier::BOLD),
+                    )
+                    .highlight_symbol("> ");
+                f.render_stateful_widget(peers, split_vertical[0], &mut state);
+
+                let paths: Vec<ListItem> = match self.paths.len() {
+                    0 => vec![ListItem::new(
+                        "No files chosen. Add some under \"Add or remove files\" first",
+                    )],
+                    _ => self
+                        .paths
+                        .iter()
+                        .map(|path| ListItem::new(path.display().to_string()))
+                        .collect(),
+                };
+                let paths =
+                    List::new(paths).block(Block::default().borders(Borders::ALL).title("Files"));
+                f.render_widget(paths, split_vertical[1]);
+
+                let help = List::new(vec![ListItem::new(
+                    "Up/Down: Choose peer | Space: Tick or untick | Enter: Offer files | S: Keep in sync | B: Sync both ways | Esc: Back",
+                )])
+                .block(Block::default().borders(Borders::ALL));
+                f.render_widget(help, split[1]);
+            })?;
+            Ok(())
+        }
+    }
+
+    // Editor for text to send to a peer, like a URL or a config snippet
+    pub struct SendText {
+        pub peers: Vec<peer::PeerStatus>,
+        pub selected: usize, // Index into the peers
+        pub editor: TextEditor,
+    }
+
+    impl SendText {
+        pub fn new(peers: Vec<peer::PeerStatus>) -> SendText {
+            SendText {
+                peers,
+                selected: 0,
+                editor: TextEditor::new(),
+            }
+        }
+
+        pub fn set_peers(&mut self, peers: Vec<peer::PeerStatus>) {
+            self.selected = follow_selection(&self.peers, self.selected, &peers);
+            self.peers = peers;
+        }
+
+        pub fn interact(
+            &mut self,
+            event: crossterm::event::Event,
+            server: &util::ThreadChannel<
+                server::Message<server::data::Ui, server::event::Ui>,
+                Message<data::Server, event::Server>,
+            >,
+        ) -> Result<Option<backend::Message<backend::data::Ui, backend::event::Ui>>> {
+            if let crossterm::event::Event::Key(event) = event {
+                // Enter is taken by the editor, so sending needs a shortcut of its own
+                let control = event
+                    .modifiers
+                    .contains(crossterm::event::KeyModifiers::CONTROL);
+                match event.code {
+                    KeyCode::Char('s') if control => {
+                        if let Some(peer) = self.peers.get(self.selected) {
+                            if !self.editor.is_blank() {
+                                server.send(server::Message::Event(
+                                    server::event::Ui::SendSnippet {
+                                        peer: peer.id,
+                                        text: self.editor.text(),
+                                    },
+                                ))?;
+                                return Ok(Some(backend::Message::Event(backend::event::Ui::Back)));
+                            }
+                        }
+                    }
+                    KeyCode::Tab if !self.peers.is_empty() => {
+                        self.selected = (self.selected + 1) % self.peers.len()
+                    }
+                    KeyCode::Esc => {
+                        return Ok(Some(backend::Message::Event(backend::event::Ui::Back)))
+                    }
+                    code => self.editor.edit(&code),
+                }
+            }
+            Ok(None)
+        }
+
+        pub fn draw(
+            &mut self,
+            terminal: &mut tui::Terminal<CrosstermBackend<io::Stdout>>,
+        ) -> Result<()> {
+            terminal.draw(|f| {
+                let split = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints(
+                        [
+                            Constraint::Length(3),
+                            Constraint::Min(0),
+                            Constraint::Length(3),
+                        ]
+                        .as_ref(),
+                    )
+                    .split(f.size());
+
+                let recipient = match self.peers.get(self.selected) {
+                    Some(peer) => peer.address.to_string(),
+                    None => String::from("No peers connected"),
+                };
+                let recipient = List::new(vec![ListItem::new(recipient)])
+                    .block(Block::default().borders(Borders::ALL).title("To"));
+                f.render_widget(recipient, split[0]);
+
+                // Keep the cursor in view
+                let height = split[1].height.saturating_sub(2) as usize;
+                let (row, _) = self.editor.cursor();
+                let scroll = (row + 1).saturating_sub(height) as u16;
+                let editor = Paragraph::new(self.editor.display_lines().join("\n"))
+                    .scroll((scroll, 0))
+                    .block(Block::default().borders(Borders::ALL).title("Text"));
+                f.render_widget(editor, split[1]);
+
+                let help = List::new(vec![ListItem::new(
+                    "Ctrl+S: Send | Tab: Next peer | Esc: Cancel",
+                )])
+                .block(Block::default().borders(Borders::ALL));
+                f.render_widget(help, split[2]);
             })?;
             Ok(())
         }
@@ -451,4 +1928,254 @@ mod scene {
             Ok(())
         }
     }
+
+    // Scrollable list of status and error events, newest at the bottom
+    pub struct History {
+        pub entries: ScrollList,
+        pub severities: Vec<history::Severity>,
+    }
+
+    impl History {
+        pub fn new(history: &history::History) -> History {
+            let mut scene = History {
+                entries: ScrollList::new(String::from("Status history"), vec![]),
+                severities: vec![],
+            };
+            for entry in history.entries() {
+                scene.push(entry);
+            }
+            if !scene.entries.options.is_empty() {
+                scene.entries.previous(); // Start out at the newest entry
+            }
+            scene
+        }
+
+        pub fn push(&mut self, entry: &history::Entry) {
+            // Stay with the newest entry, unless the user has scrolled up
+            let newest_selected = self.entries.state.selected().is_some()
+                && self.entries.state.selected() == self.entries.options.len().checked_sub(1);
+
+            self.entries.options.push(entry.to_string());
+            self.severities.push(entry.severity);
+            if self.entries.options.len() > ServerSettings::default().history_capacity {
+                self.entries.options.remove(0);
+                self.severities.remove(0);
+            }
+
+            if newest_selected {
+                self.entries
+                    .state
+                    .select(Some(self.entries.options.len() - 1));
+            }
+        }
+
+        pub fn interact(
+            &mut self,
+            event: crossterm::event::Event,
+            server: &util::ThreadChannel<
+                server::Message<server::data::Ui, server::event::Ui>,
+                Message<data::Server, event::Server>,
+            >,
+        ) -> Result<Option<backend::Message<backend::data::Ui, backend::event::Ui>>> {
+            if let crossterm::event::Event::Key(event) = event {
+                match event.code {
+                    KeyCode::Up if !self.entries.options.is_empty() => self.entries.previous(),
+                    KeyCode::Down if !self.entries.options.is_empty() => self.entries.next(),
+                    KeyCode::Char('e') => {
+                        server.send(server::Message::Event(server::event::Ui::ExportHistory))?
+                    }
+                    KeyCode::Esc => {
+                        return Ok(Some(backend::Message::Event(backend::event::Ui::Back)))
+                    }
+                    _ => (),
+                }
+            }
+            Ok(None)
+        }
+
+        pub fn draw(
+            &mut self,
+            terminal: &mut tui::Terminal<CrosstermBackend<io::Stdout>>,
+            queues: &[(&str, util::QueueMetrics)],
+        ) -> Result<()> {
+            terminal.draw(|f| {
+                let split = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints(
+                        [
+                            Constraint::Min(0),
+                            Constraint::Length(4),
+                            Constraint::Length(3),
+                        ]
+                        .as_ref(),
+                    )
+                    .split(f.size());
+
+                let style = style::Style::default();
+                let entries: Vec<ListItem> = self
+                    .entries
+                    .options
+                    .iter()
+                    .zip(&self.severities)
+                    .map(|(entry, severity)| {
+                        let color = match severity {
+                            history::Severity::Info => style::Color::Reset,
+                            history::Severity::Warning => style::Color::Yellow,
+                            history::Severity::Error => style::Color::Red,
+                        };
+                        ListItem::new(entry.as_ref()).style(style.fg(color))
+                    })
+                    .collect();
+                let entries = List::new(entries)
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .title(self.entries.heading.as_ref()),
+                    )
+                    .highlight_style(style.add_modifier(style::Modifier::BOLD))
+                    .highlight_symbol("> ");
+                f.render_stateful_widget(entries, split[0], &mut self.entries.state);
+
+                let queues = queues
+                    .iter()
+                    .map(|(name, metrics)| format!("{}: {}", name, metrics))
+                    .collect::<Vec<_>>()
+                    .join(" | ");
+                let queues = Paragraph::new(queues)
+                    .wrap(Wrap { trim: true })
+                    .block(Block::default().borders(Borders::ALL).title("Queues"));
+                f.render_widget(queues, split[1]);
+
+                let help = List::new(vec![ListItem::new(
+                    "Up/Down: Scroll | E: Export to file | Esc: Back",
+                )])
+                .block(Block::default().borders(Borders::ALL));
+                f.render_widget(help, split[2]);
+            })?;
+            Ok(())
+        }
+    }
+
+    // Files both ends edited at once. Either version can be kept, and the sync passes that on to the peer.
+    pub struct Conflicts {
+        pub entries: ScrollList,
+        pub conflicts: Vec<(usize, shared::Conflict)>,
+    }
+
+    impl Conflicts {
+        pub fn new(conflicts: Vec<(usize, shared::Conflict)>) -> Conflicts {
+            let mut scene = Conflicts {
+                entries: ScrollList::new(String::from("Conflicting edits"), vec![]),
+                conflicts: vec![],
+            };
+            scene.set_conflicts(conflicts);
+            scene
+        }
+
+        pub fn set_conflicts(&mut self, conflicts: Vec<(usize, shared::Conflict)>) {
+            self.entries.options = conflicts
+                .iter()
+                .map(|(_, conflict)| {
+                    format!(
+                        "{} | {} | Ours: \"{}\"",
+                        util::format_timestamp(conflict.time),
+                        conflict.path,
+                        conflict
+                            .copy
+                            .file_name()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                    )
+                })
+                .collect();
+            self.conflicts = conflicts;
+            let selected = match self.entries.options.len() {
+                0 => None,
+                len => Some(self.entries.state.selected().unwrap_or(0).min(len - 1)),
+            };
+            self.entries.state.select(selected);
+        }
+
+        pub fn interact(
+            &mut self,
+            event: crossterm::event::Event,
+            server: &util::ThreadChannel<
+                server::Message<server::data::Ui, server::event::Ui>,
+                Message<data::Server, event::Server>,
+            >,
+        ) -> Result<Option<backend::Message<backend::data::Ui, backend::event::Ui>>> {
+            let selected = self
+                .entries
+                .state
+                .selected()
+                .and_then(|index| self.conflicts.get(index))
+                .map(|(id, _)| *id);
+            if let crossterm::event::Event::Key(event) = event {
+                let resolution = match event.code {
+                    KeyCode::Up if !self.entries.options.is_empty() => {
+                        self.entries.previous();
+                        None
+                    }
+                    KeyCode::Down if !self.entries.options.is_empty() => {
+                        self.entries.next();
+                        None
+                    }
+                    KeyCode::Char('m') => Some(shared::Resolution::Ours),
+                    KeyCode::Char('t') => Some(shared::Resolution::Theirs),
+                    KeyCode::Esc => {
+                        return Ok(Some(backend::Message::Event(backend::event::Ui::Back)))
+                    }
+                    _ => None,
+                };
+                if let (Some(conflict), Some(resolution)) = (selected, resolution) {
+                    server.send(server::Message::Event(server::event::Ui::ResolveConflict {
+                        conflict,
+                        resolution,
+                    }))?;
+                }
+            }
+            Ok(None)
+        }
+
+        pub fn draw(
+            &mut self,
+            terminal: &mut tui::Terminal<CrosstermBackend<io::Stdout>>,
+        ) -> Result<()> {
+            terminal.draw(|f| {
+                let split = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints([Constraint::Min(0), Constraint::Length(3)].as_ref())
+                    .split(f.size());
+
+                let style = style::Style::default();
+                let entries: Vec<ListItem> = match self.entries.options.is_empty() {
+                    true => vec![ListItem::new("Nothing to resolve")],
+                    false => self
+                        .entries
+                        .options
+                        .iter()
+                        .map(|entry| ListItem::new(entry.as_ref()))
+                        .collect(),
+                };
+                let entries = List::new(entries)
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .title(self.entries.heading.as_ref()),
+                    )
+                    .highlight_style(style.add_modifier(style::Modifier::BOLD))
+                    .highlight_symbol("> ");
+                f.render_stateful_widget(entries, split[0], &mut self.entries.state);
+
+                let help = List::new(vec![ListItem::new(
+                    "Up/Down: Choose file | M: Keep mine | T: Keep theirs | Esc: Back",
+                )])
+                .block(Block::default().borders(Borders::ALL));
+                f.render_widget(help, split[1]);
+            })?;
+            Ok(())
+        }
+    }
 }
@@ -1,13 +1,80 @@
 use anyhow::Result;
 use crossbeam_channel::{self, TrySendError};
 
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
+use crate::settings::QueueSettings;
+
+// What sending does when the queue towards the other thread is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FullQueue {
+    Block,      // Wait for the receiver to catch up. For messages that must not get lost
+    DropOldest, // Make room by throwing away the oldest message. For updates where only the latest one matters
+    Error,      // Hand the message back to the sender, which gets to decide
+}
+
+// Queue depth of one direction of a channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueueMetrics {
+    pub depth: usize,
+    pub capacity: Option<usize>, // None if unbounded
+    pub high_water: usize,       // Deepest the queue has been
+    pub dropped: u64,            // Messages thrown away to make room
+    pub blocked: u64,            // Sends that had to wait for room
+}
+
+impl fmt::Display for QueueMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.depth)?;
+        if let Some(capacity) = self.capacity {
+            write!(f, "/{}", capacity)?;
+        }
+        write!(f, " (max {}", self.high_water)?;
+        if self.dropped > 0 {
+            write!(f, ", {} dropped", self.dropped)?;
+        }
+        if self.blocked > 0 {
+            write!(f, ", {} waited", self.blocked)?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    high_water: AtomicUsize,
+    dropped: AtomicU64,
+    blocked: AtomicU64,
+}
+
+impl Counters {
+    fn metrics(&self, depth: usize, capacity: Option<usize>) -> QueueMetrics {
+        QueueMetrics {
+            depth,
+            capacity,
+            high_water: self.high_water.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            blocked: self.blocked.load(Ordering::Relaxed),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ThreadChannel<TX, RX> {
     pub sender: crossbeam_channel::Sender<TX>,
     pub receiver: crossbeam_channel::Receiver<RX>,
+    policy: FullQueue,
+    // Our own handle on the outgoing queue, to drop the oldest message from. Only there with `FullQueue::DropOldest`,
+    // as it keeps the queue from ever disconnecting.
+    outgoing: Option<crossbeam_channel::Receiver<TX>>,
+    counters: Arc<Counters>,
+    incoming: Arc<Counters>, // Those of the other end, which sends to us
 }
 
 impl<TX, RX> ThreadChannel<TX, RX> {
@@ -15,26 +82,155 @@ impl<TX, RX> ThreadChannel<TX, RX> {
         sender: crossbeam_channel::Sender<TX>,
         receiver: crossbeam_channel::Receiver<RX>,
     ) -> Self {
-        Self { sender, receiver }
+        Self {
+            sender,
+            receiver,
+            policy: FullQueue::Error,
+            outgoing: None,
+            counters: Arc::new(Counters::default()),
+            incoming: Arc::new(Counters::default()),
+        }
     }
 
     pub fn new_pair() -> (ThreadChannel<TX, RX>, ThreadChannel<RX, TX>) {
-        let (a_tx, b_rx) = crossbeam_channel::unbounded::<TX>();
-        let (b_tx, a_rx) = crossbeam_channel::unbounded::<RX>();
+        let unbounded = QueueSettings::new(None, FullQueue::Block);
+        ThreadChannel::new_bounded_pair(unbounded, unbounded)
+    }
+
+    // Each direction gets its own capacity and policy, since it depends on what is sent and how quickly the receiver keeps up
+    pub fn new_bounded_pair(
+        a_to_b: QueueSettings,
+        b_to_a: QueueSettings,
+    ) -> (ThreadChannel<TX, RX>, ThreadChannel<RX, TX>) {
+        let (a_tx, b_rx) = queue::<TX>(&a_to_b);
+        let (b_tx, a_rx) = queue::<RX>(&b_to_a);
 
-        let a = ThreadChannel::new(a_tx, a_rx);
-        let b = ThreadChannel::new(b_tx, b_rx);
+        let a_outgoing = b_rx.clone();
+        let b_outgoing = a_rx.clone();
+        let mut a = ThreadChannel::with_policy(a_tx, a_rx, a_to_b.policy, a_outgoing);
+        let mut b = ThreadChannel::with_policy(b_tx, b_rx, b_to_a.policy, b_outgoing);
+        a.incoming = b.counters.clone();
+        b.incoming = a.counters.clone();
 
         (a, b)
     }
 
+    fn with_policy(
+        sender: crossbeam_channel::Sender<TX>,
+        receiver: crossbeam_channel::Receiver<RX>,
+        policy: FullQueue,
+        outgoing: crossbeam_channel::Receiver<TX>,
+    ) -> Self {
+        Self {
+            policy,
+            outgoing: (policy == FullQueue::DropOldest).then_some(outgoing),
+            ..ThreadChannel::new(sender, receiver)
+        }
+    }
+
     pub fn send(&self, message: TX) -> Result<(), TrySendError<TX>> {
-        self.sender.try_send(message)
+        let result = match self.sender.try_send(message) {
+            Err(TrySendError::Full(message)) => self.send_full(message),
+            result => result,
+        };
+        self.counters
+            .high_water
+            .fetch_max(self.sender.len(), Ordering::Relaxed);
+        result
+    }
+
+    fn send_full(&self, mut message: TX) -> Result<(), TrySendError<TX>> {
+        match (self.policy, &self.outgoing) {
+            (FullQueue::Block, _) => {
+                self.counters.blocked.fetch_add(1, Ordering::Relaxed);
+                self.sender
+                    .send(message)
+                    .map_err(|error| TrySendError::Disconnected(error.into_inner()))
+            }
+            (FullQueue::DropOldest, Some(outgoing)) => loop {
+                // The receiver may empty the queue in between, in which case there is nothing to drop
+                if outgoing.try_recv().is_ok() {
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                match self.sender.try_send(message) {
+                    Err(TrySendError::Full(rejected)) => message = rejected,
+                    result => return result,
+                }
+            },
+            _ => Err(TrySendError::Full(message)),
+        }
     }
 
     pub fn receive(&self) -> Vec<RX> {
         self.receiver.try_iter().collect()
     }
+
+    // Metrics of the queue we send on
+    pub fn metrics(&self) -> QueueMetrics {
+        self.counters
+            .metrics(self.sender.len(), self.sender.capacity())
+    }
+
+    // Metrics of the queue we receive from, so one end can show both directions
+    pub fn incoming_metrics(&self) -> QueueMetrics {
+        self.incoming
+            .metrics(self.receiver.len(), self.receiver.capacity())
+    }
+}
+
+// Keeps the latest update of each kind (enum variant) for the receiving thread, which takes them whenever it gets to
+// it. Sending never waits, and an update only ever gives way to a newer one of its own kind.
+pub struct Latest<T> {
+    updates: Arc<Mutex<HashMap<mem::Discriminant<T>, T>>>,
+    counters: Arc<Counters>,
+}
+
+impl<T> Latest<T> {
+    pub fn new_pair() -> (Latest<T>, Latest<T>) {
+        let updates = Arc::new(Mutex::new(HashMap::new()));
+        let counters = Arc::new(Counters::default());
+        let a = Latest {
+            updates: updates.clone(),
+            counters: counters.clone(),
+        };
+        (a, Latest { updates, counters })
+    }
+
+    // Hands the update back if the other end is gone
+    pub fn send(&self, update: T) -> Result<(), T> {
+        if Arc::strong_count(&self.updates) < 2 {
+            return Err(update);
+        }
+        let mut updates = self.updates.lock().unwrap();
+        if updates.insert(mem::discriminant(&update), update).is_some() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.counters
+            .high_water
+            .fetch_max(updates.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn receive(&self) -> Vec<T> {
+        let mut updates = self.updates.lock().unwrap();
+        updates.drain().map(|(_, update)| update).collect()
+    }
+
+    // Here, dropped counts the updates replaced by a newer one before they were taken
+    pub fn metrics(&self) -> QueueMetrics {
+        let depth = self.updates.lock().unwrap().len();
+        self.counters.metrics(depth, None)
+    }
+}
+
+fn queue<T>(
+    settings: &QueueSettings,
+) -> (crossbeam_channel::Sender<T>, crossbeam_channel::Receiver<T>) {
+    match settings.capacity {
+        // A capacity of 0 would make every send wait for the receiver, and leave nothing to drop
+        Some(capacity) => crossbeam_channel::bounded(capacity.max(1)),
+        None => crossbeam_channel::unbounded(),
+    }
 }
 
 // TODO: I really don't like all the type casting going on below. Look into that.
@@ -58,3 +254,192 @@ pub fn sleep_remaining_frame(clock: &time::Instant, count: &mut u128, rate: u128
         thread::sleep(time::Duration::from_micros(sleep_time as u64));
     }
 }
+
+// Formats a point in time as "YYYY-MM-DD HH:MM:SS" in UTC
+pub fn format_timestamp(timestamp: time::SystemTime) -> String {
+    let seconds = timestamp
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+
+    // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+// Formats a number of bytes for humans, e.g. "1.5 GiB"
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+// Scratch directory for tests that need the file system. Removed again once dropped.
+#[cfg(test)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let nanos = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let path =
+            std::env::temp_dir().join(format!("bitgeon-{}-{}-{}", name, std::process::id(), nanos));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_size() {
+        assert_eq!(super::format_size(0), "0 B");
+        assert_eq!(super::format_size(1023), "1023 B");
+        assert_eq!(super::format_size(1536), "1.5 KiB");
+        assert_eq!(super::format_size(200 * 1024 * 1024 * 1024), "200.0 GiB");
+        assert_eq!(super::format_size(u64::MAX), "16.0 EiB");
+    }
+
+    fn bounded(
+        capacity: usize,
+        policy: FullQueue,
+    ) -> (ThreadChannel<u32, ()>, ThreadChannel<(), u32>) {
+        let settings = QueueSettings::new(Some(capacity), policy);
+        ThreadChannel::new_bounded_pair(settings, settings)
+    }
+
+    #[test]
+    fn full_queues() {
+        let (progress, ui) = bounded(3, FullQueue::DropOldest);
+        for percent in 0..10 {
+            progress.send(percent).unwrap();
+        }
+        assert_eq!(ui.receive(), vec![7, 8, 9]);
+        let metrics = progress.metrics();
+        assert_eq!((metrics.depth, metrics.capacity), (0, Some(3)));
+        assert_eq!((metrics.high_water, metrics.dropped), (3, 7));
+        assert_eq!(ui.incoming_metrics(), metrics);
+        assert_eq!(metrics.to_string(), "0/3 (max 3, 7 dropped)");
+
+        let (chunks, ui) = bounded(2, FullQueue::Error);
+        chunks.send(1).unwrap();
+        chunks.send(2).unwrap();
+        assert_eq!(chunks.send(3), Err(TrySendError::Full(3)));
+        assert_eq!(ui.receive(), vec![1, 2]);
+        assert_eq!(chunks.metrics().dropped, 0);
+
+        let (chunks, ui) = bounded(2, FullQueue::Block);
+        let reader = thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(50));
+            ui.receiver.iter().take(100).collect::<Vec<_>>()
+        });
+        for chunk in 0..100 {
+            chunks.send(chunk).unwrap();
+        }
+        assert_eq!(reader.join().unwrap(), (0..100).collect::<Vec<_>>());
+        let metrics = chunks.metrics();
+        assert!(metrics.blocked > 0);
+        assert_eq!(metrics.high_water, 2);
+
+        // A reader that is gone doesn't leave a blocked sender hanging
+        assert_eq!(chunks.send(100), Err(TrySendError::Disconnected(100)));
+    }
+
+    #[test]
+    fn unbounded() {
+        let (a, b) = ThreadChannel::<u32, u32>::new_pair();
+        for number in 0..5000 {
+            a.send(number).unwrap();
+        }
+        assert_eq!(b.receive().len(), 5000);
+        assert_eq!(a.metrics().capacity, None);
+        assert_eq!(a.metrics().high_water, 5000);
+        assert_eq!(b.metrics().depth, 0);
+        assert_eq!(b.incoming_metrics().to_string(), "0 (max 5000)");
+    }
+
+    #[test]
+    fn latest() {
+        #[derive(Debug, PartialEq)]
+        enum Update {
+            Peers(u32),
+            Downloads(u32),
+        }
+
+        let (server, ui) = Latest::new_pair();
+        server.send(Update::Peers(0)).unwrap();
+        for percent in 0..10 {
+            server.send(Update::Downloads(percent)).unwrap();
+        }
+        let mut updates = ui.receive();
+        updates.sort_by_key(|update| matches!(update, Update::Downloads(_)));
+        assert_eq!(updates, vec![Update::Peers(0), Update::Downloads(9)]);
+        let metrics = server.metrics();
+        assert_eq!(
+            (metrics.depth, metrics.high_water, metrics.dropped),
+            (0, 2, 9)
+        );
+
+        drop(ui);
+        assert_eq!(server.send(Update::Peers(1)), Err(Update::Peers(1)));
+    }
+
+    #[test]
+    fn format_timestamp() {
+        let timestamp = time::UNIX_EPOCH + time::Duration::from_secs(951_782_400 + 3661); // Leap day 2000
+        assert_eq!(super::format_timestamp(timestamp), "2000-02-29 01:01:01");
+        assert_eq!(
+            super::format_timestamp(time::UNIX_EPOCH),
+            "1970-01-01 00:00:00"
+        );
+    }
+}
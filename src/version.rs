@@ -0,0 +1,127 @@
+// Version vectors, for telling apart edits that build on each other from edits made without knowing of each other.
+// Every replica, i.e. every running instance sharing a folder, has a counter in the vector of each file, which it bumps
+// whenever it notices a change to the file. A vector that has every counter at least as high as another one is newer.
+// If each has a counter higher than the other, both ends edited the file on their own, which is a conflict.
+
+use std::collections::BTreeMap;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Version(BTreeMap<u64, u64>); // Counter by replica. Counters at 0 are left out.
+
+// How one version relates to another
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    Same,
+    Newer,
+    Older,
+    Concurrent,
+}
+
+impl Version {
+    pub fn from_counters(counters: impl IntoIterator<Item = (u64, u64)>) -> Version {
+        Version(
+            counters
+                .into_iter()
+                .filter(|(_, counter)| *counter > 0)
+                .collect(),
+        )
+    }
+
+    // Ordered by replica, without zeros
+    pub fn counters(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.0.iter().map(|(replica, counter)| (*replica, *counter))
+    }
+
+    pub fn bump(&mut self, replica: u64) {
+        let counter = self.0.entry(replica).or_insert(0);
+        *counter = counter.saturating_add(1);
+    }
+
+    pub fn bumped(&self, replica: u64) -> Version {
+        let mut version = self.clone();
+        version.bump(replica);
+        version
+    }
+
+    // Knows about every edit either of the two knows about
+    pub fn merge(&self, other: &Version) -> Version {
+        let mut merged = self.clone();
+        for (replica, counter) in other.counters() {
+            let entry = merged.0.entry(replica).or_insert(0);
+            *entry = (*entry).max(counter);
+        }
+        merged
+    }
+
+    // How this version relates to the other one
+    pub fn compare(&self, other: &Version) -> Order {
+        let ahead = self
+            .counters()
+            .any(|(replica, counter)| counter > other.counter(replica));
+        let behind = other
+            .counters()
+            .any(|(replica, counter)| counter > self.counter(replica));
+        match (ahead, behind) {
+            (false, false) => Order::Same,
+            (true, false) => Order::Newer,
+            (false, true) => Order::Older,
+            (true, true) => Order::Concurrent,
+        }
+    }
+
+    // Number of edits this version has been through
+    pub fn edits(&self) -> u64 {
+        self.0
+            .values()
+            .fold(0, |total: u64, counter| total.saturating_add(*counter))
+    }
+
+    fn counter(&self, replica: u64) -> u64 {
+        self.0.get(&replica).copied().unwrap_or(0)
+    }
+}
+
+// Picks an ID for this instance. It only has to differ from the peer's, so it is drawn at random on every start.
+pub fn new_replica() -> u64 {
+    let mut bytes = [0; 8];
+    // Falling back to the clock is good enough to tell two instances apart
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        bytes = (std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64)
+            .to_be_bytes();
+    }
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordering() {
+        let base = Version::default().bumped(1);
+        let ours = base.bumped(1);
+        let theirs = base.bumped(2);
+
+        assert_eq!(base.compare(&base.clone()), Order::Same);
+        assert_eq!(ours.compare(&base), Order::Newer);
+        assert_eq!(base.compare(&ours), Order::Older);
+        assert_eq!(ours.compare(&theirs), Order::Concurrent);
+
+        let merged = ours.merge(&theirs);
+        assert_eq!(merged, Version::from_counters(vec![(1, 2), (2, 1)]));
+        assert_eq!(merged.compare(&ours), Order::Newer);
+        assert_eq!(merged.compare(&theirs), Order::Newer);
+        assert_eq!(merged.edits(), 3);
+
+        // Counters at 0 are the same as no counter
+        assert_eq!(
+            Version::from_counters(vec![(1, 1), (2, 0)]).compare(&base),
+            Order::Same
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use anyhow::{self, Result};
 
 use crossterm::event::KeyCode;
@@ -5,6 +7,7 @@ use crossterm::event::KeyCode;
 use tui::{self, widgets::ListState};
 
 use crate::file_processing;
+use crate::link::Link;
 use file_processing::PathState;
 
 pub struct ScrollList {
@@ -101,6 +104,11 @@ impl StyledFilePath {
             }
             PathState::File => self.display_path.insert_str(0, "✔  "),
             PathState::Invalid => self.display_path.insert_str(0, "❌ "),
+            PathState::Link => {
+                self.display_path.insert_str(0, "🔗 ");
+                self.display_path
+                    .push_str(" | Connects once you are done editing");
+            }
             _ => (),
         }
     }
@@ -140,9 +148,8 @@ impl StyledPathList {
                 // Delete current entry. Make sure not to select something invalid
                 if let Some(mut index) = self.state.selected() {
                     self.paths.remove(index);
-                    match self.paths.len() {
-                        0 => self.paths.push(StyledFilePath::new("")),
-                        _ => (),
+                    if self.paths.is_empty() {
+                        self.paths.push(StyledFilePath::new(""));
                     }
                     if index >= self.paths.len() {
                         index -= 1;
@@ -188,12 +195,41 @@ impl StyledPathList {
             .collect()
     }
 
+    // Removes the links pasted in between the paths. They are not for sending, but for connecting to whoever sent them.
+    pub fn take_links(&mut self) -> Vec<Link> {
+        let (links, paths) = self
+            .paths
+            .drain(..)
+            .partition(|path| path.state == PathState::Link);
+        self.paths = paths;
+        if self.paths.is_empty() {
+            self.paths.push(StyledFilePath::new(""));
+        }
+        self.state.select(Some(0));
+        self.paths[0].select();
+
+        links
+            .iter()
+            .filter_map(|path: &StyledFilePath| Link::parse(&path.path).ok())
+            .collect()
+    }
+
+    // Paths that have been checked and exist. Everything else stays behind for the user to fix.
+    pub fn sendable_paths(&self) -> Vec<PathBuf> {
+        self.paths
+            .iter()
+            .filter(|path| matches!(path.state, PathState::File | PathState::Directory(_)))
+            .map(|path| PathBuf::from(&path.path))
+            .collect()
+    }
+
     pub fn insert_empty_element(&mut self, index: usize) {
         let mut new_element = StyledFilePath::new("");
         new_element.style();
         self.paths.insert(index, new_element);
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Result<()> {
         let mut offset = 1;
         let i = match self.state.selected() {
@@ -271,3 +307,154 @@ impl StyledPathList {
         self.state.select(Some(0));
     }
 }
+
+// Multi-line text input. The cursor counts characters rather than bytes, so it never ends up inside a character.
+#[derive(Clone)]
+pub struct TextEditor {
+    lines: Vec<Vec<char>>,
+    row: usize,
+    column: usize,
+}
+
+impl Default for TextEditor {
+    fn default() -> Self {
+        TextEditor::new()
+    }
+}
+
+impl TextEditor {
+    pub fn new() -> TextEditor {
+        TextEditor {
+            lines: vec![vec![]],
+            row: 0,
+            column: 0,
+        }
+    }
+
+    pub fn edit(&mut self, key: &KeyCode) {
+        match key {
+            KeyCode::Char(character) => {
+                self.lines[self.row].insert(self.column, *character);
+                self.column += 1;
+            }
+            KeyCode::Enter => {
+                let rest = self.lines[self.row].split_off(self.column);
+                self.row += 1;
+                self.lines.insert(self.row, rest);
+                self.column = 0;
+            }
+            KeyCode::Backspace if self.column > 0 => {
+                self.column -= 1;
+                self.lines[self.row].remove(self.column);
+            }
+            KeyCode::Backspace if self.row > 0 => {
+                let line = self.lines.remove(self.row);
+                self.row -= 1;
+                self.column = self.lines[self.row].len();
+                self.lines[self.row].extend(line);
+            }
+            KeyCode::Delete if self.column < self.lines[self.row].len() => {
+                self.lines[self.row].remove(self.column);
+            }
+            KeyCode::Delete if self.row + 1 < self.lines.len() => {
+                let line = self.lines.remove(self.row + 1);
+                self.lines[self.row].extend(line);
+            }
+            KeyCode::Left if self.column > 0 => self.column -= 1,
+            KeyCode::Left if self.row > 0 => {
+                self.row -= 1;
+                self.column = self.lines[self.row].len();
+            }
+            KeyCode::Right if self.column < self.lines[self.row].len() => self.column += 1,
+            KeyCode::Right if self.row + 1 < self.lines.len() => {
+                self.row += 1;
+                self.column = 0;
+            }
+            KeyCode::Up if self.row > 0 => {
+                self.row -= 1;
+                self.column = self.column.min(self.lines[self.row].len());
+            }
+            KeyCode::Down if self.row + 1 < self.lines.len() => {
+                self.row += 1;
+                self.column = self.column.min(self.lines[self.row].len());
+            }
+            KeyCode::Home => self.column = 0,
+            KeyCode::End => self.column = self.lines[self.row].len(),
+            _ => (),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| line.iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // Row and column of the cursor
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.column)
+    }
+
+    pub fn is_blank(&self) -> bool {
+        self.lines.iter().flatten().all(|c| c.is_whitespace())
+    }
+
+    // The lines for display, with the cursor drawn in as a bar
+    pub fn display_lines(&self) -> Vec<String> {
+        self.lines
+            .iter()
+            .enumerate()
+            .map(|(row, line)| {
+                let mut line: String = line.iter().collect();
+                if row == self.row {
+                    let index = line
+                        .char_indices()
+                        .nth(self.column)
+                        .map_or(line.len(), |(index, _)| index);
+                    line.insert(index, '▏');
+                }
+                line
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_in(editor: &mut TextEditor, text: &str) {
+        for character in text.chars() {
+            match character {
+                '\n' => editor.edit(&KeyCode::Enter),
+                _ => editor.edit(&KeyCode::Char(character)),
+            }
+        }
+    }
+
+    #[test]
+    fn text_editor() {
+        let mut editor = TextEditor::new();
+        assert!(editor.is_blank());
+        type_in(&mut editor, "[server]\nport = 3141");
+        editor.edit(&KeyCode::Up);
+        editor.edit(&KeyCode::End);
+        type_in(&mut editor, "\nhøst = ");
+        assert_eq!(editor.text(), "[server]\nhøst = \nport = 3141");
+        assert_eq!(editor.display_lines()[1], "høst = ▏");
+
+        // Joining lines again
+        editor.edit(&KeyCode::Home);
+        editor.edit(&KeyCode::Backspace);
+        assert_eq!(editor.text(), "[server]høst = \nport = 3141");
+        editor.edit(&KeyCode::End);
+        editor.edit(&KeyCode::Delete);
+        assert_eq!(editor.text(), "[server]høst = port = 3141");
+        editor.edit(&KeyCode::Left);
+        editor.edit(&KeyCode::Backspace);
+        assert_eq!(editor.text(), "[server]høst  port = 3141");
+        assert!(!editor.is_blank());
+    }
+}
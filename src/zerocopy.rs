@@ -0,0 +1,91 @@
+// Zero-copy sending: the data of file chunks goes from the page cache to the socket with sendfile, rather than being read
+// into a buffer and written out again. splice would do the same, but needs a pipe in between. This only works where nothing
+// has to touch the bytes on the way, i.e. over plain TCP (see `PeerStatus::takes_plain_data`), and only Linux has it in
+// this form. Everywhere else, `read` gets the data into a buffer as before.
+
+use std::fs::File;
+use std::io;
+
+// Reads a range of the file without moving its position, so one file can be shared by several senders
+pub fn read(file: &File, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; length as usize];
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        file.read_exact_at(&mut data, offset)?;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut read = 0;
+        while read < data.len() {
+            match file.seek_read(&mut data[read..], offset + read as u64)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                count => read += count,
+            }
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::*;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+    use std::time;
+
+    // A peer that takes no data for this long is as good as gone
+    const STALL_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+
+    // A socket of our own to hand to a blocking task. It outlives the async one, should that go away in the meantime.
+    pub fn duplicate(socket: &impl AsRawFd) -> io::Result<OwnedFd> {
+        // Safe, as the socket is borrowed for as long as it takes to duplicate it
+        unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) }.try_clone_to_owned()
+    }
+
+    // Blocks until the whole range has been handed to the socket. Sockets of async tasks don't block, so this waits
+    // for room whenever the send buffer is full.
+    pub fn send_file(socket: &OwnedFd, file: &File, offset: u64, length: u64) -> io::Result<()> {
+        let mut offset = offset as libc::off_t;
+        let mut left = length;
+        while left > 0 {
+            // sendfile doesn't take more than about 2 GiB at once
+            let count = left.min(1 << 30) as usize;
+            match unsafe {
+                libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut offset, count)
+            } {
+                -1 => {
+                    let error = io::Error::last_os_error();
+                    match error.kind() {
+                        io::ErrorKind::WouldBlock => wait_for_room(socket.as_raw_fd())?,
+                        io::ErrorKind::Interrupted => (),
+                        _ => return Err(error),
+                    }
+                }
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                sent => left -= sent as u64,
+            }
+        }
+        Ok(())
+    }
+
+    fn wait_for_room(socket: RawFd) -> io::Result<()> {
+        let mut poll = libc::pollfd {
+            fd: socket,
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll, 1, STALL_TIMEOUT.as_millis() as libc::c_int) } {
+            -1 => match io::Error::last_os_error() {
+                error if error.kind() == io::ErrorKind::Interrupted => Ok(()),
+                error => Err(error),
+            },
+            0 => Err(io::ErrorKind::TimedOut.into()),
+            _ => Ok(()),
+        }
+    }
+}